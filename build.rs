@@ -1,4 +1,11 @@
 fn main() {
+    // The core library is also built for the host to run its tests, which
+    // must not pick up the firmware linker scripts. The variable is unset when
+    // the linker calls back into this script as its error handler.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch != "xtensa") {
+        return;
+    }
+
     linker_be_nice();
     println!("cargo:rustc-link-arg=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
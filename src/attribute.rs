@@ -0,0 +1,1374 @@
+//! Description of the parameters the controller edits.
+
+use heapless::{String, Vec, format};
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Value7, Value14};
+
+use crate::bytes::{Reader, Writer};
+use crate::clock::{DIVISIONS, MAX_BPM, MIN_BPM};
+use crate::encoder::Acceleration;
+use crate::io::MidiSink;
+use crate::parameter::{self, Parameter};
+use crate::usb_midi::Cable;
+use crate::value::{Curve, FINE_STEPS, MIDI_7_MAX, MIDI_14_MAX, Range};
+
+/// Upper bound on the number of attributes in a table.
+pub const MAX_ATTRIBUTES: usize = 32;
+/// Longest attribute name that is kept, in bytes.
+pub const NAME_LEN: usize = 12;
+/// Upper bound on the entries of an enumerated attribute.
+pub const MAX_CHOICES: usize = 6;
+/// Longest choice label that is kept, in bytes.
+pub const LABEL_LEN: usize = 6;
+
+/// Tags of the optional settings that follow the fixed fields when encoded.
+const TAG_ACCELERATION: u8 = 1;
+const TAG_ENCODING: u8 = 2;
+const TAG_STEP: u8 = 3;
+const TAG_CURVE: u8 = 4;
+const TAG_KIND: u8 = 5;
+const TAG_TEMPO_SYNC: u8 = 6;
+const TAG_CABLE: u8 = 7;
+
+pub type Name = String<NAME_LEN>;
+pub type Attributes = Vec<Attribute, MAX_ATTRIBUTES>;
+pub type Label = String<LABEL_LEN>;
+pub type Choices = Vec<Choice, MAX_CHOICES>;
+
+/// What kind of control an attribute is.
+///
+/// Toggles and enumerated attributes are discrete: their values are `0..=1`
+/// and the choice index, and they have no fine position. Neither has the
+/// tempo, which is in whole BPM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// A knob over the range.
+    #[default]
+    Continuous,
+    /// Off or on, sent as the lowest and highest MIDI value. A click flips it.
+    Toggle,
+    /// Negative and positive around the centre of the range, where turns stop
+    /// once before crossing it.
+    Bipolar,
+    /// One of a few labelled choices, each sent as its own value.
+    Enumerated(Choices),
+    /// The tempo of the clock sent as clock master, from [`MIN_BPM`] to
+    /// [`MAX_BPM`]. It isn't sent as a value; presses on it tap the tempo.
+    Tempo,
+}
+
+impl Kind {
+    /// An enumerated kind from `(label, value)` pairs. Entries past
+    /// [`MAX_CHOICES`] are dropped, labels are truncated and values masked to 7 bits.
+    pub fn choices(choices: &[(&str, u8)]) -> Self {
+        Self::Enumerated(
+            choices
+                .iter()
+                .take(MAX_CHOICES)
+                .map(|&(label, value)| Choice {
+                    label: truncate(label),
+                    value: value & 0x7F,
+                })
+                .collect(),
+        )
+    }
+
+    /// The range a kind brings along, `None` for continuous and bipolar ones.
+    fn range(&self) -> Option<Range> {
+        match self {
+            Self::Toggle => Some(Range::new(0, 1)),
+            Self::Tempo => Some(Range::new(MIN_BPM, MAX_BPM)),
+            Self::Enumerated(choices) => Some(Range::new(0, choices.len().saturating_sub(1) as u8)),
+            Self::Continuous | Self::Bipolar => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Choice {
+    pub label: Label,
+    /// The 7-bit value sent for it.
+    pub value: u8,
+}
+
+/// How a value is presented to the user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The raw value.
+    Raw,
+    /// The range scaled through its curve onto `0..=max` milliseconds.
+    Milliseconds(u16),
+    /// The range scaled through its curve onto `0..=100` percent.
+    Percent,
+}
+
+/// Graphic drawn above the value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Visual {
+    /// A horizontal level bar.
+    Bar,
+    /// Expanding rings inside a box, for delay times.
+    Ripple,
+    /// A speaker emitting arcs, for feedback amounts.
+    Waves,
+}
+
+/// How a value is sent to the synth.
+///
+/// Values are scaled from the attribute's range onto the 7 or 14 bits of the
+/// encoding. Only the Control Change encodings use the attribute's controller.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// A single Control Change carrying the value.
+    #[default]
+    Control7,
+    /// The value on the controller, which must be below 32, followed by the
+    /// fine position on the controller 32 above it.
+    Control14,
+    /// An RPN or NRPN with a 14-bit value.
+    Parameter(Parameter),
+    PitchBend,
+    ChannelPressure,
+}
+
+impl Encoding {
+    /// Whether the fine position reaches the synth.
+    pub fn is_fine(self) -> bool {
+        !matches!(self, Self::Control7 | Self::ChannelPressure)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: Name,
+    pub channel: Channel,
+    pub control: Control,
+    pub range: Range,
+    pub default: u8,
+    pub value: u8,
+    /// Position between `value` and the next one, in [`FINE_STEPS`]ths.
+    /// Always `0` unless the encoding [is fine](Encoding::is_fine).
+    pub fine: u8,
+    pub format: Format,
+    pub visual: Visual,
+    pub acceleration: Acceleration,
+    pub encoding: Encoding,
+    pub kind: Kind,
+    /// Whether turns pick note divisions while there is a MIDI clock, received
+    /// or sent. Only used with [`Format::Milliseconds`].
+    pub tempo_sync: bool,
+    /// The USB cable values are sent and received on.
+    pub cable: Cable,
+}
+
+impl Attribute {
+    /// Creates a continuous `0..=127` attribute in single steps, shown as a raw number with a
+    /// level bar, using the default [`Acceleration`] and sent as a 7-bit Control Change.
+    ///
+    /// Names longer than [`NAME_LEN`] are truncated.
+    pub fn new(name: &str, channel: Channel, control: Control) -> Self {
+        Self {
+            name: truncate(name),
+            channel,
+            control,
+            range: Range::default(),
+            default: 0,
+            value: 0,
+            fine: 0,
+            format: Format::Raw,
+            visual: Visual::Bar,
+            acceleration: Acceleration::default(),
+            encoding: Encoding::Control7,
+            kind: Kind::Continuous,
+            tempo_sync: false,
+            cable: Cable::Control,
+        }
+    }
+
+    /// Sets the range, keeping the step and curve, and pulls the default and current value
+    /// into it. Toggles and enumerated attributes bring their own range, see [`Kind`].
+    pub fn with_range(mut self, min: u8, max: u8) -> Self {
+        if self.kind.range().is_none() {
+            self.range = Range::new(min, max)
+                .with_step(self.range.step())
+                .with_curve(self.range.curve());
+        }
+        let default = self.default;
+        self.with_default(default)
+    }
+
+    /// Sets how many values a detent moves.
+    pub fn with_step(mut self, step: u8) -> Self {
+        self.range = self.range.with_step(step);
+        self
+    }
+
+    /// Sets how the range maps onto MIDI and the displayed value.
+    pub fn with_curve(mut self, curve: Curve) -> Self {
+        self.range = self.range.with_curve(curve);
+        self
+    }
+
+    /// Sets the kind. Toggles, enumerated attributes and the tempo replace
+    /// the range with their own, in single linear steps.
+    pub fn with_kind(mut self, kind: Kind) -> Self {
+        if let Some(range) = kind.range() {
+            self.range = range;
+        }
+        self.kind = kind;
+        self.default = self.range.clamp(self.default);
+        self.value = self.range.clamp(self.value);
+        self.fine = 0;
+        self
+    }
+
+    /// Lets turns pick note divisions of the clock's tempo, see
+    /// [`Attribute::step_division`].
+    pub fn with_tempo_sync(mut self) -> Self {
+        self.tempo_sync = true;
+        self
+    }
+
+    /// Routes the attribute to another USB cable than [`Cable::Control`].
+    pub fn with_cable(mut self, cable: Cable) -> Self {
+        self.cable = cable;
+        self
+    }
+
+    /// Sets the default, which also becomes the current value.
+    pub fn with_default(mut self, default: u8) -> Self {
+        self.default = self.range.clamp(default);
+        self.value = self.default;
+        self.fine = 0;
+        self
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_visual(mut self, visual: Visual) -> Self {
+        self.visual = visual;
+        self
+    }
+
+    /// Sets the acceleration curve, [`Acceleration::OFF`] for single steps only.
+    pub fn with_acceleration(mut self, acceleration: Acceleration) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    /// Sets the encoding. A 14-bit pair on a controller of 32 or more has no
+    /// room for its fine part and stays a single Control Change; decoding
+    /// refuses such a definition instead.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = match encoding {
+            Encoding::Control14 if u8::from(self.control) >= 32 => Encoding::Control7,
+            encoding => encoding,
+        };
+        self
+    }
+
+    /// The value and fine position as one number, `value * FINE_STEPS + fine`.
+    pub fn position(&self) -> u16 {
+        self.value as u16 * FINE_STEPS + self.fine as u16
+    }
+
+    /// Whether the attribute keeps a fine position: it's continuous or bipolar
+    /// and the encoding [is fine](Encoding::is_fine).
+    pub fn has_fine_steps(&self) -> bool {
+        self.encoding.is_fine() && self.kind.range().is_none()
+    }
+
+    /// Moves to `position`, clamped to the range. The fine part is dropped
+    /// when the attribute [has no fine steps](Attribute::has_fine_steps).
+    pub fn set_position(&mut self, position: i32) {
+        let position = self.range.clamp_position(position);
+        self.value = (position / FINE_STEPS) as u8;
+        self.fine = if self.has_fine_steps() {
+            (position % FINE_STEPS) as u8
+        } else {
+            0
+        };
+    }
+
+    /// Sets the value, clamped to the range, and clears the fine position.
+    pub fn set_value(&mut self, value: u8) {
+        self.value = self.range.clamp(value);
+        self.fine = 0;
+    }
+
+    /// Moves the value by `detents` steps, keeping the fine position. Bipolar
+    /// attributes stop on the centre when the move would cross it.
+    pub fn step_by(&mut self, detents: i16) {
+        let from = self.position();
+        let mut to = self.range.step_position(from, detents);
+        if self.kind == Kind::Bipolar {
+            let centre = self.range.centre() as u16 * FINE_STEPS;
+            if from.min(to) < centre && centre < from.max(to) {
+                to = centre;
+            }
+        }
+        self.set_position(to as i32);
+    }
+
+    /// The note division nearest to the value at a beat of `beat_us`, if the
+    /// attribute is tempo-synced and any division fits its range.
+    pub fn division(&self, beat_us: u32) -> Option<usize> {
+        let (max_ms, fitting) = self.divisions(beat_us)?;
+        let value_us = self.range.scale(self.value, max_ms) * 1000;
+        (0..fitting).min_by_key(|&index| DIVISIONS[index].duration_us(beat_us).abs_diff(value_us))
+    }
+
+    /// Moves the value `detents` note divisions from the current one at a
+    /// beat of `beat_us`. Returns `false`, leaving the value alone, if there's
+    /// no [division](Attribute::division).
+    pub fn step_division(&mut self, detents: i16, beat_us: u32) -> bool {
+        let (Some(current), Some((_, fitting))) = (self.division(beat_us), self.divisions(beat_us))
+        else {
+            return false;
+        };
+
+        let last = fitting as i32 - 1;
+        let mut target = current as i32 + detents as i32;
+        loop {
+            let index = target.clamp(0, last);
+            self.set_division(index as usize, beat_us);
+            // Neighbours closer together than two values share one.
+            if detents == 0 || self.division(beat_us) != Some(current) || index != target {
+                return true;
+            }
+            target += detents.signum() as i32;
+        }
+    }
+
+    /// Sets the value nearest to note division `index` at a beat of `beat_us`.
+    pub fn set_division(&mut self, index: usize, beat_us: u32) {
+        let (Some((max_ms, _)), Some(division)) = (self.divisions(beat_us), DIVISIONS.get(index))
+        else {
+            return;
+        };
+        let ms = (division.duration_us(beat_us) + 500) / 1000;
+        self.set_value(self.range.unscale(ms, max_ms));
+    }
+
+    /// The longest duration in milliseconds and how many divisions fit it,
+    /// for tempo-synced attributes.
+    fn divisions(&self, beat_us: u32) -> Option<(u32, usize)> {
+        let Format::Milliseconds(max_ms) = self.format else {
+            return None;
+        };
+        if !self.tempo_sync {
+            return None;
+        }
+        let max_us = max_ms as u32 * 1000;
+        let fitting = DIVISIONS
+            .iter()
+            .take_while(|division| division.duration_us(beat_us) <= max_us)
+            .count();
+        (fitting > 0).then_some((max_ms as u32, fitting))
+    }
+
+    /// Flips a toggle and returns `true`, or returns `false` for other kinds.
+    pub fn toggle(&mut self) -> bool {
+        if self.kind != Kind::Toggle {
+            return false;
+        }
+        let (min, max) = (self.range.min(), self.range.max());
+        self.set_value(if self.value == min { max } else { min });
+        true
+    }
+
+    /// The value scaled onto 7 bits, or the value of the chosen entry.
+    pub fn midi_7(&self) -> u8 {
+        match &self.kind {
+            Kind::Enumerated(choices) => choices
+                .get(self.value as usize)
+                .map_or(0, |choice| choice.value),
+            _ => self.range.scale(self.value, MIDI_7_MAX) as u8,
+        }
+    }
+
+    /// The position scaled onto 14 bits, or the value of the chosen entry in
+    /// the upper 7 bits.
+    pub fn midi_14(&self) -> u16 {
+        match self.kind {
+            Kind::Toggle => self.range.scale(self.value, MIDI_14_MAX) as u16,
+            Kind::Enumerated(_) => (self.midi_7() as u16) << 7,
+            _ => self.range.scale_position(self.position(), MIDI_14_MAX) as u16,
+        }
+    }
+
+    fn set_midi_7(&mut self, value: u8) {
+        let value = match &self.kind {
+            // The entry sent closest to the received value.
+            Kind::Enumerated(choices) => (0..choices.len())
+                .min_by_key(|&index| choices[index].value.abs_diff(value))
+                .unwrap_or(0) as u8,
+            _ => self.range.unscale(value as u32, MIDI_7_MAX),
+        };
+        self.set_value(value);
+    }
+
+    fn set_midi_14(&mut self, value: u16) {
+        match self.kind {
+            Kind::Enumerated(_) => self.set_midi_7((value >> 7) as u8),
+            Kind::Toggle => self.set_value(self.range.unscale(value as u32, MIDI_14_MAX)),
+            _ => {
+                let position = self.range.unscale_position(value as u32, MIDI_14_MAX);
+                self.set_position(position as i32);
+            }
+        }
+    }
+
+    /// Sends the current value in the attribute's encoding, on its cable. The
+    /// tempo goes out as the clock instead.
+    pub fn send(&self, sink: &mut impl MidiSink) {
+        if self.kind == Kind::Tempo {
+            return;
+        }
+        let control = |control: u8, value: u8| {
+            MidiMessage::ControlChange(self.channel, Control::new(control), Value7::new(value))
+        };
+        let number = u8::from(self.control);
+        match self.encoding {
+            Encoding::Control7 => sink.send(self.cable, control(number, self.midi_7())),
+            // Receivers clear the fine part on the coarse one, so it goes first.
+            Encoding::Control14 => {
+                let value = self.midi_14();
+                sink.send(self.cable, control(number, (value >> 7) as u8));
+                sink.send(self.cable, control(number + 32, (value & 0x7F) as u8));
+            }
+            Encoding::Parameter(parameter) => {
+                sink.send_parameter(self.cable, self.channel, parameter, self.midi_14())
+            }
+            Encoding::PitchBend => sink.send(
+                self.cable,
+                MidiMessage::PitchBendChange(self.channel, Value14::from(self.midi_14())),
+            ),
+            Encoding::ChannelPressure => sink.send(
+                self.cable,
+                MidiMessage::ChannelPressure(self.channel, Value7::new(self.midi_7())),
+            ),
+        }
+    }
+
+    /// Applies a received message; returns `None` if it isn't one of ours,
+    /// or whether the value changed. Parameter values arrive through
+    /// [`Attribute::receive_parameter`] instead.
+    pub fn receive(&mut self, message: &MidiMessage) -> Option<bool> {
+        if self.kind == Kind::Tempo {
+            return None;
+        }
+        let before = self.position();
+        match (self.encoding, *message) {
+            (
+                Encoding::Control7 | Encoding::Control14,
+                MidiMessage::ControlChange(channel, control, value),
+            ) if channel == self.channel && control == self.control => match self.encoding {
+                Encoding::Control14 => self.set_midi_14((u8::from(value) as u16) << 7),
+                _ => self.set_midi_7(value.into()),
+            },
+            (Encoding::Control14, MidiMessage::ControlChange(channel, control, value))
+                if channel == self.channel && u8::from(control) == u8::from(self.control) + 32 =>
+            {
+                let coarse = self.midi_14() & !0x7F;
+                self.set_midi_14(coarse | u8::from(value) as u16)
+            }
+            (Encoding::PitchBend, MidiMessage::PitchBendChange(channel, value))
+                if channel == self.channel =>
+            {
+                self.set_midi_14(value.into())
+            }
+            (Encoding::ChannelPressure, MidiMessage::ChannelPressure(channel, value))
+                if channel == self.channel =>
+            {
+                self.set_midi_7(value.into())
+            }
+            _ => return None,
+        }
+        Some(self.position() != before)
+    }
+
+    /// Applies a received parameter value, as returned by
+    /// [`parameter::ParameterReceiver::receive`], like [`Attribute::receive`].
+    pub fn receive_parameter(
+        &mut self,
+        channel: Channel,
+        parameter: Parameter,
+        value: u16,
+    ) -> Option<bool> {
+        if channel != self.channel
+            || self.encoding != Encoding::Parameter(parameter)
+            || self.kind == Kind::Tempo
+        {
+            return None;
+        }
+        let before = self.position();
+        self.set_midi_14(value);
+        Some(self.position() != before)
+    }
+
+    /// Rebinds the attribute to what `message` sets, for MIDI Learn. Returns
+    /// `false`, leaving it alone, for messages it can't be bound to; parameters
+    /// are learned with [`Attribute::learn_parameter`] once they are complete.
+    ///
+    /// A Control Change keeps 14 bits if the attribute had them and the
+    /// controller has a fine half; either half binds the pair.
+    pub fn learn(&mut self, message: &MidiMessage) -> bool {
+        let (channel, encoding, control) = match *message {
+            MidiMessage::ControlChange(_, control, _)
+                if Parameter::is_selection(control) || Parameter::is_data_entry(control) =>
+            {
+                return false;
+            }
+            MidiMessage::ControlChange(channel, control, _) => {
+                match (self.encoding, u8::from(control)) {
+                    (Encoding::Control14, 0..32) => (channel, Encoding::Control14, control),
+                    (Encoding::Control14, fine @ 32..64) => {
+                        (channel, Encoding::Control14, Control::new(fine - 32))
+                    }
+                    _ => (channel, Encoding::Control7, control),
+                }
+            }
+            MidiMessage::PitchBendChange(channel, _) => {
+                (channel, Encoding::PitchBend, self.control)
+            }
+            MidiMessage::ChannelPressure(channel, _) => {
+                (channel, Encoding::ChannelPressure, self.control)
+            }
+            _ => return false,
+        };
+        self.control = control;
+        self.bind(channel, encoding);
+        true
+    }
+
+    /// Rebinds the attribute to an RPN or NRPN, for MIDI Learn.
+    pub fn learn_parameter(&mut self, channel: Channel, parameter: Parameter) {
+        self.bind(channel, Encoding::Parameter(parameter));
+    }
+
+    fn bind(&mut self, channel: Channel, encoding: Encoding) {
+        self.channel = channel;
+        self.encoding = encoding;
+        // The fine position goes with a coarse encoding.
+        self.set_position(self.position() as i32);
+    }
+
+    /// The current value as text, e.g. `150 ms`, `On` or a choice label.
+    /// Bipolar values are signed, scaled linearly from the centre.
+    pub fn to_human_readable(&self) -> String<32> {
+        match &self.kind {
+            Kind::Toggle => format!("{}", if self.value > 0 { "On" } else { "Off" }),
+            Kind::Tempo => format!("{} BPM", self.value),
+            Kind::Enumerated(choices) => format!(
+                "{}",
+                choices
+                    .get(self.value as usize)
+                    .map_or("", |choice| choice.label.as_str())
+            ),
+            Kind::Bipolar => {
+                let (amount, unit) = match self.format {
+                    Format::Raw => (self.offset(None), ""),
+                    Format::Milliseconds(max) => (self.offset(Some(max as u32)), " ms"),
+                    Format::Percent => (self.offset(Some(100)), " %"),
+                };
+                if amount == 0 {
+                    format!("0{}", unit)
+                } else {
+                    format!("{:+}{}", amount, unit)
+                }
+            }
+            Kind::Continuous => match self.format {
+                Format::Raw => format!("{}", self.value),
+                Format::Milliseconds(max) => {
+                    format!("{} ms", self.range.scale(self.value, max as u32))
+                }
+                Format::Percent => format!("{} %", self.range.scale(self.value, 100)),
+            },
+        }
+        .unwrap_or_default()
+    }
+
+    /// Distance from the centre, scaled so that either end is `to` if given.
+    fn offset(&self, to: Option<u32>) -> i32 {
+        let centre = self.range.centre();
+        let (distance, half) = if self.value >= centre {
+            (self.value - centre, self.range.max() - centre)
+        } else {
+            (centre - self.value, centre - self.range.min())
+        };
+        let amount = match to {
+            None => distance as u32,
+            Some(_) if half == 0 => 0,
+            Some(to) => (distance as u32 * to + half as u32 / 2) / half as u32,
+        } as i32;
+        if self.value >= centre {
+            amount
+        } else {
+            -amount
+        }
+    }
+}
+
+impl Attribute {
+    /// Largest output of [`Attribute::encode`].
+    pub const ENCODED_LEN: usize =
+        1 + NAME_LEN + 10 + 1 + 5 + 5 + 3 + 4 + 4 + MAX_CHOICES * (2 + LABEL_LEN) + 3 + 3;
+
+    /// Serialises the whole attribute, shared by flash storage and SysEx.
+    ///
+    /// The fixed fields are followed by a count of optional settings, each a
+    /// tag and a length-prefixed payload. Readers skip tags they don't know
+    /// and use defaults for missing ones, so settings can be added without
+    /// changing the format.
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Option<()> {
+        writer.push_counted(self.name.as_bytes())?;
+        writer.extend(&[
+            self.channel.into(),
+            self.control.into(),
+            self.range.min(),
+            self.range.max(),
+            self.default,
+            self.value,
+        ])?;
+        let (format, argument) = match self.format {
+            Format::Raw => (0, 0),
+            Format::Milliseconds(max) => (1, max),
+            Format::Percent => (2, 0),
+        };
+        writer.push(format)?;
+        writer.push_u16(argument)?;
+        writer.push(match self.visual {
+            Visual::Bar => 0,
+            Visual::Ripple => 1,
+            Visual::Waves => 2,
+        })?;
+
+        let acceleration = self.acceleration;
+        writer.push(7)?;
+        writer.push(TAG_ACCELERATION)?;
+        writer.push_counted(&[
+            acceleration.threshold,
+            acceleration.full_speed,
+            acceleration.max_step,
+        ])?;
+        writer.push(TAG_ENCODING)?;
+        let mut encoding = [0; 3];
+        let len = match self.encoding {
+            Encoding::Control7 => 1,
+            Encoding::Control14 => {
+                encoding[0] = 1;
+                1
+            }
+            Encoding::Parameter(parameter) => {
+                encoding[0] = match parameter {
+                    Parameter::NonRegistered(_) => 2,
+                    Parameter::Registered(_) => 3,
+                };
+                encoding[1..].copy_from_slice(&parameter.number().to_le_bytes());
+                3
+            }
+            Encoding::PitchBend => {
+                encoding[0] = 4;
+                1
+            }
+            Encoding::ChannelPressure => {
+                encoding[0] = 5;
+                1
+            }
+        };
+        writer.push_counted(&encoding[..len])?;
+        writer.push(TAG_STEP)?;
+        writer.push_counted(&[self.range.step()])?;
+        writer.push(TAG_CURVE)?;
+        match self.range.curve() {
+            Curve::Linear => writer.push_counted(&[0]),
+            Curve::Logarithmic => writer.push_counted(&[1]),
+            Curve::Exponential => writer.push_counted(&[2]),
+            Curve::Stepped(levels) => writer.push_counted(&[3, levels]),
+        }?;
+        writer.push(TAG_KIND)?;
+        match &self.kind {
+            Kind::Continuous => writer.push_counted(&[0]),
+            Kind::Toggle => writer.push_counted(&[1]),
+            Kind::Bipolar => writer.push_counted(&[2]),
+            Kind::Tempo => writer.push_counted(&[4]),
+            Kind::Enumerated(choices) => {
+                let mut payload = [0; 2 + MAX_CHOICES * (2 + LABEL_LEN)];
+                let mut kind = Writer::new(&mut payload);
+                kind.extend(&[3, choices.len() as u8])?;
+                for choice in choices {
+                    kind.push(choice.value)?;
+                    kind.push_counted(choice.label.as_bytes())?;
+                }
+                let len = kind.len();
+                writer.push_counted(&payload[..len])
+            }
+        }?;
+        writer.push(TAG_TEMPO_SYNC)?;
+        writer.push_counted(&[self.tempo_sync as u8])?;
+        writer.push(TAG_CABLE)?;
+        writer.push_counted(&[self.cable.number()])
+    }
+
+    /// Parses the output of [`Attribute::encode`], rejecting inconsistent definitions.
+    pub(crate) fn decode(reader: &mut Reader) -> Option<Self> {
+        let mut attribute = Self::decode_fixed(reader)?;
+
+        for _ in 0..reader.byte()? {
+            let tag = reader.byte()?;
+            let payload = reader.counted()?;
+            match tag {
+                TAG_ACCELERATION => {
+                    let [threshold, full_speed, max_step] = payload.try_into().ok()?;
+                    attribute.acceleration = Acceleration {
+                        threshold,
+                        full_speed,
+                        max_step,
+                    };
+                    if !attribute.acceleration.is_valid() {
+                        return None;
+                    }
+                }
+                TAG_ENCODING => {
+                    attribute.encoding = match *payload {
+                        [0] => Encoding::Control7,
+                        [1] if u8::from(attribute.control) < 32 => Encoding::Control14,
+                        [kind @ (2 | 3), low, high] => {
+                            let number = u16::from_le_bytes([low, high]);
+                            if number > parameter::MAX_NUMBER {
+                                return None;
+                            }
+                            Encoding::Parameter(if kind == 2 {
+                                Parameter::NonRegistered(number)
+                            } else {
+                                Parameter::Registered(number)
+                            })
+                        }
+                        [4] => Encoding::PitchBend,
+                        [5] => Encoding::ChannelPressure,
+                        _ => return None,
+                    };
+                }
+                TAG_STEP => {
+                    let [step @ 1..=u8::MAX] = *payload else {
+                        return None;
+                    };
+                    attribute.range = attribute.range.with_step(step);
+                }
+                TAG_CURVE => {
+                    let curve = match *payload {
+                        [0] => Curve::Linear,
+                        [1] => Curve::Logarithmic,
+                        [2] => Curve::Exponential,
+                        [3, levels @ 2..=u8::MAX] => Curve::Stepped(levels),
+                        _ => return None,
+                    };
+                    attribute.range = attribute.range.with_curve(curve);
+                }
+                TAG_KIND => attribute.kind = decode_kind(payload)?,
+                TAG_TEMPO_SYNC => {
+                    let [sync @ (0 | 1)] = *payload else {
+                        return None;
+                    };
+                    attribute.tempo_sync = sync == 1;
+                }
+                TAG_CABLE => {
+                    let [number] = *payload else {
+                        return None;
+                    };
+                    attribute.cable = Cable::from_number(number)?;
+                }
+                // Written by a newer firmware.
+                _ => {}
+            }
+        }
+
+        // Kinds with their own range need exactly that range.
+        if attribute.kind.range().is_some_and(|range| {
+            (range.min(), range.max()) != (attribute.range.min(), attribute.range.max())
+        }) {
+            return None;
+        }
+
+        Some(attribute)
+    }
+
+    /// Parses only the fixed fields, as written before optional settings existed.
+    pub(crate) fn decode_fixed(reader: &mut Reader) -> Option<Self> {
+        let name = Name::try_from(reader.str()?).ok()?;
+        let (channel, control) = (reader.byte()?, reader.byte()?);
+        let (min, max) = (reader.byte()?, reader.byte()?);
+        let (default, value) = (reader.byte()?, reader.byte()?);
+        if channel > 15 || control > 127 || min > max {
+            return None;
+        }
+        if !(min..=max).contains(&default) || !(min..=max).contains(&value) {
+            return None;
+        }
+
+        let format = match (reader.byte()?, reader.u16()?) {
+            (0, _) => Format::Raw,
+            (1, max) => Format::Milliseconds(max),
+            (2, _) => Format::Percent,
+            _ => return None,
+        };
+        let visual = match reader.byte()? {
+            0 => Visual::Bar,
+            1 => Visual::Ripple,
+            2 => Visual::Waves,
+            _ => return None,
+        };
+
+        Some(Self {
+            name,
+            channel: Channel::new(channel),
+            control: Control::new(control),
+            range: Range::new(min, max),
+            default,
+            value,
+            fine: 0,
+            format,
+            visual,
+            acceleration: Acceleration::default(),
+            encoding: Encoding::Control7,
+            kind: Kind::Continuous,
+            tempo_sync: false,
+            cable: Cable::Control,
+        })
+    }
+}
+
+fn decode_kind(payload: &[u8]) -> Option<Kind> {
+    let mut reader = Reader::new(payload);
+    let kind = match reader.byte()? {
+        0 => Kind::Continuous,
+        1 => Kind::Toggle,
+        2 => Kind::Bipolar,
+        3 => {
+            let mut choices = Choices::new();
+            for _ in 0..reader.byte()? {
+                let value = reader.byte()?;
+                let label = reader.str()?;
+                if value > 0x7F {
+                    return None;
+                }
+                choices
+                    .push(Choice {
+                        label: Label::try_from(label).ok()?,
+                        value,
+                    })
+                    .ok()?;
+            }
+            if choices.len() < 2 {
+                return None;
+            }
+            Kind::Enumerated(choices)
+        }
+        4 => Kind::Tempo,
+        _ => return None,
+    };
+    reader.is_empty().then_some(kind)
+}
+
+fn truncate<const N: usize>(name: &str) -> String<N> {
+    let mut truncated = String::new();
+    for c in name.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_keeps_default_in_range() {
+        let attr = Attribute::new("Delay", Channel::C1, Control::new(20))
+            .with_default(120)
+            .with_range(10, 100);
+
+        assert_eq!(attr.default, 100);
+        assert_eq!(attr.value, 100);
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let attr = Attribute::new("Filter Cutoff Frequency", Channel::C1, Control::new(74));
+        assert_eq!(attr.name, "Filter Cutof");
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let attr = Attribute::new("Delay", Channel::C3, Control::new(20))
+            .with_range(0, 100)
+            .with_default(15)
+            .with_format(Format::Milliseconds(1000))
+            .with_visual(Visual::Ripple)
+            .with_acceleration(Acceleration::OFF)
+            .with_encoding(Encoding::Control14)
+            .with_step(4)
+            .with_curve(Curve::Stepped(8))
+            .with_kind(Kind::Bipolar)
+            .with_tempo_sync()
+            .with_cable(Cable::Thru);
+        // As large as an attribute gets.
+        let largest = Attribute::new("Oscillator 1", Channel::C16, Control::new(0))
+            .with_encoding(Encoding::Parameter(Parameter::NonRegistered(0x3FFF)))
+            .with_curve(Curve::Stepped(255))
+            .with_kind(Kind::choices(&[("Sine A", 0); MAX_CHOICES]));
+
+        let tempo = Attribute::new("Tempo", Channel::C1, Control::new(0))
+            .with_kind(Kind::Tempo)
+            .with_default(120);
+
+        for attr in [attr, waveform(), largest, tempo] {
+            let mut buf = [0; Attribute::ENCODED_LEN];
+            let mut writer = Writer::new(&mut buf);
+            attr.encode(&mut writer).unwrap();
+            let len = writer.len();
+
+            let mut reader = Reader::new(&buf[..len]);
+            assert_eq!(Attribute::decode(&mut reader), Some(attr));
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn inconsistent_definitions_are_rejected() {
+        let decode = |bytes: &[u8]| Attribute::decode(&mut Reader::new(bytes));
+
+        // Channel 16.
+        assert_eq!(
+            decode(&[1, b'A', 16, 20, 0, 100, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        // Minimum above maximum.
+        assert_eq!(
+            decode(&[1, b'A', 0, 20, 50, 10, 50, 50, 0, 0, 0, 0, 0]),
+            None
+        );
+        // Value outside the range.
+        assert_eq!(decode(&[1, b'A', 0, 20, 0, 10, 5, 50, 0, 0, 0, 0, 0]), None);
+        // Unknown format and visual.
+        assert_eq!(decode(&[1, b'A', 0, 20, 0, 10, 5, 5, 9, 0, 0, 0, 0]), None);
+        assert_eq!(decode(&[1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0, 9, 0]), None);
+        // Truncated.
+        assert_eq!(decode(&[1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0]), None);
+        assert!(decode(&[1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0, 0, 0]).is_some());
+        // An acceleration curve that never reaches full speed.
+        assert_eq!(
+            decode(&[1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0, 0, 1, 1, 3, 9, 9, 4]),
+            None
+        );
+    }
+
+    #[test]
+    fn optional_settings_default_or_are_skipped() {
+        let decode = |bytes: &[u8]| Attribute::decode(&mut Reader::new(bytes));
+        let fixed = [1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0, 0];
+
+        let missing = decode(&[&fixed[..], &[0]].concat()).unwrap();
+        assert_eq!(missing.acceleration, Acceleration::default());
+
+        // An unknown tag 9 ahead of the acceleration.
+        let unknown = decode(&[&fixed[..], &[2, 9, 2, 7, 7, 1, 3, 0, 8, 2]].concat()).unwrap();
+        assert_eq!(
+            unknown.acceleration,
+            Acceleration {
+                threshold: 0,
+                full_speed: 8,
+                max_step: 2
+            }
+        );
+        assert_eq!(unknown.encoding, Encoding::Control7);
+
+        // 14-bit pairs need a controller below 32.
+        let pair = decode(&[&fixed[..], &[1, 2, 1, 1]].concat()).unwrap();
+        assert_eq!(pair.encoding, Encoding::Control14);
+        let mut high = fixed;
+        high[3] = 40;
+        assert_eq!(decode(&[&high[..], &[1, 2, 1, 1]].concat()), None);
+
+        // Nor can the builder make one.
+        let high = Attribute::new("Cutoff", Channel::C1, Control::new(40))
+            .with_encoding(Encoding::Control14);
+        assert_eq!(high.encoding, Encoding::Control7);
+    }
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(Channel::C1, Control::new(control), Value7::new(value))
+    }
+
+    #[test]
+    fn positions_keep_fine_steps_only_when_sent() {
+        let mut coarse =
+            Attribute::new("Cutoff", Channel::C1, Control::new(20)).with_range(10, 100);
+        coarse.set_position(50 * 128 + 64);
+        assert_eq!((coarse.value, coarse.fine), (50, 0));
+
+        let mut fine = coarse.clone().with_encoding(Encoding::Control14);
+        fine.set_position(50 * 128 + 64);
+        assert_eq!((fine.value, fine.fine), (50, 64));
+        assert_eq!(fine.position(), 50 * 128 + 64);
+
+        // The range ends with the fine steps of its maximum.
+        fine.set_position(100 * 128 + 500);
+        assert_eq!((fine.value, fine.fine), (100, 127));
+        fine.set_position(-1);
+        assert_eq!((fine.value, fine.fine), (10, 0));
+
+        let mut sent = std::vec::Vec::new();
+        let mut full = fine.with_range(0, 127);
+        full.set_position(20 * 128 + 3);
+        full.send(&mut sent);
+        assert_eq!(sent, [cc(20, 20), cc(52, 3)]);
+    }
+
+    #[test]
+    fn midi_is_scaled_from_the_range() {
+        let mut mix = Attribute::new("Mix", Channel::C1, Control::new(22))
+            .with_range(20, 120)
+            .with_default(70);
+        let mut sent = std::vec::Vec::new();
+        mix.send(&mut sent);
+        assert_eq!(sent, [cc(22, 64)]);
+
+        assert_eq!(mix.receive(&cc(22, 127)), Some(true));
+        assert_eq!(mix.value, 120);
+        assert_eq!(mix.receive(&cc(22, 0)), Some(true));
+        assert_eq!(mix.value, 20);
+
+        // Wider than 7 bits, in steps of 10.
+        let mut wide = Attribute::new("Time", Channel::C1, Control::new(23))
+            .with_range(0, 250)
+            .with_step(10);
+        wide.step_by(3);
+        assert_eq!(wide.value, 30);
+        wide.step_by(30);
+        assert_eq!((wide.value, wide.midi_7()), (250, 127));
+        assert_eq!(wide.receive(&cc(23, 64)), Some(true));
+        assert_eq!(wide.value, 126);
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let encodings = [
+            Encoding::Control7,
+            Encoding::Control14,
+            Encoding::Parameter(Parameter::NonRegistered(0x3FFF)),
+            Encoding::Parameter(Parameter::Registered(2)),
+            Encoding::PitchBend,
+            Encoding::ChannelPressure,
+        ];
+        for encoding in encodings {
+            let attr = Attribute::new("A", Channel::C1, Control::new(1)).with_encoding(encoding);
+            let mut buf = [0; Attribute::ENCODED_LEN];
+            let mut writer = Writer::new(&mut buf);
+            attr.encode(&mut writer).unwrap();
+            let len = writer.len();
+            assert_eq!(Attribute::decode(&mut Reader::new(&buf[..len])), Some(attr));
+        }
+
+        // Parameter numbers have 14 bits.
+        let fixed = [1, b'A', 0, 20, 0, 10, 5, 5, 0, 0, 0, 0];
+        let decode = |bytes: &[u8]| Attribute::decode(&mut Reader::new(bytes));
+        assert_eq!(
+            decode(&[&fixed[..], &[1, 2, 3, 2, 0x00, 0x40]].concat()),
+            None
+        );
+        assert_eq!(decode(&[&fixed[..], &[1, 2, 1, 6]].concat()), None);
+        // Steps of zero.
+        assert_eq!(decode(&[&fixed[..], &[1, 3, 1, 0]].concat()), None);
+        // A single choice, or a toggle over a range other than 0..=1.
+        assert_eq!(
+            decode(&[&fixed[..], &[1, 5, 5, 3, 1, 0, 1, b'A']].concat()),
+            None
+        );
+        assert_eq!(decode(&[&fixed[..], &[1, 5, 1, 1]].concat()), None);
+        // A single level, or an unknown curve.
+        assert_eq!(decode(&[&fixed[..], &[1, 4, 2, 3, 1]].concat()), None);
+        assert_eq!(decode(&[&fixed[..], &[1, 4, 1, 4]].concat()), None);
+        // A cable the interface doesn't have.
+        assert_eq!(decode(&[&fixed[..], &[1, 7, 1, 3]].concat()), None);
+    }
+
+    #[test]
+    fn encodings_send_their_messages() {
+        let attr = Attribute::new("A", Channel::C2, Control::new(74)).with_default(64);
+        let sent = |encoding| {
+            let mut attr = attr.clone().with_encoding(encoding);
+            attr.set_position(64 * 128 + 1);
+            let mut sent = std::vec::Vec::new();
+            attr.send(&mut sent);
+            sent
+        };
+        let cc = |control: u8, value: u8| {
+            MidiMessage::ControlChange(Channel::C2, Control::new(control), Value7::new(value))
+        };
+
+        assert_eq!(sent(Encoding::Control7), [cc(74, 64)]);
+        assert_eq!(
+            sent(Encoding::Parameter(Parameter::NonRegistered(130))),
+            [cc(99, 1), cc(98, 2), cc(6, 64), cc(38, 1)]
+        );
+        assert_eq!(
+            sent(Encoding::Parameter(Parameter::Registered(0))),
+            [cc(101, 0), cc(100, 0), cc(6, 64), cc(38, 1)]
+        );
+        assert_eq!(
+            sent(Encoding::PitchBend),
+            [MidiMessage::PitchBendChange(
+                Channel::C2,
+                Value14::from(8193u16)
+            )]
+        );
+        assert_eq!(
+            sent(Encoding::ChannelPressure),
+            [MidiMessage::ChannelPressure(Channel::C2, Value7::new(64))]
+        );
+    }
+
+    #[test]
+    fn other_encodings_are_received() {
+        let mut bend =
+            Attribute::new("Bend", Channel::C1, Control::new(1)).with_encoding(Encoding::PitchBend);
+        let message = MidiMessage::PitchBendChange(Channel::C1, Value14::from(8195u16));
+        assert_eq!(bend.receive(&message), Some(true));
+        assert_eq!((bend.value, bend.fine), (64, 3));
+        // The controller isn't used.
+        assert_eq!(bend.receive(&cc(1, 5)), None);
+
+        let nrpn = Parameter::NonRegistered(7);
+        let mut cutoff = Attribute::new("Cutoff", Channel::C1, Control::new(1))
+            .with_encoding(Encoding::Parameter(nrpn));
+        assert_eq!(cutoff.receive_parameter(Channel::C1, nrpn, 300), Some(true));
+        assert_eq!(cutoff.position(), 300);
+        assert_eq!(
+            cutoff.receive_parameter(Channel::C1, Parameter::Registered(7), 1),
+            None
+        );
+        assert_eq!(cutoff.receive_parameter(Channel::C2, nrpn, 1), None);
+    }
+
+    #[test]
+    fn control_pairs_are_received() {
+        let mut attr = Attribute::new("Cutoff", Channel::C1, Control::new(20))
+            .with_encoding(Encoding::Control14);
+        let receive = |attr: &mut Attribute, control: u8, value: u8| {
+            attr.receive(&MidiMessage::ControlChange(
+                Channel::C1,
+                Control::new(control),
+                Value7::new(value),
+            ))
+        };
+
+        assert_eq!(receive(&mut attr, 52, 9), Some(true));
+        assert_eq!(receive(&mut attr, 20, 30), Some(true));
+        assert_eq!((attr.value, attr.fine), (30, 0));
+        assert_eq!(receive(&mut attr, 52, 9), Some(true));
+        assert_eq!(receive(&mut attr, 52, 9), Some(false));
+        assert_eq!(attr.position(), 30 * 128 + 9);
+
+        assert_eq!(receive(&mut attr, 21, 9), None);
+        let other_channel =
+            MidiMessage::ControlChange(Channel::C2, Control::new(20), Value7::new(1));
+        assert_eq!(attr.receive(&other_channel), None);
+    }
+
+    #[test]
+    fn formats_relative_to_range() {
+        let delay = Attribute::new("Delay", Channel::C1, Control::new(20))
+            .with_range(0, 100)
+            .with_default(15)
+            .with_format(Format::Milliseconds(1000));
+        assert_eq!(delay.to_human_readable(), "150 ms");
+
+        let mix = Attribute::new("Mix", Channel::C1, Control::new(22))
+            .with_range(20, 120)
+            .with_default(70)
+            .with_format(Format::Percent);
+        assert_eq!(mix.to_human_readable(), "50 %");
+    }
+
+    #[test]
+    fn curves_shape_display_and_midi() {
+        let delay = Attribute::new("Delay", Channel::C1, Control::new(20))
+            .with_range(0, 100)
+            .with_default(50)
+            .with_format(Format::Milliseconds(1000))
+            .with_curve(Curve::Exponential);
+        assert_eq!(delay.to_human_readable(), "111 ms");
+        let mut sent = std::vec::Vec::new();
+        delay.send(&mut sent);
+        assert_eq!(sent, [cc(20, 14)]);
+
+        let wave = delay
+            .with_curve(Curve::Stepped(3))
+            .with_format(Format::Percent);
+        assert_eq!(wave.to_human_readable(), "50 %");
+        assert_eq!(wave.with_default(24).to_human_readable(), "0 %");
+    }
+
+    fn waveform() -> Attribute {
+        Attribute::new("Wave", Channel::C1, Control::new(70))
+            .with_default(1)
+            .with_kind(Kind::choices(&[("Sine", 0), ("Saw", 40), ("Square", 80)]))
+    }
+
+    #[test]
+    fn enumerated_attributes_send_their_choices() {
+        let mut wave = waveform();
+        assert_eq!((wave.range.min(), wave.range.max()), (0, 2));
+        assert_eq!(wave.to_human_readable(), "Saw");
+
+        let mut sent = std::vec::Vec::new();
+        wave.send(&mut sent);
+        wave.step_by(5);
+        wave.send(&mut sent);
+        assert_eq!(sent, [cc(70, 40), cc(70, 80)]);
+        assert_eq!(wave.to_human_readable(), "Square");
+
+        // Received values pick the nearest choice.
+        assert_eq!(wave.receive(&cc(70, 15)), Some(true));
+        assert_eq!(wave.to_human_readable(), "Sine");
+        assert_eq!(wave.receive(&cc(70, 55)), Some(true));
+        assert_eq!(wave.value, 1);
+
+        let mut fine = waveform().with_encoding(Encoding::PitchBend);
+        fine.set_position(2 * 128 + 50);
+        assert_eq!((fine.fine, fine.midi_14()), (0, 80 << 7));
+    }
+
+    #[test]
+    fn toggles_and_bipolar_values() {
+        let mut sync = Attribute::new("Sync", Channel::C1, Control::new(30))
+            .with_kind(Kind::Toggle)
+            .with_encoding(Encoding::Control14);
+        assert_eq!(sync.to_human_readable(), "Off");
+        assert!(sync.toggle());
+        assert_eq!(
+            (sync.to_human_readable().as_str(), sync.midi_14()),
+            ("On", 0x3FFF)
+        );
+        assert_eq!(sync.receive(&cc(30, 20)), Some(true));
+        assert_eq!(sync.value, 0);
+        assert!(!waveform().toggle());
+
+        // A range set afterwards doesn't replace the toggle's own.
+        let mut sync = sync.with_range(20, 120);
+        assert!(sync.toggle());
+        assert_eq!(sync.value, 1);
+        assert!(sync.toggle());
+        assert_eq!(sync.value, 0);
+
+        let pan = Attribute::new("Pan", Channel::C1, Control::new(10))
+            .with_range(0, 100)
+            .with_kind(Kind::Bipolar)
+            .with_format(Format::Percent);
+        assert_eq!(pan.clone().with_default(50).to_human_readable(), "0 %");
+        assert_eq!(pan.clone().with_default(0).to_human_readable(), "-100 %");
+        assert_eq!(pan.clone().with_default(75).to_human_readable(), "+50 %");
+
+        // Fast turns stop at the centre before crossing it.
+        let mut pan = pan.with_default(20);
+        pan.step_by(60);
+        assert_eq!(pan.value, 50);
+        pan.step_by(-1);
+        assert_eq!(pan.value, 49);
+    }
+
+    #[test]
+    fn tempo_goes_out_as_the_clock() {
+        let mut tempo = Attribute::new("Tempo", Channel::C1, Control::new(20))
+            .with_kind(Kind::Tempo)
+            .with_default(120);
+        assert_eq!((tempo.range.min(), tempo.range.max()), (MIN_BPM, MAX_BPM));
+        assert!(
+            !tempo
+                .clone()
+                .with_encoding(Encoding::Control14)
+                .has_fine_steps()
+        );
+        assert_eq!(tempo.to_human_readable(), "120 BPM");
+
+        let mut sent = std::vec::Vec::new();
+        tempo.send(&mut sent);
+        assert_eq!(sent, []);
+        assert_eq!(tempo.receive(&cc(20, 5)), None);
+    }
+
+    #[test]
+    fn learning_keeps_what_the_binding_allows() {
+        let pitch = Attribute::new("Pitch", Channel::C1, Control::new(1))
+            .with_encoding(Encoding::Control14)
+            .with_default(64);
+        let mut fine = pitch.clone();
+        fine.set_position(64 * 128 + 5);
+
+        // Either half of a 14-bit pair binds it.
+        let mut learned = fine.clone();
+        assert!(learned.learn(&cc(39, 0)));
+        assert_eq!(
+            (learned.encoding, u8::from(learned.control), learned.fine),
+            (Encoding::Control14, 7, 5)
+        );
+        // Controllers without a fine half drop to 7 bits and the fine position.
+        let mut learned = fine.clone();
+        assert!(learned.learn(&cc(74, 0)));
+        assert_eq!((learned.encoding, learned.fine), (Encoding::Control7, 0));
+
+        let mut learned = pitch.clone();
+        let bend = MidiMessage::PitchBendChange(Channel::C10, Value14::from(0u16));
+        assert!(learned.learn(&bend));
+        assert_eq!(
+            (learned.encoding, learned.channel),
+            (Encoding::PitchBend, Channel::C10)
+        );
+
+        // Parameter selection and Data Entry wait for the whole parameter.
+        let mut learned = pitch;
+        assert!(!learned.learn(&cc(99, 1)));
+        assert!(!learned.learn(&cc(6, 1)));
+        assert!(!learned.learn(&MidiMessage::TimingClock));
+        learned.learn_parameter(Channel::C2, Parameter::Registered(0));
+        assert_eq!(
+            learned.encoding,
+            Encoding::Parameter(Parameter::Registered(0))
+        );
+    }
+
+    #[test]
+    fn synced_attributes_step_through_divisions() {
+        const BEAT_US: u32 = 500_000;
+        let delay = Attribute::new("Delay", Channel::C1, Control::new(20))
+            .with_range(0, 100)
+            .with_format(Format::Milliseconds(1000))
+            .with_curve(Curve::Exponential);
+        assert_eq!(delay.division(BEAT_US), None);
+
+        let mut delay = delay.with_tempo_sync();
+        let label = |delay: &Attribute| DIVISIONS[delay.division(BEAT_US).unwrap()].label;
+        delay.set_division(8, BEAT_US);
+        assert_eq!(label(&delay), "1/4");
+        assert!(delay.step_division(2, BEAT_US));
+        assert_eq!(label(&delay), "1/4.");
+
+        // Only divisions up to the longest duration are offered.
+        assert!(delay.step_division(10, BEAT_US));
+        assert_eq!(label(&delay), "1/2");
+        assert_eq!(delay.to_human_readable(), "1000 ms");
+        assert!(delay.step_division(-20, BEAT_US));
+        assert_eq!(label(&delay), "1/32");
+
+        // Even 1/32 is too long at 6 BPM.
+        assert!(!delay.step_division(1, 10_000_000));
+        let mut pan = Attribute::new("Pan", Channel::C1, Control::new(10)).with_tempo_sync();
+        assert!(!pan.step_division(1, BEAT_US));
+    }
+}
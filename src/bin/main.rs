@@ -9,16 +9,18 @@
 
 use defmt::info;
 use embassy_executor::Spawner;
-use embassy_time::Timer;
 use esp_hal::clock::CpuClock;
 use esp_hal::gpio::{Input, InputConfig, Pull};
 use esp_hal::timer::timg::TimerGroup;
 use esp_println as _;
 
+use crate::modules::config;
+use crate::modules::din::din_task;
 use crate::modules::display::display_task;
 use crate::modules::midi::usb_task;
 use crate::modules::rotary_encoder::rotary_encoder_task;
-use crate::modules::state::{BUTTON_PRESSED, state_task};
+use crate::modules::state::{BUTTON_CHANGED, STATE, clock_task, state_task};
+use crate::modules::storage::storage_task;
 
 pub mod modules;
 
@@ -52,6 +54,13 @@ async fn main(spawner: Spawner) -> ! {
 
     info!("Embassy initialized!");
 
+    {
+        let mut state = STATE.lock().await;
+        state.set_attributes(config::attributes());
+        state.set_echo_policy(config::ECHO_POLICY);
+        state.set_program_channel(config::PROGRAM_CHANNEL);
+    }
+
     let input_cfg = InputConfig::default().with_pull(Pull::Up);
     let mut re_key = Input::new(peripherals.GPIO18, input_cfg);
 
@@ -71,8 +80,17 @@ async fn main(spawner: Spawner) -> ! {
         ))
         .unwrap();
 
+    spawner.spawn(storage_task(peripherals.FLASH)).unwrap();
+
     spawner.spawn(state_task()).unwrap();
 
+    spawner.spawn(clock_task()).unwrap();
+
+    let (din_rx, din_tx) = config::din_pins!(peripherals);
+    spawner
+        .spawn(din_task(peripherals.UART1, din_rx.into(), din_tx.into()))
+        .unwrap();
+
     spawner
         .spawn(usb_task(
             peripherals.USB0,
@@ -81,9 +99,9 @@ async fn main(spawner: Spawner) -> ! {
         ))
         .unwrap();
 
+    // Every edge is passed on; the state task debounces them and recognises gestures.
     loop {
-        re_key.wait_for_falling_edge().await;
-        BUTTON_PRESSED.signal(());
-        Timer::after_millis(200).await;
+        re_key.wait_for_any_edge().await;
+        BUTTON_CHANGED.signal(re_key.is_low());
     }
 }
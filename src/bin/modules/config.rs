@@ -0,0 +1,95 @@
+use midi::attribute::{Attribute, Attributes, Format, Kind, Visual};
+use midi::button::Timings;
+use midi::state::EchoPolicy;
+use midi::value::Curve;
+use midi_convert::midi_types::{Channel, Control};
+
+/// Whether values automated by the host are sent back to it.
+pub const ECHO_POLICY: EchoPolicy = EchoPolicy::Suppress;
+
+/// Channel whose Program Change messages recall presets, `None` to ignore them.
+pub const PROGRAM_CHANNEL: Option<Channel> = Some(Channel::C1);
+
+/// Pulse counter steps per encoder click. Both edges of both channels are counted, so this is 4
+/// for most encoders, or 2 for encoders that click every half cycle.
+pub const COUNTS_PER_DETENT: u8 = 4;
+
+/// Swaps the turning direction, for encoders wired the other way round.
+pub const INVERT_ENCODER: bool = false;
+
+/// Encoder button timings. A click goes out once the double click window has passed, so a
+/// shorter window makes clicks snappier; `0` turns double clicks off.
+pub const BUTTON_TIMINGS: Timings = Timings {
+    debounce_ms: 10,
+    double_click_ms: 250,
+    long_press_ms: 600,
+};
+
+/// Outgoing messages that can wait for the USB endpoint. Control Changes are
+/// coalesced per controller, so this only has to cover bursts of other messages.
+pub const MIDI_QUEUE_SIZE: usize = 64;
+
+/// Leave out the RPN/NRPN number when a value is sent for the parameter selected last on its
+/// channel. Only enable this if nothing else selects parameters on the channels in use.
+pub const CACHE_PARAMETER_NUMBERS: bool = false;
+
+/// Longest incoming SysEx message, including `F0` and `F7`. Longer messages
+/// are discarded and answered with an error.
+pub const SYSEX_RECEIVE_SIZE: usize = 4096;
+
+/// Longest SysEx message passed between the USB thru cable and the DIN port, including `F0` and
+/// `F7`. Longer messages are dropped.
+pub const THRU_SYSEX_SIZE: usize = 512;
+
+/// Leave out repeated status bytes on the DIN port. Turn this off for gear that doesn't follow
+/// running status.
+pub const DIN_RUNNING_STATUS: bool = true;
+
+/// The DIN port's input and output pins, taken from the peripherals. Any free pins work; the UART
+/// reaches them through the GPIO matrix.
+macro_rules! din_pins {
+    ($peripherals:ident) => {
+        ($peripherals.GPIO16, $peripherals.GPIO17)
+    };
+}
+pub(crate) use din_pins;
+
+/// The parameters exposed by the device, in the order the button cycles through them.
+///
+/// Up to [`midi::attribute::MAX_ATTRIBUTES`] entries are supported. Values stay within the range,
+/// move by the step per detent, and are scaled onto the full MIDI range when sent, so a `0..=100`
+/// entry still reaches `127`. A [`Curve`] shapes both the value sent and the value shown, e.g.
+/// exponential for times and frequencies. Fast turns are accelerated by
+/// [`midi::encoder::Acceleration::default`] unless an entry sets its own acceleration, or
+/// [`midi::encoder::Acceleration::OFF`] for single steps only. Entries on controllers below 32
+/// can be sent as 14-bit pairs with [`midi::attribute::Encoding::Control14`]; see
+/// [`midi::attribute::Encoding`] for RPN, NRPN, pitch bend and channel pressure. Holding the button
+/// while turning adjusts the 14-bit encodings in fine steps. Toggles, labelled choices and bipolar
+/// values are set up with [`Attribute::with_kind`], see [`midi::attribute::Kind`]. Millisecond
+/// entries with [`Attribute::with_tempo_sync`] step through note divisions of the MIDI clock's
+/// tempo. With a [`Kind::Tempo`] entry the device sends its own clock whenever it isn't receiving
+/// one; on that page presses tap the tempo, turning while holding the button plays or stops, and a
+/// long press moves on. Elsewhere a click followed by holding the button learns the channel and
+/// controller of the shown entry from the next Control Change, NRPN or RPN received over USB.
+/// Entries are sent and received on the control cable unless [`Attribute::with_cable`] routes
+/// them to another [`midi::usb_midi::Cable`].
+pub fn attributes() -> Attributes {
+    Attributes::from_iter([
+        Attribute::new("Delay", Channel::C1, Control::new(20))
+            .with_range(0, 100)
+            .with_default(15)
+            .with_format(Format::Milliseconds(1000))
+            .with_curve(Curve::Exponential)
+            .with_tempo_sync()
+            .with_visual(Visual::Ripple),
+        Attribute::new("Feedback", Channel::C1, Control::new(21))
+            .with_range(0, 100)
+            .with_default(50)
+            .with_format(Format::Percent)
+            .with_visual(Visual::Waves),
+        // The tempo isn't sent as a controller, so the control is unused.
+        Attribute::new("Tempo", Channel::C1, Control::new(0))
+            .with_kind(Kind::Tempo)
+            .with_default(120),
+    ])
+}
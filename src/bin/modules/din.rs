@@ -0,0 +1,166 @@
+//! DIN (or TRS) MIDI through a UART.
+//!
+//! Everything the device sends goes out here as well as over USB, so the attributes drive
+//! hardware synths directly. The USB thru cable is passed through in both directions, which makes
+//! the device a USB to DIN interface: whatever the host sends on it comes out of the DIN port, and
+//! whatever arrives at the DIN port goes to the host on it. The routing matrix decides what
+//! actually passes, see [`crate::modules::routing`], and can also send the DIN input back out.
+//!
+//! Every source is merged into the one output by a [`Merger`], which keeps SysEx in one piece.
+
+use core::cell::RefCell;
+
+use defmt::warn;
+use embassy_futures::join::join;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use esp_hal::Async;
+use esp_hal::gpio::AnyPin;
+use esp_hal::peripherals::UART1;
+use esp_hal::uart::{Config, Uart, UartRx, UartTx};
+use heapless::Vec;
+use midi::din::{BAUD_RATE, Event, Merger, Parser};
+use midi::reassembler;
+use midi::routing::{Destination, Source};
+use midi::usb_midi::Cable;
+use midi_convert::midi_types::MidiMessage;
+use static_cell::ConstStaticCell;
+
+use crate::modules::config::{DIN_RUNNING_STATUS, MIDI_QUEUE_SIZE, THRU_SYSEX_SIZE};
+use crate::modules::midi::{send_thru, send_thru_sysex};
+use crate::modules::routing;
+
+/// A SysEx message passed between the USB thru cable and the DIN port.
+pub type ThruSysEx = Vec<u8, THRU_SYSEX_SIZE>;
+
+/// Collects SysEx from the DIN input. It holds up to [`THRU_SYSEX_SIZE`] bytes, so it is a static
+/// rather than part of [`din_task`]'s future.
+static PARSER: ConstStaticCell<Parser<THRU_SYSEX_SIZE>> = ConstStaticCell::new(Parser::new());
+
+/// Bytes of SysEx written at a time, so the clock can go out in between: at 31250 baud eight
+/// bytes take 2.6 ms.
+const SYSEX_CHUNK: usize = 8;
+
+/// Messages waiting for the DIN port. At 31250 baud a Control Change takes a millisecond, so the
+/// device's own values coalesce here just like for USB, while passed through messages keep every
+/// value.
+pub static DIN_QUEUE: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Merger<MIDI_QUEUE_SIZE, THRU_SYSEX_SIZE>>,
+> = Mutex::new(RefCell::new(Merger::new()));
+
+/// SysEx waiting for the DIN port while another message is going out.
+static DIN_SYSEX: Channel<CriticalSectionRawMutex, ThruSysEx, 1> = Channel::new();
+
+/// Set whenever a message or SysEx is queued for the DIN port, so the UART transmit loop picks it
+/// up.
+pub static DIN_PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Queues a message passed through from `source` for the DIN port, if it is routed there. Unlike
+/// the device's own values it is never coalesced.
+pub fn send_din(source: Source, message: MidiMessage) {
+    if routing::passes(source, Destination::Din, &message) {
+        DIN_QUEUE.lock(|merger| merger.borrow_mut().push_in_order(Cable::Thru, message));
+        DIN_PENDING.signal(());
+    }
+}
+
+/// Queues a complete SysEx message from `source` for the DIN port, if it is routed there, waiting
+/// while others are still going out.
+pub async fn send_din_sysex(source: Source, message: ThruSysEx) {
+    if routing::passes_sysex(source, Destination::Din) {
+        DIN_SYSEX.send(message).await;
+        DIN_PENDING.signal(());
+    }
+}
+
+#[embassy_executor::task]
+pub async fn din_task(uart1: UART1<'static>, rx: AnyPin<'static>, tx: AnyPin<'static>) {
+    let config = Config::default().with_baudrate(BAUD_RATE);
+    let uart = Uart::new(uart1, config)
+        .unwrap()
+        .with_rx(rx)
+        .with_tx(tx)
+        .into_async();
+    let (mut rx, mut tx) = uart.split();
+
+    join(receive(&mut rx), transmit(&mut tx)).await;
+}
+
+/// Passes everything received on the DIN port on to the host, and back out as routed.
+async fn receive(rx: &mut UartRx<'static, Async>) {
+    let parser = PARSER.take();
+    let mut buffer = [0; 32];
+
+    loop {
+        let size = match rx.read_async(&mut buffer).await {
+            Ok(size) => size,
+            Err(error) => {
+                warn!("DIN MIDI receive error: {:?}", defmt::Debug2Format(&error));
+                continue;
+            }
+        };
+
+        for &byte in &buffer[..size] {
+            match parser.push(byte) {
+                Some(Event::Message(message)) => {
+                    send_thru(Source::Din, message);
+                    send_din(Source::Din, message);
+                }
+                Some(Event::SysEx(reassembler::Event::Message(message))) => {
+                    // The parser keeps no more than fits.
+                    if let Ok(message) = ThruSysEx::from_slice(message) {
+                        send_thru_sysex(Source::Din, message.clone());
+                        send_din_sysex(Source::Din, message).await;
+                    }
+                }
+                Some(Event::SysEx(reassembler::Event::Dropped { reason, .. })) => {
+                    warn!("DIN SysEx dropped: {:?}", defmt::Debug2Format(&reason));
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Sends queued messages, and SysEx in one piece between them.
+async fn transmit(tx: &mut UartTx<'static, Async>) {
+    DIN_QUEUE.lock(|merger| merger.borrow_mut().set_running_status(DIN_RUNNING_STATUS));
+
+    loop {
+        // The merger hands out a message or a SysEx chunk at a time, so the lock is only held
+        // while taking it, and the clock can go out between chunks of a long message.
+        let mut buffer = [0; SYSEX_CHUNK];
+        loop {
+            let len = DIN_QUEUE.lock(|merger| {
+                let mut merger = merger.borrow_mut();
+                if !merger.is_sending_sysex()
+                    && let Ok(message) = DIN_SYSEX.try_receive()
+                {
+                    merger.push_sysex(&message);
+                }
+                merger.pop(&mut buffer)
+            });
+            if len == 0 {
+                break;
+            }
+            write(tx, &buffer[..len]).await;
+        }
+
+        DIN_PENDING.wait().await;
+    }
+}
+
+async fn write(tx: &mut UartTx<'static, Async>, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match tx.write_async(bytes).await {
+            Ok(written) => bytes = &bytes[written..],
+            Err(error) => {
+                warn!("DIN MIDI send error: {:?}", defmt::Debug2Format(&error));
+                return;
+            }
+        }
+    }
+}
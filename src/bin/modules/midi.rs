@@ -1,177 +1,365 @@
-use core::ptr::addr_of_mut;
+//! USB MIDI device.
+//!
+//! USB is serviced by embassy-usb, woken by the OTG interrupt: incoming messages are handled
+//! as soon as a transfer arrives and queued messages go out as soon as they are queued. The
+//! target is a SysEx round trip, as measured by `midi-cli latency`, below 5 ms.
+//!
+//! The interface has a jack pair per [`Cable`], which hosts show as separate ports so a DAW can
+//! route the controls and the clock independently. embassy-usb leaves the jacks unnamed, so hosts
+//! number the ports in cable order: control, clock, thru. Incoming clock and transport are only
+//! followed on the clock cable, SysEx requests only answered on the control cable, and values
+//! reach the attributes routed to the cable they arrive on. The thru cable is passed to and from
+//! the DIN port, see [`crate::modules::din`]. What goes where is up to the routing matrix, see
+//! [`crate::modules::routing`].
 
-use defmt::info;
+use core::cell::RefCell;
+
+use defmt::{info, trace, warn};
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_time::Timer;
-use esp_hal::otg_fs;
+use embassy_sync::channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use embassy_usb::Builder;
+use embassy_usb::class::midi::{MidiClass, Receiver, Sender};
+use embassy_usb::driver::EndpointError;
+use esp_hal::otg_fs::Usb;
+use esp_hal::otg_fs::asynch::{Config as DriverConfig, Driver};
 use esp_hal::peripherals::{GPIO19, GPIO20, USB0};
-use esp_println::println;
 use heapless::Vec;
-use midi_convert::midi_types::MidiMessage;
+use midi::io::MidiSink;
+use midi::outgoing::{OutQueue, Stats};
+use midi::parameter::Parameter;
+use midi::reassembler::{Event, Reassembler};
+use midi::routing::{Destination, Source};
+use midi::sysex::{Message, error_response, process_sysex};
+use midi::usb_midi::{Cable, Packet, packets, sysex_packets};
+use midi_convert::midi_types::{Channel, MidiMessage};
 use midi_convert::parse::MidiTryParseSlice;
-use midi_convert::render_slice::MidiRenderSlice;
-use usb_device::prelude::*;
-use usbd_midi::{CableNumber, UsbMidiClass, UsbMidiEventPacket, UsbMidiPacketReader};
+use static_cell::ConstStaticCell;
+
+use crate::modules::config::{
+    CACHE_PARAMETER_NUMBERS, MIDI_QUEUE_SIZE, SYSEX_RECEIVE_SIZE, THRU_SYSEX_SIZE,
+};
+use crate::modules::din::{DIN_PENDING, DIN_QUEUE, ThruSysEx, send_din, send_din_sysex};
+use crate::modules::routing;
+use crate::modules::state::{CLOCK_CHANGED, STATE};
+use crate::modules::storage::SETTINGS_CHANGED;
+
+const MAX_PACKET_SIZE: usize = 64;
+
+static EP_MEMORY: ConstStaticCell<[u8; 1024]> = ConstStaticCell::new([0; 1024]);
+static CONFIG_DESCRIPTOR: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0; 256]);
+static BOS_DESCRIPTOR: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0; 256]);
+static CONTROL_BUFFER: ConstStaticCell<[u8; 64]> = ConstStaticCell::new([0; 64]);
+
+/// Kept out of the task so the large buffer doesn't live in the task arena.
+static SYSEX_RECEIVER: ConstStaticCell<Reassembler<SYSEX_RECEIVE_SIZE>> =
+    ConstStaticCell::new(Reassembler::new());
+static THRU_RECEIVER: ConstStaticCell<Reassembler<THRU_SYSEX_SIZE>> =
+    ConstStaticCell::new(Reassembler::new());
+
+/// Messages waiting for the USB endpoint.
+pub static MIDI_QUEUE: Mutex<CriticalSectionRawMutex, RefCell<OutQueue<MIDI_QUEUE_SIZE>>> =
+    Mutex::new(RefCell::new(OutQueue::new()));
+
+/// Responses to SysEx requests waiting for the USB endpoint.
+static SYSEX_RESPONSES: channel::Channel<CriticalSectionRawMutex, Message, 2> =
+    channel::Channel::new();
+
+/// SysEx for the thru cable waiting for the USB endpoint.
+static THRU_SYSEX: channel::Channel<CriticalSectionRawMutex, ThruSysEx, 1> =
+    channel::Channel::new();
+
+/// Wakes the sending side of [`usb_task`] when something is waiting to be sent.
+static SEND_PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Feeds the device's own messages into [`MIDI_QUEUE`] for [`usb_task`] to send, and into
+/// [`DIN_QUEUE`] for the DIN port, as far as they are routed there.
+pub struct MidiQueueSink;
+
+impl MidiSink for MidiQueueSink {
+    fn send(&mut self, cable: Cable, message: MidiMessage) {
+        if routing::passes(Source::Local, Destination::Usb, &message) {
+            MIDI_QUEUE.lock(|queue| queue.borrow_mut().push(cable, message));
+            SEND_PENDING.signal(());
+        }
+        if routing::passes(Source::Local, Destination::Din, &message) {
+            DIN_QUEUE.lock(|merger| merger.borrow_mut().push(cable, message));
+            DIN_PENDING.signal(());
+        }
+    }
+
+    fn send_parameter(&mut self, cable: Cable, channel: Channel, parameter: Parameter, value: u16) {
+        // Routes only look at the channel, which every Control Change of the value shares.
+        let [selection, _] = parameter.select(channel);
+        if routing::passes(Source::Local, Destination::Usb, &selection) {
+            MIDI_QUEUE.lock(|queue| {
+                queue
+                    .borrow_mut()
+                    .push_parameter(cable, channel, parameter, value)
+            });
+            SEND_PENDING.signal(());
+        }
+        if routing::passes(Source::Local, Destination::Din, &selection) {
+            DIN_QUEUE.lock(|merger| {
+                merger
+                    .borrow_mut()
+                    .push_parameter(cable, channel, parameter, value)
+            });
+            DIN_PENDING.signal(());
+        }
+    }
+}
 
-static mut EP_MEMORY: [u32; 1024] = [0; 1024];
-const SYSEX_BUFFER_SIZE: usize = 64;
+/// Queues a message passed through from `source` for the host on the thru cable, if it is routed
+/// there. Unlike the device's own values it is never coalesced.
+pub fn send_thru(source: Source, message: MidiMessage) {
+    if routing::passes(source, Destination::Usb, &message) {
+        MIDI_QUEUE.lock(|queue| queue.borrow_mut().push_in_order(Cable::Thru, message));
+        SEND_PENDING.signal(());
+    }
+}
 
-pub static MIDI_QUEUE: Channel<CriticalSectionRawMutex, MidiMessage, 16> = Channel::new();
+/// Queues a complete SysEx message from `source` for the host on the thru cable, if it is routed
+/// there. It is dropped while the previous one is still waiting: without a host nothing takes it,
+/// and waiting would hold up the receiving side.
+pub fn send_thru_sysex(source: Source, message: ThruSysEx) {
+    if routing::passes_sysex(source, Destination::Usb) {
+        if THRU_SYSEX.try_send(message).is_err() {
+            warn!("Thru SysEx dropped, the host isn't taking it");
+            return;
+        }
+        SEND_PENDING.signal(());
+    }
+}
 
 #[embassy_executor::task]
 pub async fn usb_task(usb0: USB0<'static>, usb_dp: GPIO20<'static>, usb_dm: GPIO19<'static>) {
-    let usb_bus_allocator = otg_fs::UsbBus::new(otg_fs::Usb::new(usb0, usb_dp, usb_dm), unsafe {
-        &mut *addr_of_mut!(EP_MEMORY)
-    });
+    let driver = Driver::new(
+        Usb::new(usb0, usb_dp, usb_dm),
+        EP_MEMORY.take(),
+        DriverConfig::default(),
+    );
+
+    let mut config = embassy_usb::Config::new(0x16c0, 0x5e4);
+    config.manufacturer = Some("Hoot");
+    config.product = Some("Staas MIDI Interface");
+    config.serial_number = Some("12345678");
+    // It's important to use `0` for the class and subclass fields because otherwise the device
+    // will not enumerate correctly on certain hosts.
+    config.device_class = 0;
+    config.device_sub_class = 0;
+    config.device_protocol = 0;
+    config.composite_with_iads = false;
 
-    // Create a MIDI class with 1 input and 1 output jack.
-    let mut midi_class = UsbMidiClass::new(&usb_bus_allocator, 1, 1).unwrap();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.take(),
+        BOS_DESCRIPTOR.take(),
+        &mut [],
+        CONTROL_BUFFER.take(),
+    );
 
-    // Build the device. It's important to use `0` for the class and subclass fields because
-    // otherwise the device will not enumerate correctly on certain hosts.
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus_allocator, UsbVidPid(0x16c0, 0x5e4))
-        .device_class(0)
-        .device_sub_class(0)
-        .strings(&[StringDescriptors::default()
-            .manufacturer("Hoot")
-            .product("Staas MIDI Interface")
-            .serial_number("12345678")])
-        .unwrap()
-        .build();
+    // Create a MIDI class with an input and an output jack per cable.
+    let cables = Cable::COUNT as u8;
+    let midi_class = MidiClass::new(&mut builder, cables, cables, MAX_PACKET_SIZE as u16);
+    let mut usb = builder.build();
+    let (mut sender, mut receiver) = midi_class.split();
+
+    join3(usb.run(), receive(&mut receiver), transmit(&mut sender)).await;
+}
 
-    let mut sysex_receive_buffer = Vec::<u8, SYSEX_BUFFER_SIZE>::new();
+/// Handles messages from the host as soon as they arrive.
+async fn receive(receiver: &mut Receiver<'static, Driver<'static>>) {
+    let sysex_receiver = SYSEX_RECEIVER.take();
+    let thru_receiver = THRU_RECEIVER.take();
+    let mut buffer = [0; MAX_PACKET_SIZE];
 
     loop {
-        if usb_dev.poll(&mut [&mut midi_class]) {
-            // Receive messages.
-            let mut buffer = [0; 64];
-
-            if let Ok(size) = midi_class.read(&mut buffer) {
-                let packet_reader = UsbMidiPacketReader::new(&buffer, size);
-                for packet in packet_reader.into_iter().flatten() {
-                    if !packet.is_sysex() {
-                        // Just a regular 3-byte message that can be processed directly.
-                        let message = MidiMessage::try_parse_slice(packet.payload_bytes());
-                        println!(
-                            "Regular Message, cable: {:?}, message: {:?}",
-                            packet.cable_number(),
-                            message
-                        );
-                    } else {
-                        // If a packet containing a SysEx payload is detected, the data is saved
-                        // into a buffer and processed after the message is complete.
-                        if packet.is_sysex_start() {
-                            info!("SysEx message start");
-                            sysex_receive_buffer.clear();
+        receiver.wait_connection().await;
+        info!("USB MIDI connected");
+
+        while let Ok(size) = receiver.read_packet(&mut buffer).await {
+            for packet in packets(&buffer[..size]) {
+                let Some(cable) = Cable::from_number(packet.cable()) else {
+                    continue;
+                };
+
+                if !packet.is_sysex() {
+                    // Just a regular message that can be processed directly.
+                    let message = MidiMessage::try_parse_slice(packet.payload());
+                    if cable == Cable::Thru
+                        && let Ok(message) = message
+                    {
+                        send_din(Source::Usb, message);
+                        send_thru(Source::Usb, message);
+                    }
+
+                    // Clock ticks come 24 times per beat, too often to log, and are timed
+                    // as they arrive.
+                    if let Ok(
+                        message @ (MidiMessage::TimingClock
+                        | MidiMessage::Start
+                        | MidiMessage::Continue
+                        | MidiMessage::Stop),
+                    ) = message
+                    {
+                        if cable != Cable::Clock {
+                            continue;
                         }
+                        let now = Instant::now().as_micros();
+                        if STATE
+                            .lock()
+                            .await
+                            .receive_clock(&message, now, &mut MidiQueueSink)
+                        {
+                            // Synced attributes may have moved with the tempo.
+                            CLOCK_CHANGED.signal(());
+                            SETTINGS_CHANGED.signal(());
+                        }
+                        continue;
+                    }
 
-                        match sysex_receive_buffer.extend_from_slice(packet.payload_bytes()) {
-                            Ok(_) => {
-                                if packet.is_sysex_end() {
-                                    info!("SysEx message end");
-                                    println!("Buffered SysEx message: {:?}", sysex_receive_buffer);
-
-                                    // Process the SysEx message as request in a separate function
-                                    // and send an optional response back to the host.
-                                    if let Some(response) =
-                                        process_sysex(sysex_receive_buffer.as_ref())
-                                    {
-                                        for chunk in response.chunks(3) {
-                                            let packet = UsbMidiEventPacket::try_from_payload_bytes(
-                                                CableNumber::Cable0,
-                                                chunk,
-                                            );
-                                            match packet {
-                                                Ok(packet) => loop {
-                                                    // Make sure to add some timeout in case the host
-                                                    // does not read the data.
-                                                    let result =
-                                                        midi_class.send_packet(packet.clone());
-                                                    match result {
-                                                        Ok(_) => break,
-                                                        Err(err) => {
-                                                            if err != UsbError::WouldBlock {
-                                                                break;
-                                                            }
-                                                        }
-                                                    }
-                                                },
-                                                Err(err) => {
-                                                    println!(
-                                                        "SysEx response packet error: {:?}",
-                                                        err
-                                                    )
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                info!("SysEx buffer overflow.");
-                                break;
+                    trace!(
+                        "Regular message, cable: {}, message: {:?}",
+                        cable.name(),
+                        defmt::Debug2Format(&message)
+                    );
+
+                    // Keep the knobs in sync with automation coming from the host.
+                    if let Ok(message) = message
+                        && STATE
+                            .lock()
+                            .await
+                            .apply_remote(cable, &message, &mut MidiQueueSink)
+                    {
+                        SETTINGS_CHANGED.signal(());
+                    }
+                    continue;
+                }
+
+                // SysEx payloads are reassembled across packets and processed once the
+                // message is complete. Requests are only taken on the control cable; SysEx on
+                // the thru cable is collected apart and passed on whole.
+                if cable == Cable::Thru {
+                    for &byte in packet.payload() {
+                        if let Some(Event::Message(message)) = thru_receiver.push(byte)
+                            && let Ok(message) = ThruSysEx::from_slice(message)
+                        {
+                            send_din_sysex(Source::Usb, message.clone()).await;
+                            send_thru_sysex(Source::Usb, message);
+                        }
+                    }
+                    continue;
+                }
+                if cable != Cable::Control {
+                    continue;
+                }
+                for &byte in packet.payload() {
+                    let response = match sysex_receiver.push(byte) {
+                        Some(Event::Message(message)) => {
+                            info!("SysEx message of {} bytes", message.len());
+
+                            // Process the SysEx message as request in a separate function
+                            // and send an optional response back to the host.
+                            let mut state = STATE.lock().await;
+                            let response = process_sysex(message, &mut state, &mut MidiQueueSink);
+                            // Routes may have been set.
+                            routing::publish(&state);
+                            drop(state);
+                            if response.is_some() {
+                                // Writes are persisted; the store skips unchanged settings.
+                                SETTINGS_CHANGED.signal(());
+                                // And may have changed the tempo.
+                                CLOCK_CHANGED.signal(());
                             }
+                            response
                         }
+                        Some(Event::Dropped { reason, start }) => {
+                            warn!("SysEx message dropped: {:?}", defmt::Debug2Format(&reason));
+                            error_response(start, reason.into())
+                        }
+                        None => None,
+                    };
+
+                    if let Some(response) = response {
+                        SYSEX_RESPONSES.send(response).await;
+                        SEND_PENDING.signal(());
                     }
                 }
             }
         }
 
-        // Try to send queued packets
-        while let Ok(message) = MIDI_QUEUE.try_receive() {
-            let mut bytes = [0; 3];
-            message.render_slice(&mut bytes);
-            let packet: UsbMidiEventPacket =
-                UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, &bytes).unwrap();
+        info!("USB MIDI disconnected");
+    }
+}
+
+/// Sends queued messages and SysEx responses whenever there are any.
+async fn transmit(sender: &mut Sender<'static, Driver<'static>>) {
+    let mut reported_stats = Stats::default();
+    MIDI_QUEUE.lock(|queue| {
+        queue
+            .borrow_mut()
+            .set_parameter_caching(CACHE_PARAMETER_NUMBERS)
+    });
 
-            match midi_class.send_packet(packet) {
-                Ok(_) => {
-                    println!("Sent MIDI packet {:?}", message);
-                }
-                Err(UsbError::WouldBlock) => {
-                    // Put it back and try later
-                    println!("USB busy, will retry sending MIDI packet");
-                    // let _ = MIDI_QUEUE.try_send(message);
-                    break;
-                }
-                Err(_) => {
-                    println!("Error sending MIDI packet");
-                }
+    loop {
+        sender.wait_connection().await;
+        // Whatever the host selected before is unknown.
+        MIDI_QUEUE.lock(|queue| queue.borrow_mut().forget_parameters());
+
+        // Sending fails once the host disconnects; whatever is left waits for the next
+        // connection.
+        while flush(sender).await.is_ok() {
+            let stats = MIDI_QUEUE.lock(|queue| queue.borrow().stats());
+            if stats != reported_stats {
+                info!(
+                    "MIDI queue: {} coalesced, {} dropped",
+                    stats.coalesced, stats.dropped
+                );
+                reported_stats = stats;
             }
-        }
 
-        // Yield so other async tasks run
-        Timer::after_millis(50).await;
+            SEND_PENDING.wait().await;
+        }
     }
 }
 
-pub fn process_sysex(request: &[u8]) -> Option<Vec<u8, SYSEX_BUFFER_SIZE>> {
-    /// Identity request message.
-    ///
-    /// See section *DEVICE INQUIRY* of the *MIDI 1.0 Detailed Specification* for further details.
-    const IDENTITY_REQUEST: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
-
-    if request == IDENTITY_REQUEST {
-        let mut response = Vec::<u8, SYSEX_BUFFER_SIZE>::new();
-        response
-            .extend_from_slice(&[
-                0xF0, 0x7E, 0x7F, 0x06, 0x02, // Header
-                0x01, // Manufacturer ID
-                0x02, // Family code
-                0x03, // Family code
-                0x04, // Family member code
-                0x05, // Family member code
-                0x00, // Software revision level
-                0x00, // Software revision level
-                0x00, // Software revision level
-                0x00, // Software revision level
-                0xF7,
-            ])
-            .ok();
-
-        return Some(response);
+async fn flush(sender: &mut Sender<'static, Driver<'static>>) -> Result<(), EndpointError> {
+    while let Ok(response) = SYSEX_RESPONSES.try_receive() {
+        send_sysex(sender, Cable::Control, &response).await?;
+    }
+    while let Ok(message) = THRU_SYSEX.try_receive() {
+        send_sysex(sender, Cable::Thru, &message).await?;
+    }
+
+    // Taken off the queue before sending, as newer values may coalesce with queued ones while
+    // the host is busy. The endpoint only fails once the host is gone.
+    while let Some((cable, message)) = MIDI_QUEUE.lock(|queue| queue.borrow_mut().pop()) {
+        let packet = Packet::from_message(cable.number(), &message);
+        sender.write_packet(packet.as_bytes()).await?;
+        trace!("Sent MIDI packet {:?}", defmt::Debug2Format(&message));
     }
 
-    None
+    Ok(())
+}
+
+/// Sends a complete SysEx message on `cable`, as many packets per transfer as fit.
+async fn send_sysex(
+    sender: &mut Sender<'static, Driver<'static>>,
+    cable: Cable,
+    message: &[u8],
+) -> Result<(), EndpointError> {
+    let mut transfer = Vec::<u8, MAX_PACKET_SIZE>::new();
+    for packet in sysex_packets(cable.number(), message) {
+        if transfer.is_full() {
+            sender.write_packet(&transfer).await?;
+            transfer.clear();
+        }
+        transfer.extend_from_slice(packet.as_bytes()).ok();
+    }
+    sender.write_packet(&transfer).await
 }
@@ -1,4 +1,8 @@
+pub mod config;
+pub mod din;
 pub mod display;
 pub mod midi;
 pub mod rotary_encoder;
+pub mod routing;
 pub mod state;
+pub mod storage;
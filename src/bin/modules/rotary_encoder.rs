@@ -1,26 +1,44 @@
-use core::{cell::RefCell, cmp::min};
+use core::cell::Cell;
+use core::cmp::min;
 
 use critical_section::Mutex;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::Timer;
 use esp_hal::{
     gpio::{AnyPin, Input, InputConfig, Pull},
-    handler,
-    interrupt::Priority,
-    pcnt::{Pcnt, channel, unit},
+    pcnt::{Pcnt, channel},
     peripherals::PCNT,
 };
-
-static UNIT0: Mutex<RefCell<Option<unit::Unit<'static, 1>>>> = Mutex::new(RefCell::new(None));
-
-pub static ROTARY_COUNT: Watch<CriticalSectionRawMutex, i16, 1> = Watch::new();
-pub static ROTARY_DELTA: Watch<CriticalSectionRawMutex, i16, 1> = Watch::new();
+use midi::encoder::DetentDecoder;
+use midi::io::EncoderSource;
+
+use crate::modules::config::{COUNTS_PER_DETENT, INVERT_ENCODER};
+
+/// Detents turned since [`RotaryEncoder`] last took them, see [`DetentDecoder`].
+static PENDING_DETENTS: Mutex<Cell<i16>> = Mutex::new(Cell::new(0));
+static MOVED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Detents published by [`rotary_encoder_task`]. Turns that arrive while the
+/// state task is busy add up instead of replacing each other.
+pub struct RotaryEncoder;
+
+impl EncoderSource for RotaryEncoder {
+    async fn delta(&mut self) -> i16 {
+        loop {
+            MOVED.wait().await;
+            let delta = critical_section::with(|cs| PENDING_DETENTS.borrow(cs).take());
+            if delta != 0 {
+                return delta;
+            }
+        }
+    }
+}
 
 #[embassy_executor::task]
 pub async fn rotary_encoder_task(pcnt: PCNT<'static>, s1: AnyPin<'static>, s2: AnyPin<'static>) {
     // Initialize Pulse Counter (PCNT) unit with limits and filter settings
-    let mut pcnt = Pcnt::new(pcnt);
-    pcnt.set_interrupt_handler(interrupt_handler);
+    let pcnt = Pcnt::new(pcnt);
     let u0 = pcnt.unit1;
     u0.set_low_limit(None).unwrap();
     u0.set_high_limit(None).unwrap();
@@ -29,8 +47,8 @@ pub async fn rotary_encoder_task(pcnt: PCNT<'static>, s1: AnyPin<'static>, s2: A
 
     // Pins
     let input_cfg = InputConfig::default().with_pull(Pull::Up);
-    let pin_a = Input::new(s1, input_cfg);
-    let pin_b = Input::new(s2, input_cfg);
+    let mut pin_a = Input::new(s1, input_cfg);
+    let mut pin_b = Input::new(s2, input_cfg);
     let input_a = pin_a.peripheral_input();
     let input_b = pin_b.peripheral_input();
 
@@ -47,55 +65,40 @@ pub async fn rotary_encoder_task(pcnt: PCNT<'static>, s1: AnyPin<'static>, s2: A
     ch1.set_ctrl_mode(channel::CtrlMode::Reverse, channel::CtrlMode::Keep);
     ch1.set_input_mode(channel::EdgeMode::Decrement, channel::EdgeMode::Increment);
 
-    // Enable interrupts and resume pulse counter unit
-    u0.listen();
+    // Resume pulse counter unit
     u0.resume();
     let counter = u0.counter.clone();
 
-    critical_section::with(|cs| UNIT0.borrow_ref_mut(cs).replace(u0));
-
-    // Monitor counter value and print updates
-    let total_sender = ROTARY_COUNT.sender();
-    let delta_sender = ROTARY_DELTA.sender();
-
-    let mut count: u8 = 0;
-    let mut last_value: i16 = 0;
+    let mut decoder = DetentDecoder::new(COUNTS_PER_DETENT, INVERT_ENCODER);
+    decoder.update(counter.get());
 
     loop {
-        Timer::after_millis(100).await;
-        let current_value = counter.get();
-
-        if current_value == last_value {
-            continue;
-        }
-
-        let delta = current_value.wrapping_sub(last_value);
-        delta_sender.send(delta);
-        last_value = current_value;
-
-        let new_count = saturating_add_custom_range(count, delta, 0, 100);
-
-        if new_count == count {
-            continue;
+        // The pins are only watched for activity; the counter does the decoding. Nothing runs
+        // while the encoder is at rest.
+        select(pin_a.wait_for_any_edge(), pin_b.wait_for_any_edge()).await;
+
+        // Keep reading until the counter settles, so edges that arrive in the meantime don't
+        // wait for the next turn. The pause lets the glitch filter count an edge and bounces die
+        // down.
+        let mut last_value = None;
+        loop {
+            Timer::after_micros(500).await;
+            let value = counter.get();
+            if last_value == Some(value) {
+                break;
+            }
+            last_value = Some(value);
+
+            let delta = decoder.update(value);
+            if delta == 0 {
+                continue;
+            }
+
+            critical_section::with(|cs| {
+                let pending = PENDING_DETENTS.borrow(cs);
+                pending.set(pending.get().saturating_add(delta));
+            });
+            MOVED.signal(());
         }
-
-        count = new_count;
-        total_sender.send(count as i16);
     }
 }
-
-fn saturating_add_custom_range(value: u8, delta: i16, min: u8, max: u8) -> u8 {
-    let new = value.saturating_add_signed(delta as i8);
-    new.clamp(min, max)
-}
-
-#[handler(priority = Priority::Priority2)]
-fn interrupt_handler() {
-    critical_section::with(|cs| {
-        let mut u0 = UNIT0.borrow_ref_mut(cs);
-        let u0 = u0.as_mut().unwrap();
-        if u0.interrupt_is_set() {
-            u0.reset_interrupt();
-        }
-    });
-}
@@ -0,0 +1,32 @@
+//! The routing matrix as seen by the transports.
+//!
+//! [`STATE`](crate::modules::state::STATE) owns the matrix, which is edited on the device and over
+//! SysEx and saved with the settings. Messages are routed where the state can't be waited for,
+//! e.g. by [`MidiQueueSink`](crate::modules::midi::MidiQueueSink) while the state is locked, so a
+//! copy is published here whenever it may have changed.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use midi::routing::{Destination, Routing, Source};
+use midi::state::State;
+use midi_convert::midi_types::MidiMessage;
+
+static ROUTING: Mutex<CriticalSectionRawMutex, Cell<Routing>> =
+    Mutex::new(Cell::new(Routing::new()));
+
+/// Publishes the routing of `state`, after a restore or an edit.
+pub fn publish(state: &State) {
+    ROUTING.lock(|routing| routing.set(*state.routing()));
+}
+
+/// Whether `message` from `source`, which isn't SysEx, goes to `destination`.
+pub fn passes(source: Source, destination: Destination, message: &MidiMessage) -> bool {
+    ROUTING.lock(|routing| routing.get().passes(source, destination, message))
+}
+
+/// Whether SysEx from `source` goes to `destination`.
+pub fn passes_sysex(source: Source, destination: Destination) -> bool {
+    ROUTING.lock(|routing| routing.get().passes_sysex(source, destination))
+}
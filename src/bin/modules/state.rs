@@ -1,107 +1,128 @@
 use defmt::info;
-use embassy_futures::{
-    select::{Either, select},
-    yield_now,
-};
+use embassy_futures::select::{Either, select};
+use embassy_futures::yield_now;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
-use heapless::{String, format};
-use midi_convert::midi_types::{Channel, Control, MidiMessage, Value7};
-
-use crate::modules::{display::map_range, midi::MIDI_QUEUE, rotary_encoder::ROTARY_DELTA};
+use embassy_time::{Instant, Timer};
+use midi::button::{Gesture, Gestures};
+use midi::io::{ButtonSource, Input, next_input};
+use midi::state::State;
+
+use crate::modules::{
+    config::BUTTON_TIMINGS, midi::MidiQueueSink, rotary_encoder::RotaryEncoder, routing,
+    storage::SETTINGS_CHANGED,
+};
 
 pub type SharedState = Mutex<CriticalSectionRawMutex, State>;
 
-pub static STATE: SharedState = Mutex::new(State {
-    attributes: [
-        Attribute {
-            name: "Delay",
-            channel: Channel::C1,
-            control: Control::new(20),
-            min: 0,
-            max: 100,
-            value: 15,
-            to_human_readable: |v| format!("{} ms", map_range((0, 100), (0, 1000), v)).unwrap(),
-        },
-        Attribute {
-            name: "Feedback",
-            channel: Channel::C1,
-            control: Control::new(21),
-            min: 0,
-            max: 100,
-            value: 50,
-            to_human_readable: |v| format!("{} %", map_range((0, 100), (0, 100), v)).unwrap(),
-        },
-    ],
-    selected_option: 0,
-});
-
-pub static BUTTON_PRESSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static STATE: SharedState = Mutex::new(State::new());
+
+/// Wakes [`clock_task`] when the tempo or the transport may have changed.
+pub static CLOCK_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raw button level after an edge, `true` while pressed.
+pub static BUTTON_CHANGED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// The encoder push button, fed by the edge loop in `main`.
+pub struct EncoderButton;
+
+impl ButtonSource for EncoderButton {
+    async fn changed(&mut self) -> bool {
+        BUTTON_CHANGED.wait().await
+    }
+}
 
 #[embassy_executor::task]
 pub async fn state_task() {
-    let mut delta_receiver = ROTARY_DELTA.receiver().unwrap();
+    let mut encoder = RotaryEncoder;
+    let mut button = EncoderButton;
+    let mut gestures = Gestures::new(BUTTON_TIMINGS);
+    let mut sink = MidiQueueSink;
 
     loop {
-        match select(delta_receiver.changed(), BUTTON_PRESSED.wait()).await {
-            Either::First(delta) => {
-                STATE.lock().await.adjust_selected(delta).await;
-            }
-            Either::Second(_) => {
-                STATE.lock().await.next_option();
+        // Presses tap the tempo on its page, wherever the selection came from.
+        gestures.set_taps(STATE.lock().await.taps_tempo());
+
+        // Wake up for gesture timeouts too, e.g. a click once no double click followed.
+        let input = match gestures.deadline() {
+            Some(deadline) => {
+                let timeout = Timer::at(Instant::from_millis(deadline));
+                match select(next_input(&mut encoder, &mut button), timeout).await {
+                    Either::First(input) => Some(input),
+                    Either::Second(_) => None,
+                }
             }
+            None => Some(next_input(&mut encoder, &mut button).await),
+        };
+        let now = Instant::now().as_millis();
+
+        let (gesture, turn) = match input {
+            Some(Input::Turn(delta)) => match gestures.turn(delta) {
+                held @ Some(Gesture::PressAndTurn(_)) => (held, None),
+                pending_click => (pending_click, Some(delta)),
+            },
+            Some(Input::Button(pressed)) => (gestures.update(pressed, now), None),
+            None => (gestures.poll(now), None),
         };
+        if gesture.is_none() && turn.is_none() {
+            continue;
+        }
+
+        let mut state = STATE.lock().await;
+        if let Some(gesture) = gesture {
+            info!("Button: {}", defmt::Debug2Format(&gesture));
+            if let Some(action) = state.gesture(gesture, now, &mut sink) {
+                info!("Preset action: {}", defmt::Debug2Format(&action));
+            }
+            match (state.selected(), state.route_cursor()) {
+                (Some(attr), _) => info!("Selected option: {}", attr.name.as_str()),
+                (None, Some(_)) => info!("Selected option: routing"),
+                (None, None) => info!("Selected option: presets"),
+            }
+            // Clicks on the routing page switch routes.
+            routing::publish(&state);
+        }
+        if let Some(delta) = turn {
+            state.turn(delta, now, &mut sink);
+            if let Some(attr) = state.selected() {
+                info!(
+                    "{} adjusted to {} ({})",
+                    attr.name.as_str(),
+                    attr.value,
+                    delta
+                );
+            }
+        }
+        drop(state);
+        SETTINGS_CHANGED.signal(());
+        CLOCK_CHANGED.signal(());
 
         // Do some work...
         yield_now().await;
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Attribute {
-    pub name: &'static str,
-    pub channel: Channel,
-    pub control: Control,
-    pub min: u8,
-    pub max: u8,
-    pub value: u8,
-    pub to_human_readable: fn(u8) -> String<32>,
-}
-
-pub type Attributes = [Attribute; 2];
-
-pub struct State {
-    attributes: Attributes,
-    selected_option: usize,
-}
-
-impl State {
-    pub fn attributes(&self) -> Attributes {
-        self.attributes.clone()
-    }
-
-    pub fn selected_option(&self) -> usize {
-        self.selected_option
-    }
-
-    pub async fn adjust_selected(&mut self, delta: i16) {
-        if let Some(attr) = self.attributes.get_mut(self.selected_option) {
-            let new_value =
-                (attr.value as i16 + delta).clamp(attr.min as i16, attr.max as i16) as u8;
-            attr.value = new_value;
-
-            info!("{} adjusted to {} ({})", attr.name, attr.value, delta);
-
-            let packet =
-                MidiMessage::ControlChange(attr.channel, attr.control, Value7::from(attr.value));
+/// Sends the clock as clock master, and hands back to it once a followed clock stops.
+#[embassy_executor::task]
+pub async fn clock_task() {
+    let mut sink = MidiQueueSink;
 
-            MIDI_QUEUE.try_send(packet).ok();
+    loop {
+        // The deadline moves with every tick received, and the schedule with the tempo.
+        let deadline = STATE.lock().await.clock_deadline();
+        match deadline {
+            Some(deadline) => {
+                select(
+                    CLOCK_CHANGED.wait(),
+                    Timer::at(Instant::from_micros(deadline)),
+                )
+                .await;
+            }
+            None => CLOCK_CHANGED.wait().await,
         }
-    }
 
-    pub fn next_option(&mut self) {
-        self.selected_option = (self.selected_option + 1) % self.attributes.len();
-        if let Some(attr) = self.attributes.get(self.selected_option) {
-            info!("Selected option: {}", attr.name);
+        let now = Instant::now().as_micros();
+        if STATE.lock().await.poll_clock(now, &mut sink) {
+            info!("MIDI clock stopped");
         }
     }
 }
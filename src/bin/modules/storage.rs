@@ -0,0 +1,62 @@
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Timer;
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
+};
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use midi::storage::Store;
+
+use crate::modules::routing;
+use crate::modules::state::STATE;
+
+/// Quiet time after the last edit before the settings are written.
+const SAVE_DELAY_MS: u64 = 2000;
+
+/// Raised whenever something that is persisted changes.
+pub static SETTINGS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Restores the saved settings on boot and writes them back after edits stop.
+///
+/// Records live in the `nvs` data partition of the default partition table,
+/// which nothing else on this firmware uses.
+#[embassy_executor::task]
+pub async fn storage_task(flash: FLASH<'static>) {
+    let mut flash = FlashStorage::new(flash);
+
+    let mut table_buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(&mut flash, &mut table_buffer).unwrap();
+    let partition = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .unwrap()
+        .unwrap();
+
+    let mut store = Store::new(partition.as_embedded_storage(&mut flash));
+
+    match store.load() {
+        Some(settings) => {
+            info!("Restoring saved settings");
+            let mut state = STATE.lock().await;
+            state.restore(&settings);
+            routing::publish(&state);
+        }
+        None => info!("No saved settings, using defaults"),
+    }
+
+    loop {
+        SETTINGS_CHANGED.wait().await;
+
+        // Keep postponing while edits keep coming in.
+        while let Either::First(_) =
+            select(SETTINGS_CHANGED.wait(), Timer::after_millis(SAVE_DELAY_MS)).await
+        {}
+
+        let settings = STATE.lock().await.settings();
+        match store.save(&settings) {
+            Ok(()) => info!("Settings saved"),
+            Err(e) => warn!("Saving settings failed: {:?}", defmt::Debug2Format(&e)),
+        }
+    }
+}
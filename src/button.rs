@@ -0,0 +1,437 @@
+//! Button gesture recognition.
+//!
+//! [`Gestures`] turns raw button levels and encoder turns into clicks, double
+//! clicks, long presses, clicks followed by a long press and turns made while
+//! the button is held. Feed it every edge with [`Gestures::update`] and call
+//! [`Gestures::poll`] once [`Gestures::deadline`] has passed, see the
+//! [crate documentation](crate) on timing.
+//!
+//! For tap tempo, [`Gestures::set_taps`] reports presses as they happen
+//! instead of waiting for clicks.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timings {
+    /// How long a level has to be stable to count.
+    pub debounce_ms: u16,
+    /// Longest pause between the clicks of a double click. Clicks are only
+    /// reported once it passed, so `0` disables double clicks and reports
+    /// clicks right on release.
+    pub double_click_ms: u16,
+    /// How long the button has to be held for a long press.
+    pub long_press_ms: u16,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 10,
+            double_click_ms: 250,
+            long_press_ms: 600,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress,
+    /// A click followed by a long press.
+    ClickAndHold,
+    /// The encoder turned this many detents while the button was held.
+    PressAndTurn(i16),
+    /// A press at the given time while taps are on, see [`Gestures::set_taps`].
+    Tap(u64),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Down since `since`; `second` if it closely followed a click.
+    Down {
+        since: u64,
+        second: bool,
+    },
+    /// Held past a long press or turned; the release does nothing.
+    Consumed,
+    /// Released after a click, waiting whether a second one follows.
+    Released {
+        at: u64,
+    },
+}
+
+pub struct Gestures {
+    timings: Timings,
+    phase: Phase,
+    /// Debounced level.
+    pressed: bool,
+    /// Last raw level and when it was seen.
+    raw: (bool, u64),
+    taps: bool,
+}
+
+impl Gestures {
+    pub const fn new(timings: Timings) -> Self {
+        Self {
+            timings,
+            phase: Phase::Idle,
+            pressed: false,
+            raw: (false, 0),
+            taps: false,
+        }
+    }
+
+    /// Reports every press as a [`Gesture::Tap`] right away, without clicks
+    /// or double clicks. Long presses and turns while held still count.
+    pub fn set_taps(&mut self, enabled: bool) {
+        self.taps = enabled;
+    }
+
+    /// Takes the raw level after an edge at `now_ms`.
+    pub fn update(&mut self, pressed: bool, now_ms: u64) -> Option<Gesture> {
+        // The previous level may have settled without a poll in between.
+        let settled = self.poll(now_ms);
+        if pressed != self.raw.0 {
+            self.raw = (pressed, now_ms);
+        }
+        settled.or_else(|| self.poll(now_ms))
+    }
+
+    /// Reports gestures that completed by `now_ms` without another edge.
+    pub fn poll(&mut self, now_ms: u64) -> Option<Gesture> {
+        let (raw, since) = self.raw;
+        if raw != self.pressed && now_ms.saturating_sub(since) >= self.timings.debounce_ms as u64 {
+            self.pressed = raw;
+            // Time gestures from the edge itself rather than from when it settled.
+            let gesture = if raw {
+                self.on_press(since)
+            } else {
+                self.on_release(since)
+            };
+            if gesture.is_some() {
+                return gesture;
+            }
+        }
+
+        match self.phase {
+            Phase::Down { since, second }
+                if now_ms.saturating_sub(since) >= self.timings.long_press_ms as u64 =>
+            {
+                self.phase = Phase::Consumed;
+                Some(if second {
+                    Gesture::ClickAndHold
+                } else {
+                    Gesture::LongPress
+                })
+            }
+            Phase::Released { at }
+                if now_ms.saturating_sub(at) >= self.timings.double_click_ms as u64 =>
+            {
+                self.phase = Phase::Idle;
+                Some(Gesture::Click)
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes an encoder turn.
+    ///
+    /// While the button is held the turn becomes [`Gesture::PressAndTurn`]
+    /// and shouldn't be applied as a normal turn. Otherwise it's a normal
+    /// turn, preceded by a click that was still waiting for a double click.
+    pub fn turn(&mut self, delta: i16) -> Option<Gesture> {
+        match self.phase {
+            Phase::Down { .. } | Phase::Consumed => {
+                self.phase = Phase::Consumed;
+                Some(Gesture::PressAndTurn(delta))
+            }
+            Phase::Released { .. } => {
+                self.phase = Phase::Idle;
+                Some(Gesture::Click)
+            }
+            Phase::Idle => None,
+        }
+    }
+
+    /// Whether the button is (debounced) down.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// When [`Gestures::poll`] next has something to do, if ever.
+    pub fn deadline(&self) -> Option<u64> {
+        let settle =
+            (self.raw.0 != self.pressed).then(|| self.raw.1 + self.timings.debounce_ms as u64);
+        let timeout = match self.phase {
+            Phase::Down { since, .. } => Some(since + self.timings.long_press_ms as u64),
+            Phase::Released { at } => Some(at + self.timings.double_click_ms as u64),
+            Phase::Idle | Phase::Consumed => None,
+        };
+        match (settle, timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn on_press(&mut self, at: u64) -> Option<Gesture> {
+        if self.taps {
+            self.phase = Phase::Down {
+                since: at,
+                second: false,
+            };
+            return Some(Gesture::Tap(at));
+        }
+
+        let (second, pending_click) = match self.phase {
+            Phase::Released { at: released }
+                if at - released < self.timings.double_click_ms as u64 =>
+            {
+                (true, None)
+            }
+            Phase::Released { .. } => (false, Some(Gesture::Click)),
+            _ => (false, None),
+        };
+        self.phase = Phase::Down { since: at, second };
+        pending_click
+    }
+
+    fn on_release(&mut self, at: u64) -> Option<Gesture> {
+        let (phase, gesture) = match self.phase {
+            Phase::Down { .. } if self.taps => (Phase::Idle, None),
+            Phase::Down { second: true, .. } => (Phase::Idle, Some(Gesture::DoubleClick)),
+            Phase::Down { .. } if self.timings.double_click_ms == 0 => {
+                (Phase::Idle, Some(Gesture::Click))
+            }
+            Phase::Down { .. } => (Phase::Released { at }, None),
+            _ => (Phase::Idle, None),
+        };
+        self.phase = phase;
+        gesture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Step {
+        /// Raw level at a time.
+        Edge(bool, u64),
+        /// Deadline check at a time.
+        Poll(u64),
+        Turn(i16),
+    }
+
+    use Step::*;
+
+    fn run(timings: Timings, steps: &[Step]) -> Vec<Gesture> {
+        feed(&mut Gestures::new(timings), steps)
+    }
+
+    fn feed(gestures: &mut Gestures, steps: &[Step]) -> Vec<Gesture> {
+        steps
+            .iter()
+            .filter_map(|step| match *step {
+                Edge(pressed, at) => gestures.update(pressed, at),
+                Poll(at) => gestures.poll(at),
+                Turn(delta) => gestures.turn(delta),
+            })
+            .collect()
+    }
+
+    fn default(steps: &[Step]) -> Vec<Gesture> {
+        run(Timings::default(), steps)
+    }
+
+    #[test]
+    fn click_waits_for_the_double_click_window() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Edge(false, 200),
+            Poll(210),
+            Poll(449),
+        ];
+        assert_eq!(default(&steps), []);
+        assert_eq!(
+            default(&[Edge(true, 100), Poll(110), Edge(false, 200), Poll(450)]),
+            [Gesture::Click]
+        );
+    }
+
+    #[test]
+    fn clicks_fire_on_release_without_double_clicks() {
+        let timings = Timings {
+            double_click_ms: 0,
+            ..Timings::default()
+        };
+        let steps = [Edge(true, 100), Poll(110), Edge(false, 200), Poll(210)];
+        assert_eq!(run(timings, &steps), [Gesture::Click]);
+    }
+
+    #[test]
+    fn bounces_are_filtered() {
+        let steps = [
+            Edge(true, 100),
+            Edge(false, 102),
+            Edge(true, 104),
+            Poll(114),
+            Edge(false, 200),
+            Edge(true, 201),
+            Edge(false, 203),
+            Poll(213),
+            Poll(453),
+        ];
+        assert_eq!(default(&steps), [Gesture::Click]);
+
+        // A glitch shorter than the debounce time is no press at all.
+        assert_eq!(
+            default(&[Edge(true, 100), Edge(false, 105), Poll(1000)]),
+            []
+        );
+    }
+
+    #[test]
+    fn taps_report_presses_at_once() {
+        let mut gestures = Gestures::new(Timings::default());
+        gestures.set_taps(true);
+        let steps = [
+            Edge(true, 100),
+            Edge(false, 102),
+            Edge(true, 103),
+            Poll(113),
+            Edge(false, 200),
+            Edge(true, 300),
+            Poll(310),
+            Edge(false, 400),
+            Poll(1000),
+            Edge(true, 1100),
+            Poll(1110),
+            Poll(1700),
+        ];
+        // Timed from the edge that settled, with no clicks in between.
+        assert_eq!(
+            feed(&mut gestures, &steps),
+            [
+                Gesture::Tap(103),
+                Gesture::Tap(300),
+                Gesture::Tap(1100),
+                Gesture::LongPress
+            ]
+        );
+    }
+
+    #[test]
+    fn double_click() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Edge(false, 200),
+            Poll(210),
+            Edge(true, 300),
+            Poll(310),
+            Edge(false, 400),
+            Poll(410),
+            Poll(1000),
+        ];
+        assert_eq!(default(&steps), [Gesture::DoubleClick]);
+    }
+
+    #[test]
+    fn click_and_hold() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Edge(false, 200),
+            Poll(210),
+            Edge(true, 300),
+            Poll(310),
+            Poll(900),
+            Edge(false, 1000),
+            Poll(1010),
+            Poll(2000),
+        ];
+        assert_eq!(default(&steps), [Gesture::ClickAndHold]);
+    }
+
+    #[test]
+    fn late_second_press_is_a_new_click() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Edge(false, 200),
+            // Without polls in between, the release still settles and the
+            // press comes too late for a double click.
+            Edge(true, 460),
+            Poll(470),
+            Edge(false, 500),
+            Poll(510),
+            Poll(750),
+        ];
+        assert_eq!(default(&steps), [Gesture::Click, Gesture::Click]);
+    }
+
+    #[test]
+    fn long_press_fires_while_held() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Poll(699),
+            Poll(700),
+            Edge(false, 900),
+            Poll(910),
+            Poll(2000),
+        ];
+        assert_eq!(default(&steps), [Gesture::LongPress]);
+    }
+
+    #[test]
+    fn turning_while_held_is_press_and_turn() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Turn(2),
+            Turn(-1),
+            Poll(800),
+            Edge(false, 900),
+            Poll(910),
+            Poll(2000),
+        ];
+        assert_eq!(
+            default(&steps),
+            [Gesture::PressAndTurn(2), Gesture::PressAndTurn(-1)]
+        );
+    }
+
+    #[test]
+    fn turning_after_a_click_reports_it_first() {
+        let steps = [
+            Edge(true, 100),
+            Poll(110),
+            Edge(false, 200),
+            Poll(210),
+            Turn(1),
+            Turn(1),
+            Poll(1000),
+        ];
+        assert_eq!(default(&steps), [Gesture::Click]);
+    }
+
+    #[test]
+    fn deadlines_follow_the_phase() {
+        let mut gestures = Gestures::new(Timings::default());
+        assert_eq!(gestures.deadline(), None);
+
+        gestures.update(true, 100);
+        assert_eq!(gestures.deadline(), Some(110));
+        gestures.poll(110);
+        assert!(gestures.is_pressed());
+        assert_eq!(gestures.deadline(), Some(700));
+
+        gestures.update(false, 200);
+        gestures.poll(210);
+        assert_eq!(gestures.deadline(), Some(450));
+        assert_eq!(gestures.poll(450), Some(Gesture::Click));
+        assert_eq!(gestures.deadline(), None);
+    }
+}
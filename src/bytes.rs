@@ -0,0 +1,77 @@
+//! Cursor helpers for the binary formats in [`crate::storage`] and [`crate::sysex`].
+
+pub(crate) struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(out: &'a mut [u8]) -> Self {
+        Self { out, len: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, byte: u8) -> Option<()> {
+        *self.out.get_mut(self.len)? = byte;
+        self.len += 1;
+        Some(())
+    }
+
+    pub(crate) fn push_u16(&mut self, value: u16) -> Option<()> {
+        self.extend(&value.to_le_bytes())
+    }
+
+    pub(crate) fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        self.out
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    /// Writes a length byte followed by `bytes`.
+    pub(crate) fn push_counted(&mut self, bytes: &[u8]) -> Option<()> {
+        self.push(u8::try_from(bytes.len()).ok()?)?;
+        self.extend(bytes)
+    }
+}
+
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(byte)
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        let (&bytes, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(u16::from_le_bytes(bytes))
+    }
+
+    /// Reads a length byte and that many bytes.
+    pub(crate) fn counted(&mut self) -> Option<&'a [u8]> {
+        let len = self.byte()? as usize;
+        let bytes = self.0.get(..len)?;
+        self.0 = &self.0[len..];
+        Some(bytes)
+    }
+
+    pub(crate) fn str(&mut self) -> Option<&'a str> {
+        core::str::from_utf8(self.counted()?).ok()
+    }
+}
@@ -0,0 +1,651 @@
+//! Following an incoming MIDI clock, sending our own, and note divisions of
+//! the tempo.
+//!
+//! [`ClockFollower`] estimates the tempo from Timing Clock timestamps. Single
+//! intervals jitter by a good part of a millisecond over USB, so the estimate
+//! averages the last beat's worth of them, skips ticks that are far off and
+//! only reports a new tempo once it moved noticeably. It takes the receive
+//! time with every message and is polled by its deadline, see the
+//! [crate documentation](crate) on timing.
+//!
+//! [`ClockGenerator`] is the other side, for when the device is the clock
+//! master. Ticks are scheduled from the last tempo change rather than from
+//! the previous tick, so a late wake-up doesn't delay the ones after it. The
+//! clock and transport go out on [`Cable::Clock`].
+
+use heapless::Deque;
+use midi_convert::midi_types::MidiMessage;
+
+use crate::io::MidiSink;
+use crate::usb_midi::Cable;
+
+/// Timing Clocks per quarter note.
+pub const TICKS_PER_BEAT: u32 = 24;
+
+/// Without a tick for this long the clock is gone.
+const TIMEOUT_US: u64 = 1_000_000;
+/// Intervals needed before there is an estimate.
+const MIN_INTERVALS: usize = 6;
+/// Intervals in a row that don't fit the estimate before it starts over.
+const MAX_REJECTED: u8 = 3;
+/// Taps further apart than this start a new tempo.
+const TAP_TIMEOUT_MS: u64 = 2_000;
+/// Taps averaged for the tempo.
+const TAPS: usize = 4;
+
+/// Slowest tempo the device sends as clock master.
+pub const MIN_BPM: u8 = 30;
+/// Fastest tempo the device sends as clock master.
+pub const MAX_BPM: u8 = 250;
+
+/// Smallest change of the reported tempo, in tenths of a BPM, unless the
+/// estimate stays on the new value for a beat.
+const HYSTERESIS: u16 = 2;
+
+/// A tempo-synced note length.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Division {
+    /// E.g. `1/8.` for a dotted eighth or `1/8T` for an eighth triplet.
+    pub label: &'static str,
+    pub ticks: u16,
+}
+
+impl Division {
+    /// Length of the division at a beat of `beat_us`, in microseconds.
+    pub fn duration_us(&self, beat_us: u32) -> u32 {
+        (self.ticks as u64 * beat_us as u64 / TICKS_PER_BEAT as u64) as u32
+    }
+}
+
+const fn division(label: &'static str, ticks: u16) -> Division {
+    Division { label, ticks }
+}
+
+/// Straight, dotted and triplet divisions, shortest first.
+pub const DIVISIONS: [Division; 14] = [
+    division("1/32", 3),
+    division("1/16T", 4),
+    division("1/16", 6),
+    division("1/8T", 8),
+    division("1/16.", 9),
+    division("1/8", 12),
+    division("1/4T", 16),
+    division("1/8.", 18),
+    division("1/4", 24),
+    division("1/2T", 32),
+    division("1/4.", 36),
+    division("1/2", 48),
+    division("1/2.", 72),
+    division("1/1", 96),
+];
+
+/// Tempo and transport of a received MIDI clock.
+pub struct ClockFollower {
+    last_tick_us: Option<u64>,
+    /// Recent intervals between ticks, oldest first.
+    intervals: Deque<u32, { TICKS_PER_BEAT as usize }>,
+    rejected: u8,
+    /// Smoothed beat length.
+    smoothed_us: Option<u32>,
+    /// Beat length last reported.
+    beat_us: Option<u32>,
+    /// A tempo just next to the reported one and for how many ticks it held.
+    candidate: (u16, u32),
+    playing: bool,
+}
+
+impl Default for ClockFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockFollower {
+    pub const fn new() -> Self {
+        Self {
+            last_tick_us: None,
+            intervals: Deque::new(),
+            rejected: 0,
+            smoothed_us: None,
+            beat_us: None,
+            candidate: (0, 0),
+            playing: false,
+        }
+    }
+
+    /// Takes a real-time message received at `now_us`. Returns whether the
+    /// reported tempo changed; other messages are ignored.
+    pub fn receive(&mut self, message: &MidiMessage, now_us: u64) -> bool {
+        match message {
+            MidiMessage::TimingClock => return self.tick(now_us),
+            MidiMessage::Start | MidiMessage::Continue => self.playing = true,
+            MidiMessage::Stop => self.playing = false,
+            _ => {}
+        }
+        false
+    }
+
+    /// Forgets the tempo once no tick arrived for a while. Returns whether it did.
+    pub fn poll(&mut self, now_us: u64) -> bool {
+        if self.deadline().is_none_or(|deadline| now_us < deadline) {
+            return false;
+        }
+        *self = Self::new();
+        true
+    }
+
+    /// When [`ClockFollower::poll`] next has something to do, if ever.
+    pub fn deadline(&self) -> Option<u64> {
+        self.beat_us?;
+        Some(self.last_tick_us? + TIMEOUT_US)
+    }
+
+    /// Length of a beat, or `None` without a running clock.
+    pub fn beat_us(&self) -> Option<u32> {
+        self.beat_us
+    }
+
+    /// The tempo in tenths of a BPM, e.g. `1200` for 120 BPM.
+    pub fn bpm_tenths(&self) -> Option<u16> {
+        self.beat_us.map(bpm_tenths)
+    }
+
+    /// Whether the transport is running, after a Start or Continue.
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    fn tick(&mut self, now_us: u64) -> bool {
+        let Some(last) = self.last_tick_us else {
+            self.last_tick_us = Some(now_us);
+            return false;
+        };
+        let interval = now_us.saturating_sub(last);
+        if interval >= TIMEOUT_US {
+            // Resumed after a pause; the gap says nothing about the tempo.
+            self.last_tick_us = Some(now_us);
+            self.intervals.clear();
+            return false;
+        }
+
+        let interval = interval as u32;
+        if self.intervals.len() >= MIN_INTERVALS {
+            let beat = self.average_beat();
+            let (short, long) = (beat / TICKS_PER_BEAT / 2, beat / TICKS_PER_BEAT * 3 / 2);
+            // A missed tick, or an extra one that the next interval is
+            // measured across, unless it keeps happening and the tempo really
+            // jumped.
+            if !(short..=long).contains(&interval) {
+                self.rejected += 1;
+                if self.rejected >= MAX_REJECTED {
+                    self.intervals.clear();
+                    self.rejected = 0;
+                    self.last_tick_us = Some(now_us);
+                } else if interval > long {
+                    self.last_tick_us = Some(now_us);
+                }
+                return false;
+            }
+        }
+        self.rejected = 0;
+        self.last_tick_us = Some(now_us);
+
+        if self.intervals.is_full() {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(interval).ok();
+        if self.intervals.len() < MIN_INTERVALS {
+            return false;
+        }
+
+        let raw = self.average_beat();
+        let smoothed = match self.smoothed_us {
+            // Follow real tempo changes right away.
+            Some(smoothed) if raw.abs_diff(smoothed) <= smoothed / 16 => {
+                (smoothed as i64 + (raw as i64 - smoothed as i64) / 4) as u32
+            }
+            _ => raw,
+        };
+        self.smoothed_us = Some(smoothed);
+
+        let tenths = bpm_tenths(smoothed);
+        let moved = match self.bpm_tenths() {
+            Some(reported) if reported == tenths => false,
+            Some(reported) if reported.abs_diff(tenths) < HYSTERESIS => {
+                let (candidate, ticks) = &mut self.candidate;
+                if *candidate != tenths {
+                    *candidate = tenths;
+                    *ticks = 0;
+                }
+                *ticks += 1;
+                *ticks >= TICKS_PER_BEAT
+            }
+            _ => true,
+        };
+        if moved {
+            self.beat_us = Some(smoothed);
+            self.candidate = (0, 0);
+        }
+        moved
+    }
+
+    /// The average interval, times a beat's worth of ticks.
+    fn average_beat(&self) -> u32 {
+        let sum: u64 = self.intervals.iter().map(|&interval| interval as u64).sum();
+        let len = self.intervals.len().max(1) as u64;
+        ((sum * TICKS_PER_BEAT as u64 + len / 2) / len) as u32
+    }
+}
+
+/// Turns button taps into a tempo.
+pub struct TapTempo {
+    /// The last few taps, oldest first.
+    taps: Deque<u64, TAPS>,
+}
+
+impl Default for TapTempo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapTempo {
+    pub const fn new() -> Self {
+        Self { taps: Deque::new() }
+    }
+
+    /// Takes a tap at `now_ms`. Returns the beat length averaged over the
+    /// last taps, once there are at least two close enough together.
+    pub fn tap(&mut self, now_ms: u64) -> Option<u32> {
+        if self
+            .taps
+            .back()
+            .is_some_and(|&last| now_ms.saturating_sub(last) > TAP_TIMEOUT_MS)
+        {
+            self.taps.clear();
+        }
+        if self.taps.is_full() {
+            self.taps.pop_front();
+        }
+        self.taps.push_back(now_ms).ok();
+
+        let intervals = self.taps.len() as u64 - 1;
+        let span_ms = now_ms - *self.taps.front()?;
+        (intervals > 0).then(|| (span_ms * 1000 / intervals) as u32)
+    }
+}
+
+/// Transport state sent as clock master.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// At the start of the song; playing sends Start.
+    #[default]
+    Stopped,
+    /// Stopped somewhere in the song; playing sends Continue.
+    Paused,
+    Playing,
+}
+
+/// Timing Clock and transport sent as clock master.
+///
+/// The clock runs whenever there is a tempo, playing or not, so that
+/// receivers know the tempo before they start.
+pub struct ClockGenerator {
+    /// Tempo the schedule was made for.
+    beat_us: Option<u32>,
+    /// When a tick went out and how many followed it, or `None` when the
+    /// next tick is due right away.
+    anchor: Option<(u64, u32)>,
+    transport: Transport,
+    /// Ticks sent while playing since the start of the song.
+    song_ticks: u32,
+}
+
+impl Default for ClockGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockGenerator {
+    pub const fn new() -> Self {
+        Self {
+            beat_us: None,
+            anchor: None,
+            transport: Transport::Stopped,
+            song_ticks: 0,
+        }
+    }
+
+    /// When the next tick is due at a beat of `beat_us`, `None` without a tempo.
+    pub fn deadline(&self, beat_us: Option<u32>) -> Option<u64> {
+        let beat_us = beat_us?;
+        Some(match self.anchor(Some(beat_us)) {
+            Some((since, ticks)) => since + tick_offset(ticks + 1, beat_us),
+            None => 0,
+        })
+    }
+
+    /// Sends the ticks due by `now_us` at a beat of `beat_us`; `None` stops
+    /// the clock, e.g. while following another one.
+    pub fn poll(&mut self, beat_us: Option<u32>, now_us: u64, sink: &mut impl MidiSink) {
+        self.anchor = self.anchor(beat_us);
+        self.beat_us = beat_us;
+        let Some(beat_us) = beat_us else {
+            return;
+        };
+
+        loop {
+            let Some((since, ticks)) = self.anchor else {
+                self.send_tick(sink);
+                self.anchor = Some((now_us, 0));
+                continue;
+            };
+            let due = since + tick_offset(ticks + 1, beat_us);
+            if due > now_us {
+                return;
+            }
+            // Far behind, e.g. after a stall: start over rather than sending a burst.
+            if now_us - due > beat_us as u64 {
+                self.anchor = None;
+                continue;
+            }
+            self.send_tick(sink);
+            // A beat's worth of ticks takes exactly a beat, so the anchor can move along.
+            self.anchor = Some(match ticks + 1 {
+                TICKS_PER_BEAT => (since + beat_us as u64, 0),
+                ticks => (since, ticks),
+            });
+        }
+    }
+
+    fn send_tick(&mut self, sink: &mut impl MidiSink) {
+        sink.send(Cable::Clock, MidiMessage::TimingClock);
+        if self.transport == Transport::Playing {
+            self.song_ticks = self.song_ticks.wrapping_add(1);
+        }
+    }
+
+    /// The anchor at a beat of `beat_us`. A new tempo takes over from the
+    /// last tick sent.
+    fn anchor(&self, beat_us: Option<u32>) -> Option<(u64, u32)> {
+        if beat_us == self.beat_us {
+            return self.anchor;
+        }
+        let (since, ticks) = self.anchor?;
+        beat_us?;
+        Some((since + tick_offset(ticks, self.beat_us?), 0))
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Beats played since the start of the song.
+    pub fn song_beats(&self) -> u32 {
+        self.song_ticks / TICKS_PER_BEAT
+    }
+
+    /// Starts playing, or continues where it stopped.
+    pub fn play(&mut self, sink: &mut impl MidiSink) {
+        match self.transport {
+            Transport::Stopped => {
+                sink.send(Cable::Clock, MidiMessage::Start);
+                self.song_ticks = 0;
+            }
+            Transport::Paused => sink.send(Cable::Clock, MidiMessage::Continue),
+            Transport::Playing => return,
+        }
+        self.transport = Transport::Playing;
+    }
+
+    /// Stops playing, or goes back to the start once stopped.
+    pub fn stop(&mut self, sink: &mut impl MidiSink) {
+        self.transport = match self.transport {
+            Transport::Playing => {
+                sink.send(Cable::Clock, MidiMessage::Stop);
+                Transport::Paused
+            }
+            Transport::Paused | Transport::Stopped => Transport::Stopped,
+        };
+    }
+}
+
+/// Time from a tick to the `ticks`th one after it.
+fn tick_offset(ticks: u32, beat_us: u32) -> u64 {
+    ticks as u64 * beat_us as u64 / TICKS_PER_BEAT as u64
+}
+
+/// Length of a beat at `bpm`.
+pub fn beat_us(bpm: u8) -> u32 {
+    60_000_000 / bpm.max(1) as u32
+}
+
+/// The tempo in tenths of a BPM at a beat of `beat_us`.
+pub fn bpm_tenths(beat_us: u32) -> u16 {
+    ((600_000_000 + beat_us as u64 / 2) / beat_us.max(1) as u64).min(u16::MAX as u64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Beat length at `bpm`.
+    fn beat(bpm: u32) -> u32 {
+        60_000_000 / bpm
+    }
+
+    /// Feeds ticks at the given timestamps, returning the tempo reported after each.
+    fn feed(follower: &mut ClockFollower, ticks: &[u64]) -> Vec<Option<u16>> {
+        ticks
+            .iter()
+            .map(|&at| {
+                follower.receive(&MidiMessage::TimingClock, at);
+                follower.bpm_tenths()
+            })
+            .collect()
+    }
+
+    /// Tick timestamps at `bpm` from `start`, shifted by a repeating jitter pattern.
+    fn recording(bpm: u32, start: u64, ticks: usize, jitter: &[i64]) -> Vec<u64> {
+        (0..ticks)
+            .map(|tick| {
+                let at = start + tick as u64 * beat(bpm) as u64 / TICKS_PER_BEAT as u64;
+                (at as i64 + jitter[tick % jitter.len()]) as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steady_clock_reports_its_tempo() {
+        let mut follower = ClockFollower::new();
+        let tempos = feed(&mut follower, &recording(120, 0, 96, &[0]));
+        assert_eq!(tempos[..MIN_INTERVALS], [None; MIN_INTERVALS]);
+        assert_eq!(tempos[MIN_INTERVALS], Some(1200));
+        assert_eq!(tempos.last(), Some(&Some(1200)));
+        assert_eq!(follower.beat_us(), Some(500_000));
+    }
+
+    #[test]
+    fn jitter_is_filtered() {
+        // As recorded over USB: ticks land up to a millisecond off, in bursts.
+        let jitter = [
+            0, 900, -400, 1000, -1000, 300, 0, -700, 800, -200, 600, -900,
+        ];
+        let mut follower = ClockFollower::new();
+        let tempos = feed(&mut follower, &recording(120, 1000, 24 * 8, &jitter));
+
+        let settled = &tempos[48..];
+        assert!(settled.iter().all(|bpm| bpm.unwrap().abs_diff(1200) <= 5));
+        let mut changes = settled.to_vec();
+        changes.dedup();
+        assert!(changes.len() <= 3, "{changes:?}");
+    }
+
+    #[test]
+    fn missed_ticks_are_skipped() {
+        let mut ticks = recording(100, 0, 72, &[0]);
+        // Two lost ticks, and one delivered twice.
+        ticks.remove(30);
+        ticks.remove(50);
+        ticks.insert(60, ticks[59] + 100);
+
+        let mut follower = ClockFollower::new();
+        let tempos = feed(&mut follower, &ticks);
+        assert!(tempos[MIN_INTERVALS..].iter().all(|&bpm| bpm == Some(1000)));
+    }
+
+    #[test]
+    fn tempo_changes_are_followed() {
+        let mut ticks = recording(120, 0, 48, &[0]);
+        let last = *ticks.last().unwrap();
+        ticks.extend(recording(140, last + beat(140) as u64 / 24, 96, &[0]));
+
+        let mut follower = ClockFollower::new();
+        let tempos = feed(&mut follower, &ticks);
+        assert_eq!(tempos[47], Some(1200));
+        // Most of the way after a beat, exact after a few.
+        assert!(tempos[48 + 24].unwrap() > 1350);
+        assert_eq!(tempos.last(), Some(&Some(1400)));
+
+        // Halving the tempo isn't mistaken for missed ticks for long.
+        let last = *ticks.last().unwrap();
+        let tempos = feed(
+            &mut follower,
+            &recording(70, last + beat(70) as u64 / 24, 48, &[0]),
+        );
+        assert_eq!(tempos.last(), Some(&Some(700)));
+    }
+
+    #[test]
+    fn transport_and_timeouts() {
+        let mut follower = ClockFollower::new();
+        assert!(!follower.receive(&MidiMessage::Start, 0));
+        assert!(follower.is_playing());
+        follower.receive(&MidiMessage::Stop, 0);
+        assert!(!follower.is_playing());
+        follower.receive(&MidiMessage::Continue, 0);
+        assert!(follower.is_playing());
+        assert_eq!(follower.deadline(), None);
+
+        let ticks = recording(120, 0, 24, &[0]);
+        feed(&mut follower, &ticks);
+        let last = *ticks.last().unwrap();
+        assert_eq!(follower.deadline(), Some(last + TIMEOUT_US));
+        assert!(!follower.poll(last + TIMEOUT_US - 1));
+        assert!(follower.poll(last + TIMEOUT_US));
+        assert_eq!(follower.bpm_tenths(), None);
+        assert!(!follower.is_playing());
+    }
+
+    #[test]
+    fn divisions_scale_with_the_beat() {
+        let beat = beat(120);
+        let durations: Vec<u32> = DIVISIONS.iter().map(|d| d.duration_us(beat)).collect();
+        assert!(durations.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let length = |label| {
+            DIVISIONS
+                .iter()
+                .find(|division| division.label == label)
+                .unwrap()
+                .duration_us(beat)
+        };
+        assert_eq!(length("1/4"), 500_000);
+        assert_eq!(length("1/8."), 375_000);
+        assert_eq!(length("1/8T"), 166_666);
+        assert_eq!(length("1/1"), 2_000_000);
+    }
+
+    #[test]
+    fn taps_average_into_a_tempo() {
+        let mut taps = TapTempo::new();
+        assert_eq!(taps.tap(1000), None);
+        assert_eq!(taps.tap(1500), Some(500_000));
+        assert_eq!(taps.tap(2010), Some(505_000));
+
+        // Only the last few count.
+        taps.tap(2500);
+        assert_eq!(taps.tap(3000), Some(500_000));
+
+        // A long pause starts over.
+        assert_eq!(taps.tap(5500), None);
+        assert_eq!(taps.tap(5900), Some(400_000));
+    }
+
+    /// Polls late by `lateness_us` until `count` ticks went out, returning when each was due.
+    fn generate(
+        generator: &mut ClockGenerator,
+        beat_us: u32,
+        count: usize,
+        lateness_us: u64,
+    ) -> Vec<u64> {
+        let mut due = Vec::new();
+        while due.len() < count {
+            let deadline = generator.deadline(Some(beat_us)).unwrap();
+            let mut sent = Vec::new();
+            generator.poll(Some(beat_us), deadline + lateness_us, &mut sent);
+            assert_eq!(sent, [MidiMessage::TimingClock]);
+            due.push(deadline);
+        }
+        due
+    }
+
+    #[test]
+    fn generated_ticks_keep_to_their_schedule() {
+        let mut generator = ClockGenerator::new();
+        assert_eq!(generator.deadline(None), None);
+        assert_eq!(generator.deadline(Some(beat(120))), Some(0));
+
+        // Late wake-ups don't add up.
+        let mut sent = Vec::new();
+        generator.poll(Some(beat(120)), 1000, &mut sent);
+        let due = generate(&mut generator, beat(120), 48, 700);
+        assert_eq!(due[23], 1000 + beat(120) as u64);
+        assert_eq!(due[47], 1000 + 2 * beat(120) as u64);
+        assert!(
+            due.windows(2)
+                .all(|pair| (20_833..=20_834).contains(&(pair[1] - pair[0])))
+        );
+
+        // A new tempo takes over from the last tick.
+        let last = due[47];
+        assert_eq!(generate(&mut generator, beat(60), 1, 0), [last + 41_666]);
+
+        // After a stall the clock picks up without a burst.
+        let mut sent = Vec::new();
+        generator.poll(Some(beat(60)), 10_000_000, &mut sent);
+        assert_eq!(sent, [MidiMessage::TimingClock]);
+        assert_eq!(generator.deadline(Some(beat(60))), Some(10_041_666));
+
+        // Without a tempo nothing is sent.
+        generator.poll(None, 20_000_000, &mut sent);
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[test]
+    fn transport_starts_stops_and_continues() {
+        let mut generator = ClockGenerator::new();
+        let mut sent = Vec::new();
+        generator.play(&mut sent);
+        generator.play(&mut sent);
+        generator.stop(&mut sent);
+        generator.play(&mut sent);
+        assert_eq!(generator.transport(), Transport::Playing);
+
+        // Stopping twice goes back to the start.
+        generator.stop(&mut sent);
+        generator.stop(&mut sent);
+        generator.play(&mut sent);
+        assert_eq!(
+            sent,
+            [
+                MidiMessage::Start,
+                MidiMessage::Stop,
+                MidiMessage::Continue,
+                MidiMessage::Stop,
+                MidiMessage::Start
+            ]
+        );
+    }
+}
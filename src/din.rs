@@ -0,0 +1,501 @@
+//! Serial MIDI, as on DIN and TRS ports.
+//!
+//! Unlike USB, the serial port carries a plain byte stream at 31250 baud.
+//! Senders may leave out the status byte of a channel message when it
+//! repeats the previous one (running status), and real-time bytes (`F8`-`FF`)
+//! may appear anywhere, even in the middle of another message. SysEx is
+//! reassembled by a [`Reassembler`], so it is handled like SysEx from USB.
+//!
+//! Going out, a [`Merger`] combines the messages of every source routed to
+//! the port into one stream without breaking up SysEx.
+
+use heapless::Vec;
+use midi_convert::midi_types::{Channel, MidiMessage};
+use midi_convert::parse::MidiTryParseSlice;
+use midi_convert::render_slice::MidiRenderSlice;
+
+use crate::outgoing::{OutQueue, Stats, is_realtime};
+use crate::parameter::Parameter;
+use crate::reassembler::{self, Reassembler};
+use crate::usb_midi::Cable;
+
+/// Bits per second of a MIDI port.
+pub const BAUD_RATE: u32 = 31_250;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A complete message other than SysEx.
+    Message(MidiMessage),
+    /// A SysEx message ended or was thrown away.
+    SysEx(reassembler::Event<'a>),
+}
+
+/// Splits a received byte stream into messages, keeping SysEx messages of
+/// up to `N` bytes.
+pub struct Parser<const N: usize> {
+    /// Status of the message being received. Channel messages keep it for
+    /// the next one.
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+    in_sysex: bool,
+    sysex: Reassembler<N>,
+}
+
+impl<const N: usize> Default for Parser<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Parser<N> {
+    pub const fn new() -> Self {
+        Self {
+            status: None,
+            data: [0; 2],
+            len: 0,
+            in_sysex: false,
+            sysex: Reassembler::new(),
+        }
+    }
+
+    /// Feeds the next byte of the stream, returning an event when a message ends.
+    ///
+    /// Data bytes without a status are ignored. A Tune Request that cuts a
+    /// SysEx message short is only reported as the dropped message.
+    pub fn push(&mut self, byte: u8) -> Option<Event<'_>> {
+        match byte {
+            // Real-time bytes leave whatever they interrupt alone.
+            0xF8.. => MidiMessage::try_parse_slice(&[byte])
+                .ok()
+                .map(Event::Message),
+            0xF0 => {
+                self.status = None;
+                self.in_sysex = true;
+                self.sysex.push(byte).map(Event::SysEx)
+            }
+            0xF7 => {
+                self.status = None;
+                if !core::mem::replace(&mut self.in_sysex, false) {
+                    return None;
+                }
+                self.sysex.push(byte).map(Event::SysEx)
+            }
+            0x80.. => {
+                // Undefined status bytes still cancel running status.
+                self.status = data_len(byte).map(|_| byte);
+                self.len = 0;
+                if core::mem::replace(&mut self.in_sysex, false) {
+                    return self.sysex.push(byte).map(Event::SysEx);
+                }
+                self.complete()
+            }
+            _ if self.in_sysex => self.sysex.push(byte).map(Event::SysEx),
+            _ => {
+                self.status?;
+                self.data[self.len] = byte;
+                self.len += 1;
+                self.complete()
+            }
+        }
+    }
+
+    /// The message once all its data bytes are there.
+    fn complete(&mut self) -> Option<Event<'static>> {
+        let status = self.status?;
+        let len = data_len(status)?;
+        if self.len < len {
+            return None;
+        }
+        self.len = 0;
+        // Running status only applies to channel messages.
+        if status >= 0xF0 {
+            self.status = None;
+        }
+
+        let mut bytes = [status, 0, 0];
+        bytes[1..=len].copy_from_slice(&self.data[..len]);
+        MidiMessage::try_parse_slice(&bytes[..=len])
+            .ok()
+            .map(Event::Message)
+    }
+}
+
+/// Data bytes following `status`, or `None` if it doesn't start a message
+/// of its own.
+fn data_len(status: u8) -> Option<usize> {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(1),
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(2),
+        0xF6 => Some(0),
+        _ => None,
+    }
+}
+
+/// Renders messages for a serial port, optionally with running status.
+#[derive(Default)]
+pub struct Serializer {
+    running_status: bool,
+    /// Status of the last channel message sent.
+    status: Option<u8>,
+}
+
+impl Serializer {
+    pub const fn new() -> Self {
+        Self {
+            running_status: false,
+            status: None,
+        }
+    }
+
+    /// Leaves out the status byte of channel messages that repeat the
+    /// previous one, which saves a third of the bandwidth on runs of Control
+    /// Changes. Some older receivers don't support it.
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.running_status = enabled;
+        self.forget();
+    }
+
+    /// Sends the next status byte in full, e.g. after SysEx went out.
+    pub fn forget(&mut self) {
+        self.status = None;
+    }
+
+    /// The bytes to send for `message`, rendered into `buffer`.
+    pub fn render<'a>(&mut self, message: &MidiMessage, buffer: &'a mut [u8; 3]) -> &'a [u8] {
+        let len = message.render_slice(buffer);
+        match buffer[0] {
+            0xF8.. => {}
+            status @ 0x80..=0xEF if self.running_status && self.status == Some(status) => {
+                return &buffer[1..len];
+            }
+            status @ 0x80..=0xEF => self.status = Some(status),
+            _ => self.status = None,
+        }
+        &buffer[..len]
+    }
+}
+
+/// Merges messages and SysEx from several sources into one serial stream,
+/// queueing messages in an [`OutQueue`] of `N` and SysEx of up to `S` bytes.
+///
+/// Nothing but real-time messages may appear inside SysEx, so once a SysEx
+/// message starts going out the queued messages wait for its end, and the
+/// next SysEx message is only taken after it. Real-time messages still go
+/// first, even in the middle of SysEx.
+pub struct Merger<const N: usize, const S: usize> {
+    queue: OutQueue<N>,
+    sysex: Vec<u8, S>,
+    /// Bytes of `sysex` already taken.
+    sent: usize,
+    serializer: Serializer,
+}
+
+impl<const N: usize, const S: usize> Default for Merger<N, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const S: usize> Merger<N, S> {
+    pub const fn new() -> Self {
+        Self {
+            queue: OutQueue::new(),
+            sysex: Vec::new(),
+            sent: 0,
+            serializer: Serializer::new(),
+        }
+    }
+
+    /// See [`Serializer::set_running_status`].
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.serializer.set_running_status(enabled);
+    }
+
+    /// Queues a message, see [`OutQueue::push`]. The cable only keeps values
+    /// from different sources from coalescing.
+    pub fn push(&mut self, cable: Cable, message: MidiMessage) -> bool {
+        self.queue.push(cable, message)
+    }
+
+    /// Queues a message passed through from another port, see
+    /// [`OutQueue::push_in_order`].
+    pub fn push_in_order(&mut self, cable: Cable, message: MidiMessage) -> bool {
+        self.queue.push_in_order(cable, message)
+    }
+
+    /// Queues a parameter value, see [`OutQueue::push_parameter`].
+    pub fn push_parameter(
+        &mut self,
+        cable: Cable,
+        channel: Channel,
+        parameter: Parameter,
+        value: u16,
+    ) -> bool {
+        self.queue.push_parameter(cable, channel, parameter, value)
+    }
+
+    /// Takes a complete SysEx message. Returns `false` while the previous one
+    /// is still going out, or if it is longer than `S`.
+    pub fn push_sysex(&mut self, message: &[u8]) -> bool {
+        if self.is_sending_sysex() {
+            return false;
+        }
+        self.sysex.extend_from_slice(message).is_ok()
+    }
+
+    pub fn is_sending_sysex(&self) -> bool {
+        !self.sysex.is_empty()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.queue.stats()
+    }
+
+    /// Takes the next bytes to send into `buffer`, which holds at least
+    /// three: a message, or as much of the SysEx going out as fits. Returns
+    /// how many were taken, `0` when there is nothing to send.
+    pub fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        if let Some((_, message)) = self.queue.peek()
+            && is_realtime(&message)
+        {
+            self.queue.pop();
+            return self.render(&message, buffer);
+        }
+
+        if self.is_sending_sysex() {
+            if self.sent == 0 {
+                self.serializer.forget();
+            }
+            let rest = &self.sysex[self.sent..];
+            let len = rest.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&rest[..len]);
+            self.sent += len;
+            if self.sent == self.sysex.len() {
+                self.sysex.clear();
+                self.sent = 0;
+            }
+            return len;
+        }
+
+        match self.queue.pop() {
+            Some((_, message)) => self.render(&message, buffer),
+            None => 0,
+        }
+    }
+
+    fn render(&mut self, message: &MidiMessage, buffer: &mut [u8]) -> usize {
+        let mut rendered = [0; 3];
+        let bytes = self.serializer.render(message, &mut rendered);
+        buffer[..bytes.len()].copy_from_slice(bytes);
+        bytes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Channel, Control, Note, Program, Value7};
+
+    use super::*;
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(Channel::C1, Control::new(control), Value7::new(value))
+    }
+
+    /// Feeds `bytes`, collecting the messages and the SysEx as owned copies.
+    fn feed<const N: usize>(
+        parser: &mut Parser<N>,
+        bytes: &[u8],
+    ) -> std::vec::Vec<Result<MidiMessage, std::vec::Vec<u8>>> {
+        bytes
+            .iter()
+            .filter_map(|&b| match parser.push(b)? {
+                Event::Message(message) => Some(Ok(message)),
+                Event::SysEx(reassembler::Event::Message(message)) => Some(Err(message.to_vec())),
+                Event::SysEx(reassembler::Event::Dropped { .. }) => Some(Err(std::vec![])),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_status_repeats_channel_messages() {
+        let mut parser = Parser::<16>::new();
+        let messages = feed(&mut parser, &[0xB0, 20, 1, 21, 2, 0xC1, 5, 6]);
+        assert_eq!(
+            messages,
+            [
+                Ok(cc(20, 1)),
+                Ok(cc(21, 2)),
+                Ok(MidiMessage::ProgramChange(Channel::C2, Program::new(5))),
+                Ok(MidiMessage::ProgramChange(Channel::C2, Program::new(6))),
+            ]
+        );
+
+        // System common messages cancel it, and stray data is ignored.
+        let messages = feed(&mut parser, &[0xF3, 4, 7, 0x90, 60]);
+        assert_eq!(messages, [Ok(MidiMessage::SongSelect(Value7::new(4)))]);
+        assert_eq!(
+            feed(&mut parser, &[100, 0xF6, 1]),
+            [
+                Ok(MidiMessage::NoteOn(
+                    Channel::C1,
+                    Note::new(60),
+                    Value7::new(100)
+                )),
+                Ok(MidiMessage::TuneRequest)
+            ]
+        );
+    }
+
+    #[test]
+    fn real_time_bytes_go_anywhere() {
+        let mut parser = Parser::<16>::new();
+        let messages = feed(
+            &mut parser,
+            &[0xB0, 0xF8, 20, 0xFA, 1, 21, 0xF8, 2, 0xF0, 0x7D, 0xF8, 0xF7],
+        );
+        assert_eq!(
+            messages,
+            [
+                Ok(MidiMessage::TimingClock),
+                Ok(MidiMessage::Start),
+                Ok(cc(20, 1)),
+                Ok(MidiMessage::TimingClock),
+                Ok(cc(21, 2)),
+                Ok(MidiMessage::TimingClock),
+                Err(std::vec![0xF0, 0x7D, 0xF7]),
+            ]
+        );
+        // Undefined real-time bytes are skipped.
+        assert_eq!(feed(&mut parser, &[0xF9, 0xFD]), []);
+    }
+
+    #[test]
+    fn sysex_is_collected_and_cancels_running_status() {
+        let mut parser = Parser::<8>::new();
+        let messages = feed(&mut parser, &[0xB0, 20, 1, 0xF0, 1, 2, 0xF7, 21, 2]);
+        assert_eq!(messages, [Ok(cc(20, 1)), Err(std::vec![0xF0, 1, 2, 0xF7])]);
+
+        // A status byte cuts it short and starts its own message.
+        let messages = feed(&mut parser, &[0xF0, 1, 0xB0, 20, 3, 0xF7]);
+        assert_eq!(messages, [Err(std::vec![]), Ok(cc(20, 3))]);
+    }
+
+    #[test]
+    fn serialized_messages_parse_back() {
+        let messages = [
+            cc(20, 1),
+            cc(21, 2),
+            MidiMessage::TimingClock,
+            cc(22, 3),
+            MidiMessage::PitchBendChange(Channel::C1, 8192u16.into()),
+            MidiMessage::SongSelect(Value7::new(1)),
+            cc(23, 4),
+        ];
+
+        for running_status in [false, true] {
+            let mut serializer = Serializer::new();
+            serializer.set_running_status(running_status);
+            let mut bytes = std::vec::Vec::new();
+            for message in &messages {
+                bytes.extend_from_slice(serializer.render(message, &mut [0; 3]));
+            }
+            let expected = if running_status { 16 } else { 18 };
+            assert_eq!(bytes.len(), expected);
+
+            let mut parser = Parser::<4>::new();
+            let parsed: std::vec::Vec<_> = feed(&mut parser, &bytes)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(parsed, messages);
+        }
+
+        // After SysEx the status goes out in full again.
+        let mut serializer = Serializer::new();
+        serializer.set_running_status(true);
+        serializer.render(&cc(20, 1), &mut [0; 3]);
+        assert_eq!(serializer.render(&cc(20, 2), &mut [0; 3]), [20, 2]);
+        serializer.forget();
+        assert_eq!(serializer.render(&cc(20, 3), &mut [0; 3]), [0xB0, 20, 3]);
+    }
+
+    /// Pops everything there is in pieces of up to four bytes.
+    fn drain<const N: usize, const S: usize>(
+        merger: &mut Merger<N, S>,
+    ) -> std::vec::Vec<std::vec::Vec<u8>> {
+        let mut pieces = std::vec::Vec::new();
+        let mut buffer = [0; 4];
+        loop {
+            match merger.pop(&mut buffer) {
+                0 => return pieces,
+                len => pieces.push(buffer[..len].to_vec()),
+            }
+        }
+    }
+
+    #[test]
+    fn passed_through_messages_are_not_coalesced() {
+        let mut merger = Merger::<16, 8>::new();
+        let note = MidiMessage::NoteOn(Channel::C1, Note::new(60), Value7::new(100));
+        let sustained = [cc(64, 127), note, cc(64, 0)];
+        // Two NRPN values sent back to back.
+        let parameters = [
+            cc(99, 0),
+            cc(98, 1),
+            cc(6, 10),
+            cc(99, 0),
+            cc(98, 2),
+            cc(6, 20),
+        ];
+
+        for message in sustained.iter().chain(&parameters) {
+            assert!(merger.push_in_order(Cable::Thru, *message));
+        }
+        let bytes = drain(&mut merger).concat();
+        let parsed: std::vec::Vec<_> = feed(&mut Parser::<4>::new(), &bytes)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(parsed[..3], sustained);
+        assert_eq!(parsed[3..], parameters);
+        assert_eq!(merger.stats().coalesced, 0);
+    }
+
+    #[test]
+    fn merged_sysex_stays_whole() {
+        let mut merger = Merger::<8, 8>::new();
+        merger.set_running_status(true);
+        merger.push(Cable::Control, cc(20, 1));
+        assert_eq!(drain(&mut merger), [[0xB0, 20, 1]]);
+
+        assert!(merger.push_sysex(&[0xF0, 1, 2, 3, 4, 5, 0xF7]));
+        // Another source's SysEx has to wait.
+        assert!(!merger.push_sysex(&[0xF0, 0xF7]));
+        merger.push(Cable::Thru, cc(20, 2));
+        merger.push(Cable::Clock, MidiMessage::TimingClock);
+
+        let mut buffer = [0; 4];
+        let len = merger.pop(&mut buffer);
+        assert_eq!(buffer[..len], [0xF8]);
+        let len = merger.pop(&mut buffer);
+        assert_eq!(buffer[..len], [0xF0, 1, 2, 3]);
+
+        // The clock still goes first, the message waits for the end.
+        merger.push(Cable::Clock, MidiMessage::TimingClock);
+        assert_eq!(
+            drain(&mut merger),
+            [
+                std::vec![0xF8],
+                std::vec![4, 5, 0xF7],
+                // Running status starts over after SysEx.
+                std::vec![0xB0, 20, 2],
+            ]
+        );
+
+        // Longer than the merger holds.
+        assert!(!merger.push_sysex(&[0xF0, 1, 2, 3, 4, 5, 6, 7, 0xF7]));
+        assert!(merger.push_sysex(&[0xF0, 0xF7]));
+        assert_eq!(drain(&mut merger), [[0xF0, 0xF7]]);
+    }
+}
@@ -0,0 +1,607 @@
+use embedded_graphics::{
+    mono_font::{MonoTextStyle, ascii::FONT_6X10},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{
+        Arc, Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, StrokeAlignment,
+        Triangle,
+    },
+    text::{Alignment, Text},
+};
+use heapless::{String, format};
+
+use crate::attribute::{Attribute, Encoding, Kind, Name, Visual};
+use crate::clock::{DIVISIONS, Transport};
+use crate::io::Framebuffer;
+use crate::parameter::Parameter;
+use crate::preset::{PRESET_COUNT, PresetAction};
+use crate::routing::{Destination, ROUTES, Routing, Source};
+use crate::state::State;
+
+const DELAY_MIN_CIRCLE_SIZE: u32 = 6;
+const BEATS_PER_BAR: u32 = 4;
+
+/// Snapshot of what is on screen, so the state isn't borrowed while drawing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum View {
+    Attribute {
+        attribute: Attribute,
+        /// Position in the table.
+        index: usize,
+        count: usize,
+        /// The note division shown instead of the value, when tempo-synced.
+        division: Option<&'static str>,
+        /// Tempo of the clock, in tenths of a BPM.
+        bpm_tenths: Option<u16>,
+        /// Whether the tempo comes from another clock.
+        following: bool,
+        /// Beat within the bar while playing as clock master.
+        beat: Option<u8>,
+        /// Whether the attribute waits for a message to learn its binding.
+        learning: bool,
+    },
+    Presets {
+        action: PresetAction,
+        /// Which slots hold a preset.
+        stored: [bool; PRESET_COUNT],
+        /// Name of the targeted preset, if any.
+        name: Option<Name>,
+    },
+    Routing {
+        /// The highlighted entry of [`ROUTES`].
+        cursor: usize,
+        routing: Routing,
+    },
+}
+
+impl View {
+    pub fn capture(state: &State) -> Self {
+        if let Some(attribute) = state.selected() {
+            return Self::Attribute {
+                attribute: attribute.clone(),
+                index: state.selected_option(),
+                count: state.attributes().len(),
+                division: state
+                    .division(attribute)
+                    .map(|index| DIVISIONS[index].label),
+                bpm_tenths: state.bpm_tenths(),
+                following: state.is_following(),
+                beat: (!state.is_following() && state.transport() == Transport::Playing)
+                    .then(|| (state.song_beats() % BEATS_PER_BAR) as u8),
+                learning: state.is_learning(),
+            };
+        }
+
+        if let Some(cursor) = state.route_cursor() {
+            return Self::Routing {
+                cursor,
+                routing: *state.routing(),
+            };
+        }
+
+        let action = state.preset_action().unwrap_or(PresetAction::Back);
+
+        let presets = state.presets();
+        let name = match action {
+            PresetAction::Back => None,
+            PresetAction::Recall(slot) | PresetAction::Store(slot) => {
+                presets[slot].as_ref().map(|preset| preset.name.clone())
+            }
+        };
+
+        Self::Presets {
+            action,
+            stored: core::array::from_fn(|slot| presets[slot].is_some()),
+            name,
+        }
+    }
+}
+
+/// Renders the selected attribute and keeps the animation state between frames.
+pub struct Screen {
+    text_default: MonoTextStyle<'static, BinaryColor>,
+    fill: PrimitiveStyle<BinaryColor>,
+    thin_stroke: PrimitiveStyle<BinaryColor>,
+    thick_stroke: PrimitiveStyle<BinaryColor>,
+    last_animation_frame: u64,
+    delay_circle_size: u32,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Self {
+            text_default: MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+            fill: PrimitiveStyleBuilder::new()
+                .fill_color(BinaryColor::On)
+                .build(),
+            thin_stroke: PrimitiveStyleBuilder::new()
+                .stroke_color(BinaryColor::On)
+                .stroke_width(1)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .build(),
+            thick_stroke: PrimitiveStyleBuilder::new()
+                .stroke_color(BinaryColor::On)
+                .stroke_width(2)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .build(),
+            last_animation_frame: 0,
+            delay_circle_size: DELAY_MIN_CIRCLE_SIZE,
+        }
+    }
+
+    /// Draws one frame for `view` and flushes it.
+    ///
+    /// `now_ms` is a monotonic timestamp used to pace the animations.
+    pub fn draw<F: Framebuffer>(
+        &mut self,
+        display: &mut F,
+        view: &View,
+        now_ms: u64,
+    ) -> Result<(), F::Error> {
+        display.clear(BinaryColor::Off)?;
+
+        let (label, footer): (String<64>, String<16>) = match view {
+            View::Attribute {
+                attribute,
+                index,
+                count,
+                division,
+                bpm_tenths,
+                following,
+                beat,
+                learning,
+            } => {
+                match &attribute.kind {
+                    Kind::Toggle => self.draw_toggle(display, attribute.value > 0)?,
+                    Kind::Enumerated(choices) => {
+                        self.draw_choices(display, choices.len(), attribute.value as usize)?
+                    }
+                    Kind::Bipolar => self.draw_bipolar(display, attribute)?,
+                    // One box per beat of the bar, the current one filled.
+                    Kind::Tempo => self.draw_choices(
+                        display,
+                        BEATS_PER_BAR as usize,
+                        beat.map_or(usize::MAX, usize::from),
+                    )?,
+                    Kind::Continuous => match attribute.visual {
+                        Visual::Bar => self.draw_bar(display, attribute)?,
+                        Visual::Ripple => self.draw_delay(display, attribute, now_ms)?,
+                        Visual::Waves => self.draw_feedback(display, attribute)?,
+                    },
+                }
+
+                if let Some(bpm_tenths) = bpm_tenths {
+                    let tempo: String<16> = format!(
+                        "{}.{} {}",
+                        bpm_tenths / 10,
+                        bpm_tenths % 10,
+                        if *following { "EXT" } else { "BPM" }
+                    )
+                    .unwrap_or_default();
+                    Text::with_alignment(
+                        &tempo,
+                        Point::new(32, 113),
+                        self.text_default,
+                        Alignment::Center,
+                    )
+                    .draw(display)?;
+                }
+
+                let binding = if *learning {
+                    String::try_from("Learn...").unwrap_or_default()
+                } else {
+                    binding(attribute)
+                };
+                Text::with_alignment(
+                    &binding,
+                    Point::new(32, 103),
+                    self.text_default,
+                    Alignment::Center,
+                )
+                .draw(display)?;
+
+                let value = match division {
+                    Some(division) => String::<32>::try_from(*division).unwrap_or_default(),
+                    None => attribute.to_human_readable(),
+                };
+                (
+                    format!("{}:\n{}", attribute.name, value).unwrap_or_default(),
+                    // Position in the table, so long tables stay navigable.
+                    format!("{}/{}", index + 1, count).unwrap_or_default(),
+                )
+            }
+            View::Presets {
+                action,
+                stored,
+                name,
+            } => {
+                self.draw_preset_slots(display, *action, stored)?;
+
+                let name = name.as_deref().unwrap_or("Empty");
+                let label = match action {
+                    PresetAction::Back => format!("Presets:\nBack"),
+                    PresetAction::Recall(slot) => format!("Load {}:\n{}", slot + 1, name),
+                    PresetAction::Store(slot) => format!("Save {}:\n{}", slot + 1, name),
+                };
+                (
+                    label.unwrap_or_default(),
+                    String::try_from("Presets").unwrap_or_default(),
+                )
+            }
+            View::Routing { cursor, routing } => {
+                self.draw_routes(display, *cursor, routing)?;
+
+                let (source, destination) = ROUTES[*cursor];
+                let route = routing.route(source, destination);
+                let status = if route.is_filtered() {
+                    "Filtered"
+                } else if route.enabled {
+                    "On"
+                } else {
+                    "Off"
+                };
+                (
+                    format!("{}>{}:\n{}", source.name(), destination.name(), status)
+                        .unwrap_or_default(),
+                    String::try_from("Routes").unwrap_or_default(),
+                )
+            }
+        };
+
+        let line_y = 70;
+        Line::new(Point::new(0, line_y), Point::new(64, line_y))
+            .into_styled(self.thin_stroke)
+            .draw(display)?;
+
+        // Draw centered text.
+        let text_y = 82;
+        Text::with_alignment(
+            &label,
+            Point::new(32, text_y),
+            self.text_default,
+            Alignment::Center,
+        )
+        .draw(display)?;
+
+        Text::with_alignment(
+            &footer,
+            Point::new(32, 124),
+            self.text_default,
+            Alignment::Center,
+        )
+        .draw(display)?;
+
+        display.flush()
+    }
+
+    /// A grid of slots, filled when stored, with the targeted one outlined.
+    fn draw_preset_slots<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        action: PresetAction,
+        stored: &[bool; PRESET_COUNT],
+    ) -> Result<(), F::Error> {
+        let target = match action {
+            PresetAction::Back => None,
+            PresetAction::Recall(slot) | PresetAction::Store(slot) => Some(slot),
+        };
+
+        for (slot, &stored) in stored.iter().enumerate() {
+            let top_left = Point::new(6 + (slot % 4) as i32 * 14, 18 + (slot / 4) as i32 * 18);
+            let style = if stored { self.fill } else { self.thin_stroke };
+            Rectangle::new(top_left, Size::new(10, 10))
+                .into_styled(style)
+                .draw(display)?;
+
+            if target == Some(slot) {
+                Rectangle::new(top_left - Point::new(3, 3), Size::new(16, 16))
+                    .into_styled(self.thin_stroke)
+                    .draw(display)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A grid with a row per source and a column per destination, the boxes
+    /// filled for routes that are on and the highlighted one outlined.
+    fn draw_routes<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        cursor: usize,
+        routing: &Routing,
+    ) -> Result<(), F::Error> {
+        for (column, destination) in Destination::ALL.iter().enumerate() {
+            let label = &destination.name()[..1];
+            Text::with_alignment(
+                label,
+                Point::new(29 + column as i32 * 20, 12),
+                self.text_default,
+                Alignment::Center,
+            )
+            .draw(display)?;
+        }
+
+        for (row, source) in Source::ALL.iter().enumerate() {
+            let top = 18 + row as i32 * 16;
+            Text::new(
+                &source.name()[..1],
+                Point::new(8, top + 8),
+                self.text_default,
+            )
+            .draw(display)?;
+
+            for (column, &destination) in Destination::ALL.iter().enumerate() {
+                let top_left = Point::new(24 + column as i32 * 20, top);
+                let style = if routing.route(*source, destination).enabled {
+                    self.fill
+                } else {
+                    self.thin_stroke
+                };
+                Rectangle::new(top_left, Size::new(10, 10))
+                    .into_styled(style)
+                    .draw(display)?;
+
+                if ROUTES[cursor] == (*source, destination) {
+                    Rectangle::new(top_left - Point::new(3, 3), Size::new(16, 16))
+                        .into_styled(self.thin_stroke)
+                        .draw(display)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn draw_bar<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        attribute: &Attribute,
+    ) -> Result<(), F::Error> {
+        let width = attribute.range.scale(attribute.value, 56);
+
+        Rectangle::new(Point::new(4, 24), Size::new(56, 16))
+            .into_styled(self.thin_stroke)
+            .draw(display)?;
+        Rectangle::new(Point::new(4, 24), Size::new(width, 16))
+            .into_styled(self.fill)
+            .draw(display)
+    }
+
+    /// A switch with its knob on the left when off and filled on the right when on.
+    fn draw_toggle<F: Framebuffer>(&self, display: &mut F, on: bool) -> Result<(), F::Error> {
+        Rectangle::new(Point::new(12, 22), Size::new(40, 20))
+            .into_styled(self.thin_stroke)
+            .draw(display)?;
+        let knob = if on {
+            Point::new(34, 24)
+        } else {
+            Point::new(14, 24)
+        };
+        let style = if on { self.fill } else { self.thin_stroke };
+        Rectangle::new(knob, Size::new(16, 16))
+            .into_styled(style)
+            .draw(display)
+    }
+
+    /// A row of boxes, one per choice, with the chosen one filled.
+    fn draw_choices<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        count: usize,
+        chosen: usize,
+    ) -> Result<(), F::Error> {
+        let left = (64 - (count as i32 * 10 - 2)) / 2;
+        for choice in 0..count {
+            let style = if choice == chosen {
+                self.fill
+            } else {
+                self.thin_stroke
+            };
+            Rectangle::new(Point::new(left + choice as i32 * 10, 28), Size::new(8, 8))
+                .into_styled(style)
+                .draw(display)?;
+        }
+        Ok(())
+    }
+
+    /// A bar filled from the centre mark towards the value.
+    fn draw_bipolar<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        attribute: &Attribute,
+    ) -> Result<(), F::Error> {
+        let range = attribute.range;
+        let centre = 4 + range.scale(range.centre(), 56) as i32;
+        let value = 4 + range.scale(attribute.value, 56) as i32;
+
+        Rectangle::new(Point::new(4, 24), Size::new(56, 16))
+            .into_styled(self.thin_stroke)
+            .draw(display)?;
+        Rectangle::new(
+            Point::new(centre.min(value), 24),
+            Size::new(centre.abs_diff(value), 16),
+        )
+        .into_styled(self.fill)
+        .draw(display)?;
+        Line::new(Point::new(centre, 20), Point::new(centre, 43))
+            .into_styled(self.thin_stroke)
+            .draw(display)
+    }
+
+    fn draw_delay<F: Framebuffer>(
+        &mut self,
+        display: &mut F,
+        attribute: &Attribute,
+        now_ms: u64,
+    ) -> Result<(), F::Error> {
+        let center = Point::new(32, 32);
+        let size = 20 + attribute.range.scale(attribute.value, 40);
+        Rectangle::with_center(center, Size::new(size, size))
+            .into_styled(self.thin_stroke)
+            .draw(display)?;
+
+        if attribute.value > attribute.range.min() {
+            if now_ms - self.last_animation_frame > 100 {
+                self.last_animation_frame = now_ms;
+                self.delay_circle_size += 2;
+                if self.delay_circle_size > size - 1 {
+                    self.delay_circle_size = DELAY_MIN_CIRCLE_SIZE;
+                }
+            }
+
+            Circle::with_center(center, self.delay_circle_size)
+                .into_styled(self.thin_stroke)
+                .draw(display)?;
+
+            Circle::with_center(center, 2)
+                .into_styled(self.fill)
+                .draw(display)?;
+        }
+
+        Ok(())
+    }
+
+    fn draw_feedback<F: Framebuffer>(
+        &self,
+        display: &mut F,
+        attribute: &Attribute,
+    ) -> Result<(), F::Error> {
+        let triangle_y_middle = 32;
+        let triangle_height = 16;
+        let triangle_x_middle = 20;
+        let triangle_width = 10;
+        Triangle::new(
+            Point::new(triangle_x_middle - triangle_width, triangle_y_middle),
+            Point::new(
+                triangle_x_middle + triangle_width,
+                triangle_y_middle + triangle_height,
+            ),
+            Point::new(
+                triangle_x_middle + triangle_width,
+                triangle_y_middle - triangle_height,
+            ),
+        )
+        .into_styled(self.fill)
+        .draw(display)?;
+
+        let center = Point::new(triangle_x_middle - triangle_width, triangle_y_middle);
+        Circle::with_center(center, 10)
+            .into_styled(self.fill)
+            .draw(display)?;
+
+        for r in [10, 22, 34, 46].iter().take(level_to_arc_count(
+            attribute.range.scale(attribute.value, 100) as u8,
+        )) {
+            Arc::with_center(
+                Point::new(32, triangle_y_middle),
+                *r,
+                (-60.0).deg(),
+                (120.0).deg(),
+            )
+            .into_styled(self.thick_stroke)
+            .draw(display)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn level_to_arc_count(level: u8) -> usize {
+    if level == 0 {
+        0
+    } else {
+        1 + ((level as u16 * 3) / 100) as usize
+    }
+}
+
+/// Channel and controller of an attribute, e.g. `1:CC20` or `10:N300` for
+/// an NRPN. The tempo is sent as the clock and has none.
+fn binding(attribute: &Attribute) -> String<16> {
+    let channel = u8::from(attribute.channel) + 1;
+    let control = u8::from(attribute.control);
+    let binding = match attribute.encoding {
+        _ if attribute.kind == Kind::Tempo => return String::new(),
+        Encoding::Control7 => format!("{channel}:CC{control}"),
+        Encoding::Control14 => format!("{channel}:CC{control}+{}", control + 32),
+        Encoding::Parameter(Parameter::NonRegistered(number)) => format!("{channel}:N{number}"),
+        Encoding::Parameter(Parameter::Registered(number)) => format!("{channel}:R{number}"),
+        Encoding::PitchBend => format!("{channel}:Bend"),
+        Encoding::ChannelPressure => format!("{channel}:Press"),
+    };
+    binding.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Channel, Control};
+
+    use super::*;
+
+    #[test]
+    fn view_follows_the_selection() {
+        let mut state = State::new();
+        state.set_attributes(crate::attribute::Attributes::from_iter([Attribute::new(
+            "Delay",
+            Channel::C1,
+            Control::new(20),
+        )]));
+        state.store_preset(2);
+
+        assert!(matches!(
+            View::capture(&state),
+            View::Attribute {
+                index: 0,
+                count: 1,
+                ..
+            }
+        ));
+
+        state.next_option();
+        state.adjust_selected(3, &mut std::vec::Vec::new());
+        let View::Presets {
+            action,
+            stored,
+            name,
+        } = View::capture(&state)
+        else {
+            panic!("expected the preset page");
+        };
+        assert_eq!(action, PresetAction::Recall(2));
+        assert_eq!(stored.iter().filter(|&&stored| stored).count(), 1);
+        assert_eq!(name.as_deref(), Some("Preset 3"));
+
+        state.toggle_routing();
+        assert_eq!(
+            View::capture(&state),
+            View::Routing {
+                cursor: 0,
+                routing: Routing::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn binding_names_channel_and_controller() {
+        let attribute = Attribute::new("Delay", Channel::C16, Control::new(20));
+        assert_eq!(binding(&attribute), "16:CC20");
+        let attribute = attribute.with_encoding(Encoding::Control14);
+        assert_eq!(binding(&attribute), "16:CC20+52");
+        let attribute = attribute.with_encoding(Encoding::Parameter(Parameter::NonRegistered(300)));
+        assert_eq!(binding(&attribute), "16:N300");
+    }
+
+    #[test]
+    fn arc_count_grows_with_level() {
+        assert_eq!(level_to_arc_count(0), 0);
+        assert_eq!(level_to_arc_count(1), 1);
+        assert_eq!(level_to_arc_count(50), 2);
+        assert_eq!(level_to_arc_count(100), 4);
+    }
+}
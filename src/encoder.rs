@@ -0,0 +1,271 @@
+//! Encoder count handling.
+
+/// Turns quadrature counter readings into detents.
+///
+/// Counting both edges of both channels gives several counts per click. Counts
+/// short of a full detent are carried over to the next reading, so slow turns
+/// neither lose nor double-count clicks.
+#[derive(Clone, Debug)]
+pub struct DetentDecoder {
+    counts_per_detent: i16,
+    invert: bool,
+    last: Option<i16>,
+    remainder: i16,
+}
+
+impl DetentDecoder {
+    /// `counts_per_detent` is clamped to at least 1; `invert` swaps the direction.
+    pub const fn new(counts_per_detent: u8, invert: bool) -> Self {
+        Self {
+            counts_per_detent: if counts_per_detent == 0 {
+                1
+            } else {
+                counts_per_detent as i16
+            },
+            invert,
+            last: None,
+            remainder: 0,
+        }
+    }
+
+    /// Takes the current counter value and returns the detents turned since
+    /// the previous one. The first reading only sets the starting point.
+    ///
+    /// The counter may wrap around, as long as it moves less than half its
+    /// range between readings.
+    pub fn update(&mut self, count: i16) -> i16 {
+        let Some(last) = self.last.replace(count) else {
+            return 0;
+        };
+
+        let counts = count.wrapping_sub(last);
+        // A jump of exactly half the range is `i16::MIN`, which has no negation.
+        let counts = if self.invert {
+            counts.saturating_neg()
+        } else {
+            counts
+        };
+        self.remainder = self.remainder.saturating_add(counts);
+
+        let detents = self.remainder / self.counts_per_detent;
+        self.remainder %= self.counts_per_detent;
+        detents
+    }
+}
+
+/// How much faster turns move a value.
+///
+/// Below `threshold` detents per second every detent is one step. Above it
+/// the step grows linearly, reaching `max_step` at `full_speed`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Acceleration {
+    pub threshold: u8,
+    pub full_speed: u8,
+    pub max_step: u8,
+}
+
+impl Acceleration {
+    /// One step per detent at any speed.
+    pub const OFF: Self = Self {
+        threshold: 0,
+        full_speed: 1,
+        max_step: 1,
+    };
+
+    /// Whether the values make a usable curve.
+    pub fn is_valid(&self) -> bool {
+        self.max_step >= 1 && self.full_speed > self.threshold
+    }
+
+    /// Step size per detent at `speed` detents per second.
+    pub fn step(&self, speed: u16) -> i16 {
+        let (threshold, full_speed) = (self.threshold as u16, self.full_speed as u16);
+        if speed <= threshold || !self.is_valid() {
+            return 1;
+        }
+        let extra = (self.max_step as u16 - 1) * (speed.min(full_speed) - threshold);
+        1 + (extra / (full_speed - threshold)) as i16
+    }
+}
+
+impl Default for Acceleration {
+    /// Single steps up to 5 detents per second, ten at 40.
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            full_speed: 40,
+            max_step: 10,
+        }
+    }
+}
+
+/// Turns slower than this don't count towards the speed of the next one.
+const IDLE_MS: u64 = 250;
+
+/// Estimates turning speed from timestamped deltas and scales them by an
+/// [`Acceleration`] curve.
+#[derive(Clone, Debug, Default)]
+pub struct Accelerator {
+    last: Option<(u64, bool)>,
+    /// Smoothed speed in detents per second.
+    speed: u16,
+}
+
+impl Accelerator {
+    pub const fn new() -> Self {
+        Self {
+            last: None,
+            speed: 0,
+        }
+    }
+
+    /// Scales `delta` detents, turned just before `now_ms`.
+    pub fn apply(&mut self, delta: i16, now_ms: u64, curve: Acceleration) -> i16 {
+        if delta == 0 {
+            return 0;
+        }
+
+        let forward = delta > 0;
+        let speed = match self.last {
+            // Keep building speed while turning on in the same direction.
+            Some((then, direction))
+                if direction == forward && now_ms.saturating_sub(then) < IDLE_MS =>
+            {
+                let elapsed = now_ms.saturating_sub(then).max(1);
+                let speed = delta.unsigned_abs() as u64 * 1000 / elapsed;
+                ((self.speed as u64 + speed) / 2).min(u16::MAX as u64) as u16
+            }
+            _ => 0,
+        };
+        self.last = Some((now_ms, forward));
+        self.speed = speed;
+
+        delta.saturating_mul(curve.step(speed))
+    }
+
+    /// Forgets the speed, e.g. when another attribute gets selected.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds counter readings, returning the detents reported for each.
+    fn decode(decoder: &mut DetentDecoder, counts: &[i16]) -> Vec<i16> {
+        counts.iter().map(|&count| decoder.update(count)).collect()
+    }
+
+    #[test]
+    fn counts_are_grouped_into_detents() {
+        let mut decoder = DetentDecoder::new(4, false);
+        assert_eq!(decode(&mut decoder, &[0, 4, 8, 20, 16]), [0, 1, 1, 3, -1]);
+
+        let mut decoder = DetentDecoder::new(2, false);
+        assert_eq!(decode(&mut decoder, &[10, 12, 13, 14]), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn partial_detents_carry_over() {
+        let mut decoder = DetentDecoder::new(4, false);
+        // One edge at a time, as a slow turn is polled.
+        assert_eq!(
+            decode(&mut decoder, &[0, 1, 2, 3, 4, 5, 6, 7, 8]),
+            [0, 0, 0, 0, 1, 0, 0, 0, 1]
+        );
+        // Readings that straddle a detent.
+        assert_eq!(decode(&mut decoder, &[11, 13, 16]), [0, 1, 1]);
+    }
+
+    #[test]
+    fn wobbling_on_a_detent_reports_nothing() {
+        let mut decoder = DetentDecoder::new(4, false);
+        assert_eq!(
+            decode(&mut decoder, &[0, 2, -1, 1, -2, 0, 3, 0]),
+            [0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(decode(&mut decoder, &[-4, -8]), [-1, -1]);
+    }
+
+    #[test]
+    fn direction_can_be_inverted() {
+        let mut decoder = DetentDecoder::new(4, true);
+        assert_eq!(decode(&mut decoder, &[0, 8, 4]), [0, -2, 1]);
+    }
+
+    #[test]
+    fn counter_wraparound_is_a_small_step() {
+        let mut decoder = DetentDecoder::new(4, false);
+        assert_eq!(decode(&mut decoder, &[32760, -32768, -32764]), [0, 2, 1]);
+        assert_eq!(DetentDecoder::new(0, false).update(5), 0);
+    }
+
+    #[test]
+    fn half_range_jumps_do_not_overflow() {
+        // A glitch moving the counter by exactly half its range, either way round.
+        let mut decoder = DetentDecoder::new(4, true);
+        assert_eq!(decode(&mut decoder, &[0, -32768]), [0, 8191]);
+        let mut decoder = DetentDecoder::new(4, false);
+        assert_eq!(decode(&mut decoder, &[0, -32768]), [0, -8192]);
+    }
+
+    #[test]
+    fn curve_grows_between_threshold_and_full_speed() {
+        let curve = Acceleration::default();
+        assert_eq!(curve.step(0), 1);
+        assert_eq!(curve.step(5), 1);
+        assert_eq!(curve.step(22), 5);
+        assert_eq!(curve.step(40), 10);
+        assert_eq!(curve.step(400), 10);
+        assert_eq!(Acceleration::OFF.step(400), 1);
+    }
+
+    /// Feeds `(delta, ms since the previous one)` pairs, returning the scaled deltas.
+    fn turn(accelerator: &mut Accelerator, turns: &[(i16, u64)]) -> Vec<i16> {
+        let mut now = 1000;
+        turns
+            .iter()
+            .map(|&(delta, after)| {
+                now += after;
+                accelerator.apply(delta, now, Acceleration::default())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slow_turns_step_singly() {
+        let mut accelerator = Accelerator::new();
+        let steps = turn(&mut accelerator, &[(1, 0), (1, 300), (1, 300), (-1, 500)]);
+        assert_eq!(steps, [1, 1, 1, -1]);
+    }
+
+    #[test]
+    fn fast_spins_speed_up() {
+        let mut accelerator = Accelerator::new();
+        // One detent every 10 ms is 100 per second, settling on the full step.
+        let mut turns = vec![(1, 0)];
+        turns.extend([(1, 10); 6]);
+        let steps = turn(&mut accelerator, &turns);
+        assert_eq!(steps, [1, 10, 10, 10, 10, 10, 10]);
+
+        // Polled deltas: four detents per 100 ms poll is 40 per second.
+        let mut accelerator = Accelerator::new();
+        let steps = turn(&mut accelerator, &[(4, 0), (4, 100), (4, 100), (4, 100)]);
+        assert_eq!(steps, [4, 16, 28, 32]);
+    }
+
+    #[test]
+    fn reversing_or_pausing_starts_over() {
+        let mut accelerator = Accelerator::new();
+        let steps = turn(&mut accelerator, &[(1, 0), (1, 10), (-1, 10), (-1, 10)]);
+        assert_eq!(steps, [1, 10, -1, -10]);
+
+        let steps = turn(&mut accelerator, &[(-1, 1000)]);
+        assert_eq!(steps, [-1]);
+
+        accelerator.reset();
+        assert_eq!(accelerator.apply(1, 5000, Acceleration::default()), 1);
+    }
+}
@@ -0,0 +1,70 @@
+//! Traits the controller logic uses to reach the hardware.
+
+use embassy_futures::select::{Either, select};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::DrawTarget};
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+use crate::parameter::Parameter;
+use crate::usb_midi::Cable;
+
+/// Source of relative encoder movement.
+#[allow(async_fn_in_trait)]
+pub trait EncoderSource {
+    /// Waits for the encoder to move and returns the number of steps turned.
+    async fn delta(&mut self) -> i16;
+}
+
+/// Source of raw encoder button levels, debounced by [`crate::button::Gestures`].
+#[allow(async_fn_in_trait)]
+pub trait ButtonSource {
+    /// Waits for the next edge and returns whether the button is now down.
+    async fn changed(&mut self) -> bool;
+}
+
+/// Destination for outgoing MIDI messages, each on one of the [`Cable`]s.
+pub trait MidiSink {
+    fn send(&mut self, cable: Cable, message: MidiMessage);
+
+    /// Sends a parameter value: its selection followed by Data Entry. Sinks
+    /// that reorder messages have to keep these together.
+    fn send_parameter(&mut self, cable: Cable, channel: Channel, parameter: Parameter, value: u16) {
+        for message in parameter.select(channel) {
+            self.send(cable, message);
+        }
+        for message in Parameter::data(channel, value) {
+            self.send(cable, message);
+        }
+    }
+}
+
+/// Monochrome display the UI is rendered into.
+pub trait Framebuffer: DrawTarget<Color = BinaryColor> {
+    /// Pushes the drawn frame to the panel.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A single user interaction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Turn(i16),
+    /// The button level changed; `true` when it went down.
+    Button(bool),
+}
+
+/// Waits for whichever of the encoder or the button fires first.
+pub async fn next_input<E: EncoderSource, B: ButtonSource>(
+    encoder: &mut E,
+    button: &mut B,
+) -> Input {
+    match select(encoder.delta(), button.changed()).await {
+        Either::First(delta) => Input::Turn(delta),
+        Either::Second(pressed) => Input::Button(pressed),
+    }
+}
+
+#[cfg(test)]
+impl MidiSink for std::vec::Vec<MidiMessage> {
+    fn send(&mut self, _cable: Cable, message: MidiMessage) {
+        self.push(message);
+    }
+}
@@ -1 +1,31 @@
-#![no_std]
+//! Hardware-independent controller logic for the Staas MIDI interface.
+//!
+//! Everything in here is plain `no_std` Rust that talks to the outside world
+//! through the traits in [`io`], so it can be unit tested on the host with
+//! `cargo +stable host-test`. The ESP32-S3 firmware in `src/bin` only wires
+//! peripherals up to these traits.
+//!
+//! Nothing in here reads a clock either. The time is passed in with every
+//! input, and whatever waits for something, like [`button::Gestures`] or
+//! [`clock::ClockFollower`], reports a `deadline` by which its `poll` has to
+//! be called.
+#![cfg_attr(not(test), no_std)]
+
+pub mod attribute;
+pub mod button;
+mod bytes;
+pub mod clock;
+pub mod din;
+pub mod display;
+pub mod encoder;
+pub mod io;
+pub mod outgoing;
+pub mod parameter;
+pub mod preset;
+pub mod reassembler;
+pub mod routing;
+pub mod state;
+pub mod storage;
+pub mod sysex;
+pub mod usb_midi;
+pub mod value;
@@ -0,0 +1,521 @@
+//! Outgoing message queue.
+//!
+//! Messages wait here until the transport accepts them, so a busy USB
+//! endpoint delays them instead of losing them. Continuous controls are
+//! coalesced: a new Control Change replaces a queued one for the same
+//! controller, so fast encoder turns can't fill the queue and the latest
+//! value always goes out. Everything else is sent in order.
+//!
+//! Messages passed through from another port are queued with
+//! [`OutQueue::push_in_order`] and never coalesce: each of them matters to
+//! the receiver, like a sustain pedal going down and up around a note.
+//!
+//! Parameter values take a single entry, coalesced per parameter, and are
+//! only expanded into their Control Changes when sent, so other messages
+//! can't end up between them. Optionally the selection is left out when the
+//! parameter was the last one sent on its channel.
+//!
+//! Real-time messages, the clock and transport, skip ahead of everything
+//! else, so their timing doesn't depend on what else is being sent. MIDI
+//! allows them anywhere, even inside a parameter value.
+//!
+//! Every message goes out on a [`Cable`]. Cables are separate ports, so
+//! values only coalesce with ones on the same cable and parameters are
+//! cached per cable.
+
+use heapless::{Deque, Vec};
+use midi_convert::midi_types::{Channel, Control, MidiMessage};
+
+use crate::io::MidiSink;
+use crate::parameter::Parameter;
+use crate::usb_midi::Cable;
+
+/// Counters for what the queue did to keep up.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Messages replaced by a newer value for the same control.
+    pub coalesced: u32,
+    /// Messages lost because the queue was full.
+    pub dropped: u32,
+}
+
+/// What a message sets; a newer message with the same key supersedes it.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Key {
+    Control(Channel, Control),
+    PitchBend(Channel),
+    ChannelPressure(Channel),
+    Parameter(Channel, Parameter),
+}
+
+#[derive(Copy, Clone)]
+enum Entry {
+    Message(Cable, MidiMessage),
+    /// A message that neither replaces nor is replaced by another.
+    InOrder(Cable, MidiMessage),
+    Parameter(Cable, Channel, Parameter, u16),
+}
+
+impl Entry {
+    fn key(&self) -> Option<(Cable, Key)> {
+        let key = match *self {
+            Self::Message(_, MidiMessage::ControlChange(channel, control, _)) => {
+                Key::Control(channel, control)
+            }
+            Self::Message(_, MidiMessage::PitchBendChange(channel, _)) => Key::PitchBend(channel),
+            Self::Message(_, MidiMessage::ChannelPressure(channel, _)) => {
+                Key::ChannelPressure(channel)
+            }
+            Self::Message(..) | Self::InOrder(..) => return None,
+            Self::Parameter(_, channel, parameter, _) => Key::Parameter(channel, parameter),
+        };
+        Some((self.cable(), key))
+    }
+
+    fn cable(&self) -> Cable {
+        match *self {
+            Self::Message(cable, _) | Self::InOrder(cable, _) | Self::Parameter(cable, ..) => cable,
+        }
+    }
+}
+
+/// Queue of up to `N` messages waiting to be sent.
+pub struct OutQueue<const N: usize> {
+    entries: Vec<Entry, N>,
+    /// Clock and transport messages, sent before anything else.
+    realtime: Deque<(Cable, MidiMessage), 4>,
+    /// The rest of the parameter value being sent, and its cable.
+    expanded: Deque<MidiMessage, 4>,
+    expanded_cable: Cable,
+    /// The parameter last selected on each cable and channel, if caching.
+    selected: [[Option<Parameter>; 16]; Cable::COUNT],
+    cache_parameters: bool,
+    stats: Stats,
+}
+
+impl<const N: usize> Default for OutQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> OutQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            realtime: Deque::new(),
+            expanded: Deque::new(),
+            expanded_cable: Cable::Control,
+            selected: [[None; 16]; Cable::COUNT],
+            cache_parameters: false,
+            stats: Stats {
+                coalesced: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    /// Queues `message` for `cable`, replacing an older value for the same
+    /// control.
+    ///
+    /// The new value moves to the back so it still follows everything queued
+    /// before it. Returns `false` if the queue was full and it was dropped.
+    pub fn push(&mut self, cable: Cable, message: MidiMessage) -> bool {
+        if is_realtime(&message) {
+            if self.realtime.push_back((cable, message)).is_err() {
+                self.stats.dropped = self.stats.dropped.wrapping_add(1);
+                return false;
+            }
+            return true;
+        }
+        self.push_entry(Entry::Message(cable, message))
+    }
+
+    /// Queues `message` for `cable` behind everything else without replacing
+    /// anything, for messages passed through from another port. Real-time
+    /// messages still skip ahead.
+    pub fn push_in_order(&mut self, cable: Cable, message: MidiMessage) -> bool {
+        if is_realtime(&message) {
+            return self.push(cable, message);
+        }
+        self.push_entry(Entry::InOrder(cable, message))
+    }
+
+    /// Queues a parameter value, replacing an older one for the same parameter.
+    pub fn push_parameter(
+        &mut self,
+        cable: Cable,
+        channel: Channel,
+        parameter: Parameter,
+        value: u16,
+    ) -> bool {
+        self.push_entry(Entry::Parameter(cable, channel, parameter, value))
+    }
+
+    fn push_entry(&mut self, entry: Entry) -> bool {
+        if let Some(key) = entry.key()
+            && let Some(index) = self
+                .entries
+                .iter()
+                .position(|queued| queued.key() == Some(key))
+        {
+            self.entries.remove(index);
+            self.stats.coalesced = self.stats.coalesced.wrapping_add(1);
+        }
+
+        if self.entries.push(entry).is_err() {
+            self.stats.dropped = self.stats.dropped.wrapping_add(1);
+            return false;
+        }
+        true
+    }
+
+    /// Leaves out the selection of a parameter that is already selected on
+    /// its cable and channel. Only safe if nothing else selects parameters on
+    /// the channels in use.
+    pub fn set_parameter_caching(&mut self, enabled: bool) {
+        self.cache_parameters = enabled;
+        self.forget_parameters();
+    }
+
+    /// Selects the next parameter in full, e.g. after the receiver reconnected.
+    pub fn forget_parameters(&mut self) {
+        self.selected = [[None; 16]; Cable::COUNT];
+    }
+
+    /// The next message to send and its cable. It stays queued until
+    /// [`Self::pop`], so a send that would block can be retried.
+    pub fn peek(&self) -> Option<(Cable, MidiMessage)> {
+        if let Some(&queued) = self.realtime.front() {
+            return Some(queued);
+        }
+        if let Some(&message) = self.expanded.front() {
+            return Some((self.expanded_cable, message));
+        }
+        match *self.entries.first()? {
+            Entry::Message(cable, message) | Entry::InOrder(cable, message) => {
+                Some((cable, message))
+            }
+            Entry::Parameter(cable, channel, parameter, value) => self
+                .expand(cable, channel, parameter, value)
+                .pop_front()
+                .map(|message| (cable, message)),
+        }
+    }
+
+    /// Removes the message returned by [`Self::peek`] once it was sent.
+    pub fn pop(&mut self) -> Option<(Cable, MidiMessage)> {
+        if let Some(queued) = self.realtime.pop_front() {
+            return Some(queued);
+        }
+        if let Some(message) = self.expanded.pop_front() {
+            return Some((self.expanded_cable, message));
+        }
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        match self.entries.remove(0) {
+            Entry::Message(cable, message) | Entry::InOrder(cable, message) => {
+                // Someone else selected a parameter.
+                if let MidiMessage::ControlChange(channel, control, _) = message
+                    && Parameter::is_selection(control)
+                {
+                    self.selected[cable.number() as usize][u8::from(channel) as usize] = None;
+                }
+                Some((cable, message))
+            }
+            Entry::Parameter(cable, channel, parameter, value) => {
+                self.expanded = self.expand(cable, channel, parameter, value);
+                self.expanded_cable = cable;
+                self.selected[cable.number() as usize][u8::from(channel) as usize] =
+                    Some(parameter);
+                self.expanded.pop_front().map(|message| (cable, message))
+            }
+        }
+    }
+
+    fn expand(
+        &self,
+        cable: Cable,
+        channel: Channel,
+        parameter: Parameter,
+        value: u16,
+    ) -> Deque<MidiMessage, 4> {
+        let cached = self.cache_parameters
+            && self.selected[cable.number() as usize][u8::from(channel) as usize]
+                == Some(parameter);
+        let mut messages = Deque::new();
+        if !cached {
+            for message in parameter.select(channel) {
+                messages.push_back(message).ok();
+            }
+        }
+        for message in Parameter::data(channel, value) {
+            messages.push_back(message).ok();
+        }
+        messages
+    }
+
+    /// Number of entries waiting, counting a parameter value being sent as
+    /// its remaining messages.
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.realtime.len() + self.expanded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+pub(crate) fn is_realtime(message: &MidiMessage) -> bool {
+    matches!(
+        message,
+        MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::ActiveSensing
+            | MidiMessage::Reset
+    )
+}
+
+impl<const N: usize> MidiSink for OutQueue<N> {
+    fn send(&mut self, cable: Cable, message: MidiMessage) {
+        self.push(cable, message);
+    }
+
+    fn send_parameter(&mut self, cable: Cable, channel: Channel, parameter: Parameter, value: u16) {
+        self.push_parameter(cable, channel, parameter, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Note, Program, Value7};
+
+    use super::*;
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(Channel::C1, Control::new(control), Value7::new(value))
+    }
+
+    fn drain<const N: usize>(queue: &mut OutQueue<N>) -> std::vec::Vec<MidiMessage> {
+        core::iter::from_fn(|| queue.pop())
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    #[test]
+    fn fast_turns_keep_only_the_latest_value() {
+        let mut queue = OutQueue::<4>::new();
+        for value in 0..100 {
+            assert!(queue.push(Cable::Control, cc(20, value)));
+        }
+        queue.push(Cable::Control, cc(21, 5));
+
+        assert_eq!(drain(&mut queue), [cc(20, 99), cc(21, 5)]);
+        assert_eq!(
+            queue.stats(),
+            Stats {
+                coalesced: 99,
+                dropped: 0
+            }
+        );
+    }
+
+    #[test]
+    fn controls_are_told_apart_by_channel() {
+        let mut queue = OutQueue::<4>::new();
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push(
+            Cable::Control,
+            MidiMessage::ControlChange(Channel::C2, Control::new(20), Value7::new(2)),
+        );
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.stats().coalesced, 0);
+    }
+
+    #[test]
+    fn other_messages_keep_their_order() {
+        let mut queue = OutQueue::<8>::new();
+        let program = MidiMessage::ProgramChange(Channel::C1, Program::new(3));
+        let note = MidiMessage::NoteOn(Channel::C1, Note::new(60), Value7::new(100));
+
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push(Cable::Control, program);
+        queue.push(Cable::Control, note);
+        queue.push(Cable::Control, note);
+        queue.push(Cable::Control, cc(20, 2));
+
+        // The newer value moves behind the program change it followed.
+        assert_eq!(drain(&mut queue), [program, note, note, cc(20, 2)]);
+    }
+
+    #[test]
+    fn passed_through_messages_keep_every_value() {
+        let mut queue = OutQueue::<8>::new();
+        queue.set_parameter_caching(true);
+        let cutoff = Parameter::NonRegistered(3);
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 1);
+        drain(&mut queue);
+
+        queue.push_in_order(Cable::Thru, cc(64, 127));
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push_in_order(Cable::Thru, cc(64, 0));
+        queue.push(Cable::Control, cc(20, 2));
+        assert_eq!(drain(&mut queue), [cc(64, 127), cc(64, 0), cc(20, 2)]);
+        assert_eq!(queue.stats().coalesced, 1);
+
+        // A passed through selection still means the parameter is selected again.
+        queue.push_in_order(Cable::Control, cc(99, 1));
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 2);
+        assert_eq!(drain(&mut queue).len(), 5);
+    }
+
+    #[test]
+    fn peeked_messages_stay_until_popped() {
+        let mut queue = OutQueue::<4>::new();
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push(Cable::Control, cc(21, 1));
+
+        // A blocked send leaves the message queued for the next attempt.
+        assert_eq!(queue.peek(), Some((Cable::Control, cc(20, 1))));
+        assert_eq!(queue.peek(), Some((Cable::Control, cc(20, 1))));
+        assert_eq!(queue.pop(), Some((Cable::Control, cc(20, 1))));
+        assert_eq!(queue.peek(), Some((Cable::Control, cc(21, 1))));
+
+        queue.pop();
+        queue.push_parameter(Cable::Control, Channel::C1, Parameter::NonRegistered(1), 0);
+        assert_eq!(queue.peek(), Some((Cable::Control, cc(99, 0))));
+        assert_eq!(queue.pop(), Some((Cable::Control, cc(99, 0))));
+        assert_eq!(queue.peek(), Some((Cable::Control, cc(98, 1))));
+    }
+
+    #[test]
+    fn clock_skips_ahead() {
+        let mut queue = OutQueue::<8>::new();
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push_parameter(Cable::Control, Channel::C1, Parameter::NonRegistered(1), 0);
+        assert_eq!(queue.pop(), Some((Cable::Control, cc(20, 1))));
+        assert_eq!(queue.pop(), Some((Cable::Control, cc(99, 0))));
+
+        queue.push(Cable::Control, MidiMessage::TimingClock);
+        queue.push(Cable::Control, MidiMessage::Start);
+        assert_eq!(queue.len(), 5);
+        assert_eq!(
+            drain(&mut queue),
+            [
+                MidiMessage::TimingClock,
+                MidiMessage::Start,
+                cc(98, 1),
+                cc(6, 0),
+                cc(38, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn parameter_values_stay_together() {
+        let mut queue = OutQueue::<8>::new();
+        let cutoff = Parameter::NonRegistered(0x0101);
+        let program = MidiMessage::ProgramChange(Channel::C1, Program::new(3));
+
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 100);
+        queue.push_parameter(Cable::Control, Channel::C1, Parameter::NonRegistered(2), 5);
+        queue.push(Cable::Control, cc(6, 1));
+        queue.push(Cable::Control, program);
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 200);
+
+        // The newer value moves behind the program change, in one piece.
+        assert_eq!(
+            drain(&mut queue),
+            [
+                cc(99, 0),
+                cc(98, 2),
+                cc(6, 0),
+                cc(38, 5),
+                cc(6, 1),
+                program,
+                cc(99, 2),
+                cc(98, 1),
+                cc(6, 1),
+                cc(38, 72),
+            ]
+        );
+        assert_eq!(queue.stats().coalesced, 1);
+    }
+
+    #[test]
+    fn cached_parameters_skip_the_selection() {
+        let mut queue = OutQueue::<8>::new();
+        queue.set_parameter_caching(true);
+        let cutoff = Parameter::NonRegistered(3);
+
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 1);
+        assert_eq!(
+            drain(&mut queue),
+            [cc(99, 0), cc(98, 3), cc(6, 0), cc(38, 1)]
+        );
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 2);
+        assert_eq!(drain(&mut queue), [cc(6, 0), cc(38, 2)]);
+
+        // Any other selection on the channel, or a reconnection, selects it again.
+        queue.push(Cable::Control, cc(101, 0));
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 3);
+        assert_eq!(drain(&mut queue)[1..3], [cc(99, 0), cc(98, 3)]);
+        queue.forget_parameters();
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 4);
+        assert_eq!(drain(&mut queue).len(), 4);
+    }
+
+    #[test]
+    fn cables_are_queued_apart() {
+        let mut queue = OutQueue::<8>::new();
+        queue.set_parameter_caching(true);
+        let cutoff = Parameter::NonRegistered(3);
+
+        queue.push(Cable::Control, cc(20, 1));
+        queue.push(Cable::Thru, cc(20, 2));
+        queue.push(Cable::Control, cc(20, 3));
+        queue.push_parameter(Cable::Control, Channel::C1, cutoff, 1);
+        assert_eq!(queue.stats().coalesced, 1);
+        assert_eq!(queue.pop(), Some((Cable::Thru, cc(20, 2))));
+        assert_eq!(queue.pop(), Some((Cable::Control, cc(20, 3))));
+        assert_eq!(queue.len(), 1);
+        while queue.pop().is_some() {}
+
+        // The same parameter is selected again on another cable.
+        queue.push_parameter(Cable::Thru, Channel::C1, cutoff, 2);
+        assert_eq!(queue.pop(), Some((Cable::Thru, cc(99, 0))));
+        assert_eq!(queue.len(), 3);
+
+        queue.push(Cable::Clock, MidiMessage::TimingClock);
+        assert_eq!(queue.pop(), Some((Cable::Clock, MidiMessage::TimingClock)));
+        assert_eq!(queue.pop(), Some((Cable::Thru, cc(98, 3))));
+    }
+
+    #[test]
+    fn full_queue_counts_drops() {
+        let mut queue = OutQueue::<2>::new();
+        let program = MidiMessage::ProgramChange(Channel::C1, Program::new(0));
+        assert!(queue.push(Cable::Control, program));
+        assert!(queue.push(Cable::Control, cc(20, 1)));
+        assert!(!queue.push(Cable::Control, program));
+
+        // Superseding a queued value still fits.
+        assert!(queue.push(Cable::Control, cc(20, 2)));
+        assert_eq!(
+            queue.stats(),
+            Stats {
+                coalesced: 1,
+                dropped: 1
+            }
+        );
+        assert_eq!(drain(&mut queue), [program, cc(20, 2)]);
+    }
+}
@@ -0,0 +1,244 @@
+//! Registered and non-registered parameters (RPN and NRPN).
+//!
+//! A parameter value is sent as four Control Changes on its channel: the
+//! parameter number, most significant half first, followed by the 14-bit
+//! value through Data Entry, again most significant half first. Receivers
+//! apply Data Entry to whichever parameter was selected last, so the four
+//! have to stay together.
+
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Value7};
+
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+
+/// Largest parameter number, 14 bits.
+pub const MAX_NUMBER: u16 = 0x3FFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parameter {
+    /// A parameter defined by the MIDI specification, e.g. `0` for the pitch
+    /// bend range.
+    Registered(u16),
+    /// A parameter defined by the synth.
+    NonRegistered(u16),
+}
+
+impl Parameter {
+    pub fn number(self) -> u16 {
+        match self {
+            Self::Registered(number) | Self::NonRegistered(number) => number,
+        }
+    }
+
+    /// Whether a Control Change on `control` selects a parameter.
+    pub fn is_selection(control: Control) -> bool {
+        (NRPN_LSB..=RPN_MSB).contains(&u8::from(control))
+    }
+
+    /// Whether a Control Change on `control` is Data Entry for the selected parameter.
+    pub fn is_data_entry(control: Control) -> bool {
+        matches!(u8::from(control), DATA_ENTRY_MSB | DATA_ENTRY_LSB)
+    }
+
+    /// The Control Changes selecting the parameter on `channel`.
+    pub fn select(self, channel: Channel) -> [MidiMessage; 2] {
+        let (msb, lsb) = match self {
+            Self::Registered(_) => (RPN_MSB, RPN_LSB),
+            Self::NonRegistered(_) => (NRPN_MSB, NRPN_LSB),
+        };
+        let number = self.number();
+        [
+            control_change(channel, msb, (number >> 7) as u8),
+            control_change(channel, lsb, number as u8),
+        ]
+    }
+
+    /// The Data Entry Control Changes setting the selected parameter to `value`.
+    pub fn data(channel: Channel, value: u16) -> [MidiMessage; 2] {
+        [
+            control_change(channel, DATA_ENTRY_MSB, (value >> 7) as u8),
+            control_change(channel, DATA_ENTRY_LSB, value as u8),
+        ]
+    }
+}
+
+fn control_change(channel: Channel, control: u8, value: u8) -> MidiMessage {
+    MidiMessage::ControlChange(channel, Control::new(control), Value7::new(value & 0x7F))
+}
+
+/// Selection seen on one channel.
+#[derive(Copy, Clone, Default)]
+struct Selection {
+    registered: bool,
+    msb: Option<u8>,
+    lsb: Option<u8>,
+    data_msb: u8,
+}
+
+impl Selection {
+    fn parameter(&self) -> Option<Parameter> {
+        let number = (self.msb? as u16) << 7 | self.lsb? as u16;
+        match (self.registered, number) {
+            // The null parameter deselects.
+            (true, MAX_NUMBER) => None,
+            (true, number) => Some(Parameter::Registered(number)),
+            (false, number) => Some(Parameter::NonRegistered(number)),
+        }
+    }
+
+    /// Sets one half of the number, starting over when the kind changes.
+    fn set(&mut self, registered: bool, msb: Option<u8>, lsb: Option<u8>) {
+        if registered != self.registered {
+            *self = Self {
+                registered,
+                ..Self::default()
+            };
+        }
+        self.msb = msb.or(self.msb);
+        self.lsb = lsb.or(self.lsb);
+    }
+}
+
+/// Follows parameter selection and Data Entry in received Control Changes.
+pub struct ParameterReceiver {
+    channels: [Selection; 16],
+}
+
+impl Default for ParameterReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParameterReceiver {
+    pub const fn new() -> Self {
+        Self {
+            channels: [Selection {
+                registered: false,
+                msb: None,
+                lsb: None,
+                data_msb: 0,
+            }; 16],
+        }
+    }
+
+    /// Takes a received Control Change. Returns the selected parameter and
+    /// its new value when it was Data Entry; a lone most significant half
+    /// counts as a value with the lower half `0`.
+    pub fn receive(
+        &mut self,
+        channel: Channel,
+        control: Control,
+        value: Value7,
+    ) -> Option<(Parameter, u16)> {
+        let selection = &mut self.channels[u8::from(channel) as usize];
+        let value = u8::from(value);
+        match u8::from(control) {
+            NRPN_MSB => selection.set(false, Some(value), None),
+            NRPN_LSB => selection.set(false, None, Some(value)),
+            RPN_MSB => selection.set(true, Some(value), None),
+            RPN_LSB => selection.set(true, None, Some(value)),
+            DATA_ENTRY_MSB => {
+                selection.data_msb = value;
+                return Some((selection.parameter()?, (value as u16) << 7));
+            }
+            DATA_ENTRY_LSB => {
+                let data = (selection.data_msb as u16) << 7 | value as u16;
+                return Some((selection.parameter()?, data));
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(receiver: &mut ParameterReceiver, messages: &[MidiMessage]) -> Vec<(Parameter, u16)> {
+        messages
+            .iter()
+            .filter_map(|message| match *message {
+                MidiMessage::ControlChange(channel, control, value) => {
+                    receiver.receive(channel, control, value)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn values_are_sent_number_first() {
+        let parameter = Parameter::NonRegistered(0x0123);
+        let messages = [
+            parameter.select(Channel::C2),
+            Parameter::data(Channel::C2, 0x1FFF),
+        ];
+        assert_eq!(
+            messages.concat(),
+            [
+                control_change(Channel::C2, 99, 0x02),
+                control_change(Channel::C2, 98, 0x23),
+                control_change(Channel::C2, 6, 0x3F),
+                control_change(Channel::C2, 38, 0x7F),
+            ]
+        );
+        assert_eq!(
+            Parameter::Registered(0).select(Channel::C1),
+            [
+                control_change(Channel::C1, 101, 0),
+                control_change(Channel::C1, 100, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sent_values_are_received() {
+        let mut receiver = ParameterReceiver::new();
+        let parameter = Parameter::Registered(2);
+        let sent = [
+            &parameter.select(Channel::C3)[..],
+            &Parameter::data(Channel::C3, 1000)[..],
+        ]
+        .concat();
+        assert_eq!(
+            feed(&mut receiver, &sent),
+            [(parameter, (1000 >> 7) << 7), (parameter, 1000)]
+        );
+
+        // Data Entry alone goes to the parameter selected before.
+        let more = Parameter::data(Channel::C3, 5);
+        assert_eq!(feed(&mut receiver, &more), [(parameter, 0), (parameter, 5)]);
+    }
+
+    #[test]
+    fn incomplete_or_null_selections_are_ignored() {
+        let mut receiver = ParameterReceiver::new();
+        assert_eq!(feed(&mut receiver, &Parameter::data(Channel::C1, 5)), []);
+
+        // Half an NRPN number, then the RPN kind takes over.
+        let messages = [
+            control_change(Channel::C1, 99, 1),
+            control_change(Channel::C1, 6, 1),
+            control_change(Channel::C1, 100, 4),
+            control_change(Channel::C1, 6, 1),
+        ];
+        assert_eq!(feed(&mut receiver, &messages), []);
+
+        let null = Parameter::Registered(MAX_NUMBER).select(Channel::C1);
+        assert_eq!(feed(&mut receiver, &null), []);
+        assert_eq!(feed(&mut receiver, &Parameter::data(Channel::C1, 5)), []);
+
+        // Channels are tracked apart.
+        feed(
+            &mut receiver,
+            &Parameter::NonRegistered(7).select(Channel::C1),
+        );
+        assert_eq!(feed(&mut receiver, &Parameter::data(Channel::C2, 5)), []);
+    }
+}
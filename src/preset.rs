@@ -0,0 +1,75 @@
+//! Snapshots of all attribute values.
+
+use heapless::{Vec, format};
+
+use crate::attribute::{MAX_ATTRIBUTES, Name};
+
+/// Number of preset slots.
+pub const PRESET_COUNT: usize = 8;
+
+pub type Presets = [Option<Preset>; PRESET_COUNT];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preset {
+    pub name: Name,
+    /// Attribute values in table order, without their fine positions.
+    pub values: Vec<u8, MAX_ATTRIBUTES>,
+}
+
+impl Preset {
+    /// Name given to presets stored from the device, e.g. `Preset 3` for slot 2.
+    pub fn default_name(slot: usize) -> Name {
+        format!("Preset {}", slot + 1).unwrap_or_default()
+    }
+}
+
+/// Entry highlighted on the preset page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresetAction {
+    /// Leave the page without doing anything.
+    Back,
+    Recall(usize),
+    Store(usize),
+}
+
+impl PresetAction {
+    /// Number of entries on the preset page.
+    pub const COUNT: usize = 1 + 2 * PRESET_COUNT;
+
+    /// The entry at `cursor`, in page order: back, recall slots, store slots.
+    pub fn from_cursor(cursor: usize) -> Self {
+        match cursor {
+            0 => Self::Back,
+            c if c <= PRESET_COUNT => Self::Recall(c - 1),
+            c => Self::Store((c - 1 - PRESET_COUNT).min(PRESET_COUNT - 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_maps_to_actions() {
+        assert_eq!(PresetAction::from_cursor(0), PresetAction::Back);
+        assert_eq!(PresetAction::from_cursor(1), PresetAction::Recall(0));
+        assert_eq!(
+            PresetAction::from_cursor(PRESET_COUNT),
+            PresetAction::Recall(7)
+        );
+        assert_eq!(
+            PresetAction::from_cursor(PRESET_COUNT + 1),
+            PresetAction::Store(0)
+        );
+        assert_eq!(
+            PresetAction::from_cursor(PresetAction::COUNT - 1),
+            PresetAction::Store(PRESET_COUNT - 1)
+        );
+    }
+
+    #[test]
+    fn default_names_are_one_based() {
+        assert_eq!(Preset::default_name(2), "Preset 3");
+    }
+}
@@ -0,0 +1,209 @@
+//! Reassembly of SysEx messages from a MIDI byte stream.
+//!
+//! Bytes are fed in one at a time as they arrive, whatever the transport
+//! split them into. Real-time bytes (`F8`-`FF`) may appear anywhere, even in
+//! the middle of a message, and are skipped. A message that doesn't fit the
+//! buffer is discarded up to its `F7` and reported once, so the next message
+//! starts from a clean state.
+
+use heapless::Vec;
+
+/// Why a message was thrown away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dropped {
+    /// The message didn't fit in the buffer.
+    TooLarge,
+    /// A status byte other than `F7`, such as a new `F0`, cut the message short.
+    Interrupted,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A complete message, from `F0` to `F7`.
+    Message(&'a [u8]),
+    /// A message was thrown away. `start` holds its first bytes, enough to
+    /// tell who it was meant for.
+    Dropped { reason: Dropped, start: &'a [u8] },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Receiving,
+    /// Full; everything up to the next status byte is discarded.
+    Overflowed,
+    /// A new `F0` arrived while the buffer still holds the dropped message.
+    Restarting,
+}
+
+/// Collects SysEx messages of up to `N` bytes, including `F0` and `F7`.
+pub struct Reassembler<const N: usize> {
+    buffer: Vec<u8, N>,
+    phase: Phase,
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            phase: Phase::Idle,
+        }
+    }
+
+    /// Feeds the next byte of the stream, returning an event when a message ends.
+    pub fn push(&mut self, byte: u8) -> Option<Event<'_>> {
+        if byte >= 0xF8 {
+            return None;
+        }
+        if self.phase == Phase::Restarting {
+            self.start();
+        }
+
+        match (byte, self.phase) {
+            (0xF0, Phase::Idle) => {
+                self.start();
+                None
+            }
+            (0xF0, phase) => {
+                self.phase = Phase::Restarting;
+                Some(self.dropped(phase))
+            }
+            (0xF7, Phase::Receiving) => {
+                if self.buffer.push(0xF7).is_err() {
+                    self.phase = Phase::Idle;
+                    return Some(self.dropped(Phase::Overflowed));
+                }
+                self.phase = Phase::Idle;
+                Some(Event::Message(&self.buffer))
+            }
+            (0x80.., Phase::Receiving | Phase::Overflowed) => {
+                let phase = self.phase;
+                self.phase = Phase::Idle;
+                Some(self.dropped(phase))
+            }
+            (0x00..=0x7F, Phase::Receiving) => {
+                if self.buffer.push(byte).is_err() {
+                    self.phase = Phase::Overflowed;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn start(&mut self) {
+        self.buffer.clear();
+        self.buffer.push(0xF0).ok();
+        self.phase = Phase::Receiving;
+    }
+
+    fn dropped(&self, phase: Phase) -> Event<'_> {
+        let reason = match phase {
+            Phase::Overflowed => Dropped::TooLarge,
+            _ => Dropped::Interrupted,
+        };
+        Event::Dropped {
+            reason,
+            start: &self.buffer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `bytes`, collecting an owned copy of every event.
+    fn feed<const N: usize>(
+        reassembler: &mut Reassembler<N>,
+        bytes: &[u8],
+    ) -> std::vec::Vec<Result<std::vec::Vec<u8>, Dropped>> {
+        bytes
+            .iter()
+            .filter_map(|&b| match reassembler.push(b)? {
+                Event::Message(message) => Some(Ok(message.to_vec())),
+                Event::Dropped { reason, .. } => Some(Err(reason)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn collects_messages_larger_than_a_packet() {
+        let mut reassembler = Reassembler::<4096>::new();
+        let mut message = std::vec![0xF0];
+        message.extend((0..3000).map(|i| (i % 128) as u8));
+        message.push(0xF7);
+
+        assert_eq!(feed(&mut reassembler, &message), [Ok(message.clone())]);
+        assert_eq!(feed(&mut reassembler, &message), [Ok(message)]);
+    }
+
+    #[test]
+    fn skips_real_time_bytes() {
+        let mut reassembler = Reassembler::<16>::new();
+        let events = feed(
+            &mut reassembler,
+            &[0xF8, 0xF0, 0x01, 0xF8, 0xFE, 0x02, 0xF7],
+        );
+        assert_eq!(events, [Ok(std::vec![0xF0, 0x01, 0x02, 0xF7])]);
+    }
+
+    #[test]
+    fn ignores_bytes_outside_messages() {
+        let mut reassembler = Reassembler::<16>::new();
+        let events = feed(&mut reassembler, &[0x01, 0xF7, 0x90, 0x40, 0xF0, 0xF7]);
+        assert_eq!(events, [Ok(std::vec![0xF0, 0xF7])]);
+    }
+
+    #[test]
+    fn new_start_resynchronises() {
+        let mut reassembler = Reassembler::<16>::new();
+        let events = feed(&mut reassembler, &[0xF0, 0x01, 0x02, 0xF0, 0x03, 0xF7]);
+        assert_eq!(
+            events,
+            [Err(Dropped::Interrupted), Ok(std::vec![0xF0, 0x03, 0xF7])]
+        );
+
+        // The dropped message's start is still available for an error response.
+        let mut reassembler = Reassembler::<16>::new();
+        feed(&mut reassembler, &[0xF0, 0x7D, 0x53]);
+        assert_eq!(
+            reassembler.push(0xF0),
+            Some(Event::Dropped {
+                reason: Dropped::Interrupted,
+                start: &[0xF0, 0x7D, 0x53],
+            })
+        );
+    }
+
+    #[test]
+    fn status_byte_interrupts() {
+        let mut reassembler = Reassembler::<16>::new();
+        let events = feed(&mut reassembler, &[0xF0, 0x01, 0x90, 0x02, 0xF7]);
+        assert_eq!(events, [Err(Dropped::Interrupted)]);
+    }
+
+    #[test]
+    fn oversize_messages_are_reported_once_and_discarded() {
+        let mut reassembler = Reassembler::<4>::new();
+        let events = feed(&mut reassembler, &[0xF0, 1, 2, 3, 4, 5, 6, 0xF7]);
+        assert_eq!(events, [Err(Dropped::TooLarge)]);
+
+        // Room for the data but not the F7.
+        let events = feed(&mut reassembler, &[0xF0, 1, 2, 3, 0xF7]);
+        assert_eq!(events, [Err(Dropped::TooLarge)]);
+
+        // A new message after an overflow starts clean.
+        let events = feed(&mut reassembler, &[0xF0, 1, 2, 3, 4, 0xF0, 9, 0xF7]);
+        assert_eq!(
+            events,
+            [Err(Dropped::TooLarge), Ok(std::vec![0xF0, 9, 0xF7])]
+        );
+    }
+}
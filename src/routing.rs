@@ -0,0 +1,370 @@
+//! Which messages go where.
+//!
+//! Messages come from three sources: the device's own controls, the host on
+//! the USB thru cable and the DIN input. Each source has a [`Route`] to each
+//! destination, the host and the DIN output, that can be switched off or
+//! filtered by channel and message type. The device's own messages reach the
+//! host on the cable of their attribute, anything else on the thru cable.
+//!
+//! Routes only decide whether a message passes; merging the sources on an
+//! output without breaking up SysEx is up to the output, see
+//! [`Merger`](crate::din::Merger) for the DIN port.
+
+use midi_convert::midi_types::MidiMessage;
+
+use crate::bytes::{Reader, Writer};
+
+/// Where messages come from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Attribute values, presets and the clock sent by the device itself.
+    Local,
+    /// The USB thru cable.
+    Usb,
+    /// The DIN input.
+    Din,
+}
+
+impl Source {
+    pub const ALL: [Self; 3] = [Self::Local, Self::Usb, Self::Din];
+    pub const COUNT: usize = Self::ALL.len();
+
+    /// The source with `number`, its position in [`Source::ALL`].
+    pub fn from_number(number: u8) -> Option<Self> {
+        Self::ALL.get(number as usize).copied()
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Local => "Local",
+            Self::Usb => "USB",
+            Self::Din => "DIN",
+        }
+    }
+}
+
+/// Where messages go.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Usb,
+    Din,
+}
+
+impl Destination {
+    pub const ALL: [Self; 2] = [Self::Usb, Self::Din];
+    pub const COUNT: usize = Self::ALL.len();
+
+    /// The destination with `number`, its position in [`Destination::ALL`].
+    pub fn from_number(number: u8) -> Option<Self> {
+        Self::ALL.get(number as usize).copied()
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Usb => "USB",
+            Self::Din => "DIN",
+        }
+    }
+}
+
+/// Every route, source by source, in the order the routing page lists them.
+pub const ROUTES: [(Source, Destination); Source::COUNT * Destination::COUNT] = [
+    (Source::Local, Destination::Usb),
+    (Source::Local, Destination::Din),
+    (Source::Usb, Destination::Usb),
+    (Source::Usb, Destination::Din),
+    (Source::Din, Destination::Usb),
+    (Source::Din, Destination::Din),
+];
+
+/// Channel mask passing every channel.
+pub const ALL_CHANNELS: u16 = 0xFFFF;
+
+const ENABLED: u8 = 1 << 0;
+const DROP_CLOCK: u8 = 1 << 1;
+const DROP_SYSEX: u8 = 1 << 2;
+const DROP_ACTIVE_SENSING: u8 = 1 << 3;
+
+/// What a source passes to a destination.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub enabled: bool,
+    /// Channels passed, bit 0 for channel 1. System messages have no channel
+    /// and aren't affected.
+    pub channels: u16,
+    /// Drops the clock and transport: ticks, start, continue, stop and song
+    /// position.
+    pub drop_clock: bool,
+    pub drop_sysex: bool,
+    pub drop_active_sensing: bool,
+}
+
+impl Default for Route {
+    fn default() -> Self {
+        Self::OPEN
+    }
+}
+
+impl Route {
+    /// Passes everything.
+    pub const OPEN: Self = Self {
+        enabled: true,
+        channels: ALL_CHANNELS,
+        drop_clock: false,
+        drop_sysex: false,
+        drop_active_sensing: false,
+    };
+
+    /// Passes nothing.
+    pub const CLOSED: Self = Self {
+        enabled: false,
+        ..Self::OPEN
+    };
+
+    /// Whether the route is on but leaves some messages out.
+    pub fn is_filtered(&self) -> bool {
+        self.enabled && *self != Self::OPEN
+    }
+
+    /// Whether `message`, which isn't SysEx, passes.
+    pub fn passes(&self, message: &MidiMessage) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        match *message {
+            MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::SongPositionPointer(_) => !self.drop_clock,
+            MidiMessage::ActiveSensing => !self.drop_active_sensing,
+            MidiMessage::NoteOff(channel, ..)
+            | MidiMessage::NoteOn(channel, ..)
+            | MidiMessage::KeyPressure(channel, ..)
+            | MidiMessage::ControlChange(channel, ..)
+            | MidiMessage::ProgramChange(channel, _)
+            | MidiMessage::ChannelPressure(channel, _)
+            | MidiMessage::PitchBendChange(channel, _) => {
+                self.channels & (1 << u8::from(channel)) != 0
+            }
+            _ => true,
+        }
+    }
+
+    pub fn passes_sysex(&self) -> bool {
+        self.enabled && !self.drop_sysex
+    }
+
+    /// Writes the flags followed by the 16-bit channel mask.
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Option<()> {
+        let flags = [
+            (self.enabled, ENABLED),
+            (self.drop_clock, DROP_CLOCK),
+            (self.drop_sysex, DROP_SYSEX),
+            (self.drop_active_sensing, DROP_ACTIVE_SENSING),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        writer.push(flags)?;
+        writer.push_u16(self.channels)
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Option<Self> {
+        let flags = reader.byte()?;
+        if flags & !(ENABLED | DROP_CLOCK | DROP_SYSEX | DROP_ACTIVE_SENSING) != 0 {
+            return None;
+        }
+        Some(Self {
+            enabled: flags & ENABLED != 0,
+            channels: reader.u16()?,
+            drop_clock: flags & DROP_CLOCK != 0,
+            drop_sysex: flags & DROP_SYSEX != 0,
+            drop_active_sensing: flags & DROP_ACTIVE_SENSING != 0,
+        })
+    }
+}
+
+/// A route from every source to every destination.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Routing {
+    routes: [[Route; Destination::COUNT]; Source::COUNT],
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Routing {
+    /// The device's own messages go everywhere and the thru cable and the DIN
+    /// port are joined, but nothing is echoed back where it came from.
+    pub const fn new() -> Self {
+        Self {
+            routes: [
+                [Route::OPEN, Route::OPEN],
+                [Route::CLOSED, Route::OPEN],
+                [Route::OPEN, Route::CLOSED],
+            ],
+        }
+    }
+
+    pub fn route(&self, source: Source, destination: Destination) -> &Route {
+        &self.routes[source as usize][destination as usize]
+    }
+
+    pub fn set_route(&mut self, source: Source, destination: Destination, route: Route) {
+        self.routes[source as usize][destination as usize] = route;
+    }
+
+    /// Whether `message` from `source`, which isn't SysEx, goes to `destination`.
+    pub fn passes(&self, source: Source, destination: Destination, message: &MidiMessage) -> bool {
+        self.route(source, destination).passes(message)
+    }
+
+    /// Whether SysEx from `source` goes to `destination`.
+    pub fn passes_sysex(&self, source: Source, destination: Destination) -> bool {
+        self.route(source, destination).passes_sysex()
+    }
+
+    /// Writes every route in [`ROUTES`] order.
+    pub(crate) fn encode(&self, writer: &mut Writer) -> Option<()> {
+        ROUTES
+            .iter()
+            .try_for_each(|&(source, destination)| self.route(source, destination).encode(writer))
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Option<Self> {
+        let mut routing = Self::new();
+        for (source, destination) in ROUTES {
+            routing.set_route(source, destination, Route::decode(reader)?);
+        }
+        Some(routing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Channel, Control, Value7};
+
+    use super::*;
+    use crate::din::{Event, Merger, Parser};
+    use crate::usb_midi::Cable;
+
+    fn cc(channel: Channel) -> MidiMessage {
+        MidiMessage::ControlChange(channel, Control::new(20), Value7::new(1))
+    }
+
+    #[test]
+    fn filters_channels_and_message_types() {
+        let route = Route {
+            channels: 1 << 0 | 1 << 15,
+            drop_clock: true,
+            drop_active_sensing: true,
+            ..Route::OPEN
+        };
+        assert!(route.passes(&cc(Channel::C1)));
+        assert!(route.passes(&cc(Channel::C16)));
+        assert!(!route.passes(&cc(Channel::C2)));
+        assert!(!route.passes(&MidiMessage::TimingClock));
+        assert!(!route.passes(&MidiMessage::Start));
+        assert!(!route.passes(&MidiMessage::ActiveSensing));
+        // System messages have no channel to filter on.
+        assert!(route.passes(&MidiMessage::TuneRequest));
+        assert!(route.passes_sysex());
+        assert!(route.is_filtered());
+
+        let closed = Route::CLOSED;
+        assert!(!closed.passes(&cc(Channel::C1)));
+        assert!(!closed.passes(&MidiMessage::TuneRequest));
+        assert!(!closed.passes_sysex());
+        assert!(!closed.is_filtered());
+    }
+
+    #[test]
+    fn routes_are_set_per_source_and_destination() {
+        let mut routing = Routing::new();
+        let message = cc(Channel::C1);
+        assert!(routing.passes(Source::Local, Destination::Din, &message));
+        assert!(routing.passes(Source::Usb, Destination::Din, &message));
+        assert!(!routing.passes(Source::Usb, Destination::Usb, &message));
+
+        let no_sysex = Route {
+            drop_sysex: true,
+            ..Route::OPEN
+        };
+        routing.set_route(Source::Din, Destination::Usb, no_sysex);
+        assert!(routing.passes(Source::Din, Destination::Usb, &message));
+        assert!(!routing.passes_sysex(Source::Din, Destination::Usb));
+        assert!(routing.passes_sysex(Source::Usb, Destination::Din));
+    }
+
+    #[test]
+    fn passed_through_streams_merge_unchanged() {
+        let mut routing = Routing::new();
+        routing.set_route(Source::Din, Destination::Din, Route::OPEN);
+        let sustain =
+            |value| MidiMessage::ControlChange(Channel::C1, Control::new(64), Value7::new(value));
+        let usb = [cc(Channel::C2), sustain(127), sustain(0), cc(Channel::C2)];
+        let din = [sustain(127), cc(Channel::C3), sustain(0)];
+
+        // Both inputs reach the DIN output, one message at a time from each.
+        let mut merger = Merger::<16, 4>::new();
+        let mut sent = std::vec::Vec::new();
+        for index in 0..usb.len() {
+            for (source, messages) in [(Source::Usb, &usb[..]), (Source::Din, &din[..])] {
+                if let Some(&message) = messages.get(index)
+                    && routing.passes(source, Destination::Din, &message)
+                {
+                    merger.push_in_order(Cable::Thru, message);
+                    sent.push(message);
+                }
+            }
+        }
+        assert_eq!(sent.len(), usb.len() + din.len());
+
+        let mut received = std::vec::Vec::new();
+        let mut parser = Parser::<4>::new();
+        let mut buffer = [0; 3];
+        loop {
+            let len = merger.pop(&mut buffer);
+            if len == 0 {
+                break;
+            }
+            for &byte in &buffer[..len] {
+                if let Some(Event::Message(message)) = parser.push(byte) {
+                    received.push(message);
+                }
+            }
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn routing_round_trips() {
+        let mut routing = Routing::new();
+        routing.set_route(
+            Source::Din,
+            Destination::Din,
+            Route {
+                channels: 0x00F0,
+                drop_sysex: true,
+                ..Route::OPEN
+            },
+        );
+
+        let mut buffer = [0; 32];
+        let mut writer = Writer::new(&mut buffer);
+        routing.encode(&mut writer).unwrap();
+        let len = writer.len();
+        assert_eq!(len, ROUTES.len() * 3);
+
+        let mut reader = Reader::new(&buffer[..len]);
+        assert_eq!(Routing::decode(&mut reader), Some(routing));
+        assert!(reader.is_empty());
+
+        // Unknown flags are refused.
+        assert_eq!(Route::decode(&mut Reader::new(&[0x10, 0xFF, 0xFF])), None);
+    }
+}
@@ -0,0 +1,1306 @@
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+use crate::attribute::{Attribute, Attributes, Kind, MAX_ATTRIBUTES};
+use crate::button::Gesture;
+use crate::clock::{self, ClockFollower, ClockGenerator, TapTempo, Transport, bpm_tenths};
+use crate::encoder::{Acceleration, Accelerator};
+use crate::io::MidiSink;
+use crate::parameter::{Parameter, ParameterReceiver};
+use crate::preset::{PRESET_COUNT, Preset, PresetAction, Presets};
+use crate::routing::{Destination, ROUTES, Route, Routing, Source};
+use crate::storage::Settings;
+use crate::usb_midi::Cable;
+use crate::value::FINE_STEPS;
+
+/// What happens to values received from the host.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EchoPolicy {
+    /// Only update the device; nothing is sent back.
+    #[default]
+    Suppress,
+    /// Send the value back out when it changed the device state, for hosts
+    /// that don't pass their own automation through.
+    OnChange,
+    /// Send every matching value back out.
+    Always,
+}
+
+/// The attributes, the presets and the UI position.
+///
+/// The button cycles through every attribute followed by a preset page, on
+/// which the encoder picks a [`PresetAction`] that the next press carries out.
+/// On a toggle a click flips it instead; a double click still leaves for the
+/// previous option. On the tempo presses tap it and a long press moves on.
+/// From the preset page a click followed by holding the button opens the
+/// routing page, on which the encoder picks a route and a click switches it
+/// on or off. See [`State::gesture`] for what the other button gestures do.
+pub struct State {
+    attributes: Attributes,
+    selected_option: usize,
+    echo_policy: EchoPolicy,
+    presets: Presets,
+    preset_cursor: usize,
+    program_channel: Option<Channel>,
+    routing: Routing,
+    /// The highlighted entry of [`ROUTES`] while the routing page is open.
+    route_cursor: Option<usize>,
+    /// Whether the table was edited at runtime and has to be persisted.
+    custom_table: bool,
+    accelerator: Accelerator,
+    /// Parameter selection is followed per cable, as each is a port of its own.
+    parameters: [ParameterReceiver; Cable::COUNT],
+    follower: ClockFollower,
+    generator: ClockGenerator,
+    taps: TapTempo,
+    /// The beat tempo-synced attributes were last set for.
+    synced_beat: Option<u32>,
+    /// Whether the selected attribute takes its binding from the next
+    /// message, see [`State::learn`].
+    learning: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Creates a state without attributes; load a table with [`State::set_attributes`].
+    pub const fn new() -> Self {
+        Self {
+            attributes: Attributes::new(),
+            selected_option: 0,
+            echo_policy: EchoPolicy::Suppress,
+            presets: [const { None }; PRESET_COUNT],
+            preset_cursor: 0,
+            program_channel: None,
+            routing: Routing::new(),
+            route_cursor: None,
+            custom_table: false,
+            accelerator: Accelerator::new(),
+            parameters: [const { ParameterReceiver::new() }; Cable::COUNT],
+            follower: ClockFollower::new(),
+            generator: ClockGenerator::new(),
+            taps: TapTempo::new(),
+            synced_beat: None,
+            learning: false,
+        }
+    }
+
+    /// Replaces the attribute table and selects its first entry.
+    pub fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes;
+        self.selected_option = 0;
+        self.route_cursor = None;
+        self.learning = false;
+    }
+
+    pub fn attributes(&self) -> &[Attribute] {
+        &self.attributes
+    }
+
+    pub fn selected_option(&self) -> usize {
+        self.selected_option
+    }
+
+    /// The selected attribute, or `None` on the preset page.
+    pub fn selected(&self) -> Option<&Attribute> {
+        self.attributes.get(self.selected_option)
+    }
+
+    /// The highlighted preset page entry, or `None` when an attribute or the
+    /// routing page is shown.
+    pub fn preset_action(&self) -> Option<PresetAction> {
+        (self.selected_option == self.attributes.len() && self.route_cursor.is_none())
+            .then(|| PresetAction::from_cursor(self.preset_cursor))
+    }
+
+    pub fn presets(&self) -> &Presets {
+        &self.presets
+    }
+
+    pub fn routing(&self) -> &Routing {
+        &self.routing
+    }
+
+    pub fn set_route(&mut self, source: Source, destination: Destination, route: Route) {
+        self.routing.set_route(source, destination, route);
+    }
+
+    /// The highlighted entry of [`ROUTES`], or `None` unless the routing page is open.
+    pub fn route_cursor(&self) -> Option<usize> {
+        self.route_cursor
+    }
+
+    /// Opens the routing page from the preset page, or closes it again.
+    pub fn toggle_routing(&mut self) {
+        self.route_cursor = match self.route_cursor {
+            Some(_) => None,
+            None => self.preset_action().map(|_| 0),
+        };
+    }
+
+    /// Replaces the attribute at `index`, or appends it when `index` is the table length.
+    pub fn set_attribute(&mut self, index: usize, attribute: Attribute) -> bool {
+        let len = self.attributes.len();
+        let stored = match self.attributes.get_mut(index) {
+            Some(slot) => {
+                *slot = attribute;
+                true
+            }
+            None if index == len => self.attributes.push(attribute).is_ok(),
+            None => false,
+        };
+        self.custom_table |= stored;
+        stored
+    }
+
+    /// Sets the value of the attribute at `index` and sends it out.
+    pub fn set_value(&mut self, index: usize, value: u8, sink: &mut impl MidiSink) -> bool {
+        let Some(attr) = self.attributes.get_mut(index) else {
+            return false;
+        };
+
+        attr.set_value(value);
+        attr.send(sink);
+        true
+    }
+
+    pub fn set_preset(&mut self, slot: usize, preset: Option<Preset>) -> bool {
+        let Some(stored) = self.presets.get_mut(slot) else {
+            return false;
+        };
+        *stored = preset;
+        true
+    }
+
+    /// Handles an encoder turn of `delta` detents at `now_ms`, accelerated by
+    /// the selected attribute's curve. The preset page, and attributes picking
+    /// note divisions, always move singly.
+    pub fn turn(&mut self, delta: i16, now_ms: u64, sink: &mut impl MidiSink) {
+        let curve = self
+            .selected()
+            .filter(|attr| self.division(attr).is_none())
+            .map_or(Acceleration::OFF, |attr| attr.acceleration);
+        let steps = self.accelerator.apply(delta, now_ms, curve);
+        self.adjust_selected(steps, sink);
+    }
+
+    /// Handles a turn made while holding the button.
+    ///
+    /// Attributes with fine steps move by accelerated fine steps, [`FINE_STEPS`]
+    /// of them per value step. Others, and the preset page, move in single
+    /// steps however fast the turn.
+    pub fn fine_turn(&mut self, delta: i16, now_ms: u64, sink: &mut impl MidiSink) {
+        let Some(attr) = self
+            .attributes
+            .get_mut(self.selected_option)
+            .filter(|attr| attr.has_fine_steps())
+        else {
+            self.adjust_selected(delta, sink);
+            return;
+        };
+
+        let steps = self.accelerator.apply(delta, now_ms, attr.acceleration);
+        attr.set_position(attr.position() as i32 + steps as i32);
+        attr.send(sink);
+    }
+
+    /// Moves the route or preset cursor, or the selected attribute by `delta`
+    /// of its steps, keeping its fine position. Tempo-synced attributes move
+    /// by note divisions while there is a tempo.
+    pub fn adjust_selected(&mut self, delta: i16, sink: &mut impl MidiSink) {
+        if let Some(cursor) = self.route_cursor {
+            self.route_cursor = Some(
+                (cursor as i16)
+                    .saturating_add(delta)
+                    .clamp(0, ROUTES.len() as i16 - 1) as usize,
+            );
+        } else if self.preset_action().is_some() {
+            self.preset_cursor = (self.preset_cursor as i16)
+                .saturating_add(delta)
+                .clamp(0, PresetAction::COUNT as i16 - 1) as usize;
+        } else {
+            let beat_us = self.beat_us();
+            let Some(attr) = self.attributes.get_mut(self.selected_option) else {
+                return;
+            };
+            let synced = beat_us.is_some_and(|beat_us| attr.step_division(delta, beat_us));
+            if !synced {
+                attr.step_by(delta);
+            }
+            attr.send(sink);
+        }
+    }
+
+    /// Snapshot of everything that should survive a power cycle.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            selected: self.selected_option as u8,
+            echo_policy: self.echo_policy,
+            values: self.values(),
+            fine: self.attributes.iter().map(|attr| attr.fine).collect(),
+            program_channel: self.program_channel,
+            presets: self.presets.clone(),
+            attributes: self.custom_table.then(|| self.attributes.clone()),
+            routing: self.routing,
+        }
+    }
+
+    /// Restores saved settings on top of the current table.
+    ///
+    /// A table edited over SysEx replaces the compiled-in one. Values are
+    /// matched by position and clamped, so a saved record stays usable after
+    /// the compiled-in table changed.
+    pub fn restore(&mut self, settings: &Settings) {
+        if let Some(attributes) = &settings.attributes {
+            self.attributes = attributes.clone();
+            self.custom_table = true;
+        }
+        for (attr, &value) in self.attributes.iter_mut().zip(settings.values.iter()) {
+            attr.set_value(value);
+        }
+        for (attr, &fine) in self.attributes.iter_mut().zip(settings.fine.iter()) {
+            attr.set_position((attr.position() + fine.min(FINE_STEPS as u8 - 1) as u16) as i32);
+        }
+        if (settings.selected as usize) < self.attributes.len() {
+            self.selected_option = settings.selected as usize;
+        }
+        self.echo_policy = settings.echo_policy;
+        self.program_channel = settings.program_channel;
+        self.presets = settings.presets.clone();
+        self.routing = settings.routing;
+    }
+
+    pub fn set_echo_policy(&mut self, policy: EchoPolicy) {
+        self.echo_policy = policy;
+    }
+
+    /// Sets the channel whose Program Change messages recall presets, `None` to ignore them.
+    pub fn set_program_channel(&mut self, channel: Option<Channel>) {
+        self.program_channel = channel;
+    }
+
+    /// Applies a message received from the host on `cable`.
+    ///
+    /// Values update every attribute on that cable bound to them in the same
+    /// encoding, RPNs and NRPNs once their Data Entry arrives. Program Changes
+    /// on the program channel of the control cable recall a preset. While
+    /// [learning](State::learn) the value is taken by the attribute it
+    /// rebinds. Returns `true` when the state changed as a result.
+    pub fn apply_remote(
+        &mut self,
+        cable: Cable,
+        message: &MidiMessage,
+        sink: &mut impl MidiSink,
+    ) -> bool {
+        match *message {
+            MidiMessage::ControlChange(channel, control, value) => {
+                let parameter =
+                    self.parameters[cable.number() as usize].receive(channel, control, value);
+                let learned =
+                    self.learn_from(cable, message, parameter.map(|(parameter, _)| parameter));
+                let mut matched = self.apply_value(cable, |attr| attr.receive(message), sink);
+                if let Some((parameter, value)) = parameter {
+                    matched |= self.apply_value(
+                        cable,
+                        |attr| attr.receive_parameter(channel, parameter, value),
+                        sink,
+                    );
+                }
+                learned | matched
+            }
+            MidiMessage::PitchBendChange(..) | MidiMessage::ChannelPressure(..) => {
+                let learned = self.learn_from(cable, message, None);
+                learned | self.apply_value(cable, |attr| attr.receive(message), sink)
+            }
+            MidiMessage::ProgramChange(channel, program)
+                if cable == Cable::Control && Some(channel) == self.program_channel =>
+            {
+                self.recall_preset(u8::from(program) as usize, sink)
+            }
+            _ => false,
+        }
+    }
+
+    /// Follows a received MIDI clock or transport message at `now_us`.
+    ///
+    /// While another clock is followed the device sends none of its own.
+    /// Returns `true` when the tempo changed, so that
+    /// [`State::clock_deadline`] should be waited for.
+    pub fn receive_clock(
+        &mut self,
+        message: &MidiMessage,
+        now_us: u64,
+        sink: &mut impl MidiSink,
+    ) -> bool {
+        if !self.follower.receive(message, now_us) {
+            return false;
+        }
+        self.resync(sink);
+        true
+    }
+
+    /// When [`State::poll_clock`] next has something to do, in microseconds:
+    /// the next tick to send, or when a followed clock is considered stopped.
+    pub fn clock_deadline(&self) -> Option<u64> {
+        match (
+            self.follower.deadline(),
+            self.generator.deadline(self.master_beat()),
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Sends the clock ticks due by `now_us`, and forgets a followed clock
+    /// once it timed out, which hands the clock back to the tempo attribute.
+    /// Returns `true` if it timed out.
+    pub fn poll_clock(&mut self, now_us: u64, sink: &mut impl MidiSink) -> bool {
+        let timed_out = self.follower.poll(now_us);
+        self.generator.poll(self.master_beat(), now_us, sink);
+        self.resync(sink);
+        timed_out
+    }
+
+    /// Length of a beat: of the followed clock, or else of the one sent.
+    pub fn beat_us(&self) -> Option<u32> {
+        self.follower.beat_us().or(self.master_beat())
+    }
+
+    /// The tempo in tenths of a BPM, see [`State::beat_us`].
+    pub fn bpm_tenths(&self) -> Option<u16> {
+        self.beat_us().map(bpm_tenths)
+    }
+
+    /// Whether the tempo comes from another clock.
+    pub fn is_following(&self) -> bool {
+        self.follower.beat_us().is_some()
+    }
+
+    /// Whether the device sends the clock: there is a [`Kind::Tempo`]
+    /// attribute and no other clock to follow.
+    fn master_beat(&self) -> Option<u32> {
+        if self.is_following() {
+            return None;
+        }
+        let tempo = self
+            .attributes
+            .iter()
+            .find(|attr| attr.kind == Kind::Tempo)?;
+        Some(clock::beat_us(tempo.value))
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.generator.transport()
+    }
+
+    /// Beats played as clock master since the start of the song.
+    pub fn song_beats(&self) -> u32 {
+        self.generator.song_beats()
+    }
+
+    /// Starts or continues playing as clock master.
+    pub fn play(&mut self, sink: &mut impl MidiSink) {
+        if self.master_beat().is_some() {
+            self.generator.play(sink);
+        }
+    }
+
+    /// Stops playing as clock master, or goes back to the start once stopped.
+    pub fn stop(&mut self, sink: &mut impl MidiSink) {
+        if self.master_beat().is_some() {
+            self.generator.stop(sink);
+        }
+    }
+
+    /// Whether presses tap the tempo, see [`Gestures::set_taps`](crate::button::Gestures::set_taps).
+    pub fn taps_tempo(&self) -> bool {
+        self.selected().is_some_and(|attr| attr.kind == Kind::Tempo)
+    }
+
+    /// Takes a tap at `now_ms` on the selected tempo attribute, which moves to
+    /// the tapped tempo from the second tap on.
+    pub fn tap(&mut self, now_ms: u64) {
+        let Some(attr) = self
+            .attributes
+            .get_mut(self.selected_option)
+            .filter(|attr| attr.kind == Kind::Tempo)
+        else {
+            return;
+        };
+        if let Some(beat_us) = self.taps.tap(now_ms) {
+            let bpm = (60_000_000 + beat_us / 2) / beat_us;
+            attr.set_value(bpm.min(u8::MAX as u32) as u8);
+        }
+    }
+
+    /// The note division `attr` is set to, while there is a tempo and the
+    /// attribute is tempo-synced.
+    pub fn division(&self, attr: &Attribute) -> Option<usize> {
+        attr.division(self.beat_us()?)
+    }
+
+    /// Moves tempo-synced attributes to the same note division at a new
+    /// tempo, and sends them out. They stay where they are when a tempo first
+    /// appears.
+    fn resync(&mut self, sink: &mut impl MidiSink) {
+        let beat_us = self.beat_us();
+        if let (Some(previous), Some(beat_us)) = (self.synced_beat, beat_us)
+            && previous != beat_us
+        {
+            for attr in self.attributes.iter_mut() {
+                if let Some(index) = attr.division(previous) {
+                    let value = attr.value;
+                    attr.set_division(index, beat_us);
+                    if attr.value != value {
+                        attr.send(sink);
+                    }
+                }
+            }
+        }
+        self.synced_beat = beat_us;
+    }
+
+    /// Offers a value received on `cable` to every attribute on it, echoing
+    /// it as configured.
+    fn apply_value(
+        &mut self,
+        cable: Cable,
+        mut receive: impl FnMut(&mut Attribute) -> Option<bool>,
+        sink: &mut impl MidiSink,
+    ) -> bool {
+        let mut matched = false;
+        for attr in self
+            .attributes
+            .iter_mut()
+            .filter(|attr| attr.cable == cable)
+        {
+            let Some(changed) = receive(attr) else {
+                continue;
+            };
+            matched = true;
+
+            let echo = match self.echo_policy {
+                EchoPolicy::Suppress => false,
+                EchoPolicy::OnChange => changed,
+                EchoPolicy::Always => true,
+            };
+            if echo {
+                attr.send(sink);
+            }
+        }
+
+        matched
+    }
+
+    /// Moves to the next attribute, or from the last one to the preset page.
+    pub fn next_option(&mut self) {
+        self.selected_option = (self.selected_option + 1) % (self.attributes.len() + 1);
+        self.preset_cursor = 0;
+        self.route_cursor = None;
+        self.accelerator.reset();
+        self.learning = false;
+    }
+
+    /// Moves to the previous attribute, or from the first one to the preset page.
+    pub fn previous_option(&mut self) {
+        let options = self.attributes.len() + 1;
+        self.selected_option = (self.selected_option + options - 1) % options;
+        self.preset_cursor = 0;
+        self.route_cursor = None;
+        self.accelerator.reset();
+        self.learning = false;
+    }
+
+    /// Starts MIDI Learn for the selected attribute, or cancels it: the next
+    /// Control Change, RPN, NRPN, pitch bend or channel pressure received
+    /// rebinds the attribute to its cable, channel and controller. The tempo
+    /// and the preset page can't learn.
+    pub fn learn(&mut self) {
+        self.learning =
+            !self.learning && self.selected().is_some_and(|attr| attr.kind != Kind::Tempo);
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learning
+    }
+
+    /// Rebinds the attribute being learned to `message`, or to `parameter`
+    /// once one was received in full. The new binding is persisted with the
+    /// table.
+    fn learn_from(
+        &mut self,
+        cable: Cable,
+        message: &MidiMessage,
+        parameter: Option<Parameter>,
+    ) -> bool {
+        if !self.learning {
+            return false;
+        }
+        let Some(attr) = self.attributes.get_mut(self.selected_option) else {
+            return false;
+        };
+
+        let learned = match (parameter, *message) {
+            (Some(parameter), MidiMessage::ControlChange(channel, ..)) => {
+                attr.learn_parameter(channel, parameter);
+                true
+            }
+            _ => attr.learn(message),
+        };
+        if learned {
+            attr.cable = cable;
+            self.learning = false;
+            self.custom_table = true;
+        }
+        learned
+    }
+
+    /// Sets the selected attribute back to its default and sends it out.
+    /// On the preset and routing pages this returns to the first attribute
+    /// instead.
+    pub fn reset_selected(&mut self, sink: &mut impl MidiSink) {
+        match self.selected() {
+            Some(attr) => {
+                let default = attr.default;
+                self.set_value(self.selected_option, default, sink);
+            }
+            None => {
+                self.selected_option = 0;
+                self.preset_cursor = 0;
+                self.route_cursor = None;
+            }
+        }
+    }
+
+    /// Handles a button gesture:
+    ///
+    /// - a click is a [`State::press`],
+    /// - a double click goes back to the previous option,
+    /// - a long press resets the selected attribute, see [`State::reset_selected`],
+    /// - turning while holding the button adjusts finely, see [`State::fine_turn`],
+    /// - a click followed by holding the button starts or cancels MIDI Learn,
+    ///   see [`State::learn`], or on the preset and routing pages opens or
+    ///   closes the routing page, see [`State::toggle_routing`].
+    ///
+    /// On the tempo taps set it, see [`State::tap`], a long press moves on to
+    /// the next option, and turning while holding the button plays to the
+    /// right or stops to the left.
+    pub fn gesture(
+        &mut self,
+        gesture: Gesture,
+        now_ms: u64,
+        sink: &mut impl MidiSink,
+    ) -> Option<PresetAction> {
+        if self.taps_tempo() {
+            match gesture {
+                Gesture::Tap(at_ms) => self.tap(at_ms),
+                Gesture::LongPress => self.next_option(),
+                Gesture::PressAndTurn(delta) if delta > 0 => self.play(sink),
+                Gesture::PressAndTurn(_) => self.stop(sink),
+                Gesture::Click | Gesture::DoubleClick | Gesture::ClickAndHold => {}
+            }
+            return None;
+        }
+
+        match gesture {
+            Gesture::Click => return self.press(sink),
+            Gesture::DoubleClick => self.previous_option(),
+            Gesture::LongPress => self.reset_selected(sink),
+            Gesture::ClickAndHold if self.selected().is_none() => self.toggle_routing(),
+            Gesture::ClickAndHold => self.learn(),
+            Gesture::PressAndTurn(delta) => self.fine_turn(delta, now_ms, sink),
+            Gesture::Tap(_) => {}
+        }
+        None
+    }
+
+    /// Handles a button press: carries out the highlighted preset action when
+    /// on the preset page, then moves on. A selected toggle is flipped and
+    /// sent instead, staying selected, and on the routing page the highlighted
+    /// route is switched on or off.
+    pub fn press(&mut self, sink: &mut impl MidiSink) -> Option<PresetAction> {
+        if let Some(cursor) = self.route_cursor {
+            let (source, destination) = ROUTES[cursor];
+            let mut route = *self.routing.route(source, destination);
+            route.enabled = !route.enabled;
+            self.routing.set_route(source, destination, route);
+            return None;
+        }
+        if let Some(attr) = self.attributes.get_mut(self.selected_option)
+            && attr.toggle()
+        {
+            attr.send(sink);
+            return None;
+        }
+
+        let action = self.preset_action();
+        match action {
+            Some(PresetAction::Recall(slot)) => {
+                self.recall_preset(slot, sink);
+            }
+            Some(PresetAction::Store(slot)) => self.store_preset(slot),
+            Some(PresetAction::Back) | None => {}
+        }
+        self.next_option();
+        action
+    }
+
+    /// Snapshots the current values into `slot`, keeping its name if it had one.
+    pub fn store_preset(&mut self, slot: usize) {
+        let values = self.values();
+        if let Some(preset) = self.presets.get_mut(slot) {
+            let name = preset
+                .take()
+                .map_or_else(|| Preset::default_name(slot), |preset| preset.name);
+            *preset = Some(Preset { name, values });
+        }
+    }
+
+    /// Loads the values stored in `slot` and sends them out so the synth follows.
+    ///
+    /// Returns `false` if the slot is empty or doesn't exist.
+    pub fn recall_preset(&mut self, slot: usize, sink: &mut impl MidiSink) -> bool {
+        let Some(Some(preset)) = self.presets.get(slot) else {
+            return false;
+        };
+
+        for (attr, &value) in self.attributes.iter_mut().zip(preset.values.iter()) {
+            attr.set_value(value);
+            attr.send(sink);
+        }
+        true
+    }
+
+    fn values(&self) -> heapless::Vec<u8, MAX_ATTRIBUTES> {
+        self.attributes.iter().map(|attr| attr.value).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Control, Program, Value7};
+
+    use super::*;
+    use crate::attribute::{Encoding, Format, Kind};
+
+    fn state() -> State {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([
+            Attribute::new("Delay", Channel::C1, Control::new(20))
+                .with_range(0, 100)
+                .with_default(15),
+            Attribute::new("Feedback", Channel::C1, Control::new(21))
+                .with_range(0, 100)
+                .with_default(50)
+                .with_acceleration(Acceleration::OFF),
+            Attribute::new("Mix", Channel::C2, Control::new(22)),
+        ]));
+        state
+    }
+
+    #[test]
+    fn adjust_sends_control_change() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        state.adjust_selected(5, &mut sent);
+
+        // Sent scaled from 0..=100 onto 0..=127.
+        assert_eq!(state.attributes()[0].value, 20);
+        assert_eq!(
+            sent,
+            [MidiMessage::ControlChange(
+                Channel::C1,
+                Control::new(20),
+                Value7::from(25)
+            )]
+        );
+    }
+
+    #[test]
+    fn adjust_clamps_to_range() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        state.adjust_selected(-100, &mut sent);
+        assert_eq!(state.attributes()[0].value, 0);
+
+        state.adjust_selected(500, &mut sent);
+        assert_eq!(state.attributes()[0].value, 100);
+    }
+
+    #[test]
+    fn turns_accelerate_per_attribute() {
+        let mut state = state();
+        let mut sent = Vec::new();
+        let mut spin = |state: &mut State, start: u64| {
+            for i in 0..5 {
+                state.turn(1, start + i * 10, &mut sent);
+            }
+        };
+
+        // A detent every 10 ms: one single step, then full steps.
+        spin(&mut state, 1000);
+        assert_eq!(state.attributes()[0].value, 15 + 1 + 4 * 10);
+
+        state.next_option();
+        spin(&mut state, 2000);
+        assert_eq!(state.attributes()[1].value, 55);
+
+        state.next_option();
+        state.next_option();
+        spin(&mut state, 3000);
+        assert_eq!(state.preset_action(), Some(PresetAction::from_cursor(5)));
+    }
+
+    #[test]
+    fn next_option_wraps_through_the_preset_page() {
+        let mut state = state();
+
+        state.next_option();
+        assert_eq!(state.selected().unwrap().name, "Feedback");
+
+        state.next_option();
+        state.next_option();
+        assert!(state.selected().is_none());
+        assert_eq!(state.preset_action(), Some(PresetAction::Back));
+
+        state.next_option();
+        assert_eq!(state.selected_option(), 0);
+    }
+
+    #[test]
+    fn gestures_navigate_and_reset() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        state.gesture(Gesture::DoubleClick, 0, &mut sent);
+        assert_eq!(state.preset_action(), Some(PresetAction::Back));
+        // Back to the first attribute, as a long press on the preset page.
+        state.gesture(Gesture::LongPress, 0, &mut sent);
+        assert_eq!(state.selected_option(), 0);
+        assert!(sent.is_empty());
+
+        assert_eq!(state.gesture(Gesture::Click, 0, &mut sent), None);
+        assert_eq!(state.selected().unwrap().name, "Feedback");
+        state.gesture(Gesture::DoubleClick, 0, &mut sent);
+        assert_eq!(state.selected_option(), 0);
+
+        // Held turns on a 7-bit attribute move in single steps however fast they come.
+        for i in 0..5 {
+            state.gesture(Gesture::PressAndTurn(1), 1000 + i * 10, &mut sent);
+        }
+        assert_eq!(state.attributes()[0].value, 20);
+
+        sent.clear();
+        state.gesture(Gesture::LongPress, 0, &mut sent);
+        assert_eq!(state.attributes()[0].value, 15);
+        assert_eq!(sent, [control_change(Channel::C1, 20, 19)]);
+    }
+
+    #[test]
+    fn routing_page_switches_routes() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        // Only opens from the preset page; elsewhere the gesture learns.
+        state.gesture(Gesture::ClickAndHold, 0, &mut sent);
+        assert_eq!(state.route_cursor(), None);
+        state.gesture(Gesture::ClickAndHold, 0, &mut sent);
+
+        state.previous_option();
+        // Cursors stop at the ends however far they are moved.
+        state.adjust_selected(1, &mut sent);
+        state.adjust_selected(i16::MAX, &mut sent);
+        assert_eq!(
+            state.preset_action(),
+            Some(PresetAction::Store(PRESET_COUNT - 1))
+        );
+        state.adjust_selected(i16::MIN, &mut sent);
+        state.gesture(Gesture::ClickAndHold, 0, &mut sent);
+        assert_eq!(state.route_cursor(), Some(0));
+        assert_eq!(state.preset_action(), None);
+
+        // Turns pick a route and clicks switch it, staying on the page.
+        state.turn(2, 0, &mut sent);
+        state.turn(10, 1000, &mut sent);
+        state.adjust_selected(i16::MAX, &mut sent);
+        assert_eq!(state.route_cursor(), Some(ROUTES.len() - 1));
+        state.gesture(Gesture::Click, 0, &mut sent);
+        assert!(state.routing().route(Source::Din, Destination::Din).enabled);
+        state.turn(-2, 2000, &mut sent);
+        state.gesture(Gesture::Click, 0, &mut sent);
+        assert!(!state.routing().route(Source::Usb, Destination::Din).enabled);
+        assert_eq!(state.route_cursor(), Some(3));
+        assert!(sent.is_empty());
+
+        // Back to the preset page, and the routing survives a restart.
+        state.gesture(Gesture::ClickAndHold, 0, &mut sent);
+        assert_eq!(state.preset_action(), Some(PresetAction::Back));
+        let mut restored = self::state();
+        restored.restore(&state.settings());
+        assert_eq!(restored.routing(), state.routing());
+
+        // A long press leaves for the first attribute.
+        state.toggle_routing();
+        state.gesture(Gesture::LongPress, 0, &mut sent);
+        assert_eq!(state.route_cursor(), None);
+        assert_eq!(state.selected_option(), 0);
+    }
+
+    #[test]
+    fn clicks_flip_toggles_and_bipolar_turns_stop_at_the_centre() {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([
+            Attribute::new("Sync", Channel::C1, Control::new(30)).with_kind(Kind::Toggle),
+            Attribute::new("Pan", Channel::C1, Control::new(10))
+                .with_kind(Kind::Bipolar)
+                .with_default(60),
+        ]));
+        let mut sent = Vec::new();
+
+        assert_eq!(state.gesture(Gesture::Click, 0, &mut sent), None);
+        assert_eq!(state.gesture(Gesture::Click, 0, &mut sent), None);
+        assert_eq!(state.selected_option(), 0);
+        assert_eq!(
+            sent,
+            [
+                control_change(Channel::C1, 30, 127),
+                control_change(Channel::C1, 30, 0)
+            ]
+        );
+
+        state.gesture(Gesture::DoubleClick, 0, &mut sent);
+        state.gesture(Gesture::DoubleClick, 0, &mut sent);
+        assert_eq!(state.selected().unwrap().name, "Pan");
+        state.adjust_selected(10, &mut sent);
+        assert_eq!(state.attributes()[1].value, 64);
+        state.adjust_selected(10, &mut sent);
+        assert_eq!(state.attributes()[1].value, 74);
+        assert_eq!(state.attributes()[1].to_human_readable(), "+10");
+    }
+
+    #[test]
+    fn synced_attributes_follow_the_clock() {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([Attribute::new(
+            "Delay",
+            Channel::C1,
+            Control::new(20),
+        )
+        .with_range(0, 100)
+        .with_default(50)
+        .with_format(Format::Milliseconds(1000))
+        .with_tempo_sync()]));
+        let mut sent = Vec::new();
+        let clock = |state: &mut State, bpm: u64, from_us: u64, sent: &mut Vec<_>| {
+            let tick_us = 60_000_000 / bpm / 24;
+            (0..48).fold(false, |changed, tick| {
+                let now_us = from_us + tick * tick_us;
+                state.receive_clock(&MidiMessage::TimingClock, now_us, sent) | changed
+            })
+        };
+
+        // Without a clock turns move by steps.
+        state.turn(1, 0, &mut sent);
+        assert_eq!(state.attributes()[0].value, 51);
+
+        assert!(clock(&mut state, 120, 0, &mut sent));
+        assert_eq!(state.bpm_tenths(), Some(1200));
+        assert_eq!(state.division(&state.attributes()[0]), Some(8));
+        state.turn(1, 100, &mut sent);
+        assert_eq!(state.attributes()[0].value, 67);
+        state.turn(1, 110, &mut sent);
+        assert_eq!(state.attributes()[0].to_human_readable(), "750 ms");
+
+        // Slowing down to 100 BPM keeps the dotted quarter.
+        sent.clear();
+        assert!(clock(&mut state, 100, 1_000_000, &mut sent));
+        assert_eq!(state.attributes()[0].value, 90);
+        assert_eq!(sent.last(), Some(&control_change(Channel::C1, 20, 114)));
+
+        // Once the clock stops the steps are back.
+        let deadline = state.clock_deadline().unwrap();
+        assert!(!state.poll_clock(deadline - 1, &mut sent));
+        assert!(state.poll_clock(deadline, &mut sent));
+        assert_eq!(state.clock_deadline(), None);
+        state.turn(1, 5_000, &mut sent);
+        assert_eq!(state.attributes()[0].value, 91);
+    }
+
+    #[test]
+    fn tempo_attribute_makes_the_device_clock_master() {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([
+            Attribute::new("Tempo", Channel::C1, Control::new(0))
+                .with_kind(Kind::Tempo)
+                .with_default(120),
+            Attribute::new("Delay", Channel::C1, Control::new(20))
+                .with_range(0, 100)
+                .with_default(50)
+                .with_format(Format::Milliseconds(1000))
+                .with_tempo_sync(),
+        ]));
+        let mut sent = Vec::new();
+
+        // The first tick goes out right away, then every 24th of a beat.
+        assert_eq!(state.clock_deadline(), Some(0));
+        state.poll_clock(1000, &mut sent);
+        assert_eq!(sent, [MidiMessage::TimingClock]);
+        assert_eq!(state.clock_deadline(), Some(1000 + 20_833));
+
+        // Tapping 100 BPM moves the synced delay from a quarter of 500 ms to
+        // one of 600 ms. Holding the button and turning right plays.
+        assert!(state.taps_tempo());
+        for at_ms in [0, 600, 1200] {
+            state.gesture(Gesture::Tap(at_ms), at_ms, &mut sent);
+        }
+        assert_eq!(state.attributes()[0].value, 100);
+        sent.clear();
+        state.gesture(Gesture::PressAndTurn(1), 1300, &mut sent);
+        state.poll_clock(1_000_000, &mut sent);
+        assert_eq!(
+            sent,
+            [
+                MidiMessage::Start,
+                MidiMessage::TimingClock,
+                control_change(Channel::C1, 20, 76)
+            ]
+        );
+
+        // Another clock takes over, and hands back once it stops.
+        let tick_us = 60_000_000 / 140 / 24;
+        for tick in 0..48 {
+            state.receive_clock(
+                &MidiMessage::TimingClock,
+                2_000_000 + tick * tick_us,
+                &mut sent,
+            );
+        }
+        assert!(state.is_following());
+        assert_eq!(state.bpm_tenths(), Some(1400));
+        assert_eq!(state.attributes()[1].to_human_readable(), "430 ms");
+        sent.clear();
+        let deadline = state.clock_deadline().unwrap();
+        assert!(!state.poll_clock(deadline - 1, &mut sent));
+        assert_eq!(sent, []);
+        assert!(state.poll_clock(deadline, &mut sent));
+        assert_eq!(state.bpm_tenths(), Some(1000));
+        assert_eq!(sent[0], MidiMessage::TimingClock);
+
+        // Turning left while held stops; a long press moves on.
+        sent.clear();
+        state.gesture(Gesture::PressAndTurn(-1), 0, &mut sent);
+        state.gesture(Gesture::LongPress, 0, &mut sent);
+        assert_eq!(sent, [MidiMessage::Stop]);
+        assert_eq!(state.selected_option(), 1);
+        assert!(!state.taps_tempo());
+    }
+
+    #[test]
+    fn fine_turns_reach_14_bit_attributes() {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([Attribute::new(
+            "Cutoff",
+            Channel::C1,
+            Control::new(20),
+        )
+        .with_default(64)
+        .with_encoding(Encoding::Control14)]));
+        let mut sent = Vec::new();
+
+        // Slow held turns move a single fine step each.
+        state.fine_turn(1, 1000, &mut sent);
+        state.fine_turn(1, 1500, &mut sent);
+        state.fine_turn(-3, 2000, &mut sent);
+        assert_eq!(state.attributes()[0].position(), 64 * 128 - 1);
+        assert_eq!(
+            sent[sent.len() - 2..],
+            [
+                control_change(Channel::C1, 20, 63),
+                control_change(Channel::C1, 52, 127)
+            ]
+        );
+
+        // Normal turns keep the fine position.
+        state.turn(1, 3000, &mut sent);
+        assert_eq!(state.attributes()[0].position(), 65 * 128 - 1);
+
+        // The fine controller is received and persisted.
+        state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 52, 5),
+            &mut sent,
+        );
+        let settings = state.settings();
+        assert_eq!(settings.fine, [5]);
+        let mut restored = State::new();
+        restored.set_attributes(state.attributes.clone());
+        restored.restore(&settings);
+        assert_eq!(restored.attributes()[0].position(), 64 * 128 + 5);
+    }
+
+    fn go_to_preset_page(state: &mut State) {
+        while state.preset_action().is_none() {
+            state.next_option();
+        }
+    }
+
+    #[test]
+    fn presets_are_stored_and_recalled_from_the_ui() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        // Store the defaults in slot 2.
+        go_to_preset_page(&mut state);
+        state.adjust_selected((PRESET_COUNT + 2) as i16, &mut sent);
+        assert_eq!(state.press(&mut sent), Some(PresetAction::Store(1)));
+        assert_eq!(state.selected_option(), 0);
+        assert_eq!(state.presets()[1].as_ref().unwrap().name, "Preset 2");
+
+        state.adjust_selected(50, &mut sent);
+        sent.clear();
+
+        go_to_preset_page(&mut state);
+        state.adjust_selected(2, &mut sent);
+        assert_eq!(state.press(&mut sent), Some(PresetAction::Recall(1)));
+        assert_eq!(state.attributes()[0].value, 15);
+        assert_eq!(
+            sent,
+            [
+                control_change(Channel::C1, 20, 19),
+                control_change(Channel::C1, 21, 64),
+                control_change(Channel::C2, 22, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn program_change_recalls_on_the_program_channel_only() {
+        let mut state = state();
+        let mut sent = Vec::new();
+        state.store_preset(3);
+        state.adjust_selected(30, &mut sent);
+        sent.clear();
+
+        let program = |channel| MidiMessage::ProgramChange(channel, Program::new(3));
+
+        // Ignored until a program channel is configured.
+        assert!(!state.apply_remote(Cable::Control, &program(Channel::C1), &mut sent));
+
+        state.set_program_channel(Some(Channel::C2));
+        assert!(!state.apply_remote(Cable::Control, &program(Channel::C1), &mut sent));
+        assert!(state.apply_remote(Cable::Control, &program(Channel::C2), &mut sent));
+        assert_eq!(state.attributes()[0].value, 15);
+        assert_eq!(sent.len(), 3);
+
+        // Empty slot.
+        let empty = MidiMessage::ProgramChange(Channel::C2, Program::new(0));
+        assert!(!state.apply_remote(Cable::Control, &empty, &mut sent));
+    }
+
+    fn control_change(channel: Channel, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel, Control::new(control), Value7::from(value))
+    }
+
+    #[test]
+    fn remote_control_change_updates_matching_attribute() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        // Scaled onto the attribute range.
+        assert!(state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 21, 102),
+            &mut sent
+        ));
+        assert_eq!(state.attributes()[1].value, 80);
+        assert!(state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 20, 127),
+            &mut sent
+        ));
+        assert_eq!(state.attributes()[0].value, 100);
+
+        // Wrong channel or unbound controller.
+        assert!(!state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C2, 21, 10),
+            &mut sent
+        ));
+        assert!(!state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 30, 10),
+            &mut sent
+        ));
+        assert_eq!(state.attributes()[1].value, 80);
+        // Or another cable.
+        assert!(!state.apply_remote(Cable::Thru, &control_change(Channel::C1, 21, 10), &mut sent));
+
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn remote_parameters_and_pitch_bend_update_attributes() {
+        let mut state = State::new();
+        let nrpn = Parameter::NonRegistered(300);
+        state.set_attributes(Attributes::from_iter([
+            Attribute::new("Cutoff", Channel::C1, Control::new(0))
+                .with_encoding(Encoding::Parameter(nrpn)),
+            Attribute::new("Bend", Channel::C1, Control::new(0)).with_encoding(Encoding::PitchBend),
+        ]));
+        state.set_echo_policy(EchoPolicy::OnChange);
+        let mut sent = Vec::new();
+
+        let mut incoming = Vec::new();
+        incoming.send_parameter(Cable::Control, Channel::C1, nrpn, 1000);
+        let changed: Vec<bool> = incoming
+            .iter()
+            .map(|message| state.apply_remote(Cable::Control, message, &mut sent))
+            .collect();
+        assert_eq!(changed, [false, false, true, true]);
+        assert_eq!(state.attributes()[0].position(), 1000);
+        // Echoed once for the coarse value, once for the fine one.
+        assert_eq!(sent.len(), 8);
+        assert_eq!(sent[4..], incoming[..]);
+
+        let bend = MidiMessage::PitchBendChange(Channel::C1, 8192u16.into());
+        assert!(state.apply_remote(Cable::Control, &bend, &mut sent));
+        assert_eq!(state.attributes()[1].value, 64);
+        // Control 0 isn't bound to either attribute.
+        assert!(!state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 0, 9),
+            &mut sent
+        ));
+    }
+
+    #[test]
+    fn learn_rebinds_the_selected_attribute() {
+        let mut state = state();
+        let mut sent = Vec::new();
+        assert_eq!(state.settings().attributes, None);
+
+        // Click and hold, then move a knob on channel 3.
+        state.gesture(Gesture::ClickAndHold, 0, &mut sent);
+        assert!(state.is_learning());
+        assert!(state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C3, 74, 127),
+            &mut sent
+        ));
+        assert!(!state.is_learning());
+        let delay = &state.attributes()[0];
+        assert_eq!((delay.channel, u8::from(delay.control)), (Channel::C3, 74));
+        assert_eq!(delay.value, 100);
+        assert!(!state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 20, 0),
+            &mut sent
+        ));
+
+        // NRPNs are learned once their Data Entry arrives.
+        state.next_option();
+        state.learn();
+        let nrpn = Parameter::NonRegistered(300);
+        let mut incoming = Vec::new();
+        incoming.send_parameter(Cable::Thru, Channel::C2, nrpn, 1 << 13);
+        for message in &incoming[..2] {
+            state.apply_remote(Cable::Thru, message, &mut sent);
+        }
+        assert!(state.is_learning());
+        for message in &incoming[2..] {
+            state.apply_remote(Cable::Thru, message, &mut sent);
+        }
+        let feedback = &state.attributes()[1];
+        assert_eq!(feedback.encoding, Encoding::Parameter(nrpn));
+        assert_eq!(
+            (feedback.cable, feedback.channel),
+            (Cable::Thru, Channel::C2)
+        );
+        assert_eq!(feedback.value, 50);
+
+        // Bindings are persisted with the table; moving on cancels learning.
+        assert_eq!(state.settings().attributes.unwrap()[1], *feedback);
+        state.learn();
+        state.next_option();
+        assert!(!state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 22, 5),
+            &mut sent
+        ));
+        assert_eq!(u8::from(state.attributes()[2].control), 22);
+    }
+
+    #[test]
+    fn echo_policy_controls_what_is_sent_back() {
+        let mut state = state();
+        let mut sent = Vec::new();
+
+        state.set_echo_policy(EchoPolicy::OnChange);
+        state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 20, 38),
+            &mut sent,
+        );
+        state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 20, 38),
+            &mut sent,
+        );
+        assert_eq!(sent, [control_change(Channel::C1, 20, 38)]);
+
+        sent.clear();
+        state.set_echo_policy(EchoPolicy::Always);
+        state.apply_remote(
+            Cable::Control,
+            &control_change(Channel::C1, 20, 38),
+            &mut sent,
+        );
+        assert_eq!(sent, [control_change(Channel::C1, 20, 38)]);
+    }
+
+    #[test]
+    fn settings_survive_a_restore() {
+        let mut edited = state();
+        let mut sent = Vec::new();
+        edited.next_option();
+        edited.adjust_selected(7, &mut sent);
+
+        let settings = edited.settings();
+        let mut restored = state();
+        restored.restore(&settings);
+
+        assert_eq!(restored.selected_option(), 1);
+        assert_eq!(restored.attributes()[1].value, 57);
+    }
+
+    #[test]
+    fn restore_tolerates_a_changed_table() {
+        let mut state = state();
+        state.restore(&Settings {
+            selected: 9,
+            values: heapless::Vec::from_slice(&[200]).unwrap(),
+            ..Settings::default()
+        });
+
+        assert_eq!(state.selected_option(), 0);
+        assert_eq!(state.attributes()[0].value, 100);
+        assert_eq!(state.attributes()[1].value, 50);
+    }
+
+    #[test]
+    fn empty_table_is_inert() {
+        let mut state = State::new();
+        let mut sent = Vec::new();
+
+        state.next_option();
+        state.adjust_selected(1, &mut sent);
+
+        assert!(state.selected().is_none());
+        assert!(sent.is_empty());
+    }
+}
@@ -0,0 +1,621 @@
+//! Persistent settings on NOR flash.
+//!
+//! The flash region is divided into fixed-size slots. Every save appends a
+//! record to the slot after the newest one, wrapping around the region, so
+//! writes are spread evenly over all sectors. A sector is erased just before
+//! its first slot is reused.
+//!
+//! Slot layout (little endian):
+//!
+//! | offset | size | field                                |
+//! |--------|------|--------------------------------------|
+//! | 0      | 2    | magic, `b"ST"`                       |
+//! | 2      | 1    | payload format version               |
+//! | 3      | 1    | reserved, `0`                        |
+//! | 4      | 4    | sequence number                      |
+//! | 8      | 2    | payload length                       |
+//! | 10     | 2    | reserved, `0`                        |
+//! | 12     | n    | payload                              |
+//! | 12 + n | 4    | CRC-32 (IEEE) of everything before it |
+//!
+//! On load the valid record with the highest sequence number wins. Records
+//! with a bad CRC or a payload that doesn't decode are skipped, so a torn
+//! write falls back to the previous save and an empty or corrupted region
+//! falls back to the defaults.
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+
+use midi_convert::midi_types::Channel;
+
+use crate::attribute::{Attribute, Attributes, MAX_ATTRIBUTES, Name};
+use crate::bytes::{Reader, Writer};
+use crate::preset::{Preset, Presets};
+use crate::routing::Routing;
+use crate::state::EchoPolicy;
+
+/// Size of one record slot. Must divide the flash erase size.
+pub const SLOT_SIZE: usize = 2048;
+
+/// Payload format written by [`Store::save`].
+///
+/// 1. selection, echo policy and attribute values
+/// 2. adds the program channel and presets
+/// 3. adds the attribute table when it was edited over SysEx
+/// 4. adds optional settings to every attribute in the table
+/// 5. adds the fine position of every value
+/// 6. adds the routing matrix
+pub const VERSION: u8 = 6;
+
+const MAGIC: [u8; 2] = *b"ST";
+const HEADER_SIZE: usize = 12;
+const CRC_SIZE: usize = 4;
+const MAX_PAYLOAD: usize = SLOT_SIZE - HEADER_SIZE - CRC_SIZE;
+const NO_CHANNEL: u8 = 0x7F;
+
+/// Everything that survives a power cycle.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    pub selected: u8,
+    pub echo_policy: EchoPolicy,
+    /// Attribute values in table order.
+    pub values: Vec<u8, MAX_ATTRIBUTES>,
+    /// Fine positions of the values, see [`Attribute::fine`].
+    pub fine: Vec<u8, MAX_ATTRIBUTES>,
+    pub program_channel: Option<Channel>,
+    pub presets: Presets,
+    /// The attribute table, if it differs from the compiled-in one.
+    pub attributes: Option<Attributes>,
+    pub routing: Routing,
+}
+
+impl Settings {
+    /// Serialises the settings in the current [`VERSION`] format.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut writer = Writer::new(out);
+
+        writer.push(self.selected)?;
+        writer.push(match self.echo_policy {
+            EchoPolicy::Suppress => 0,
+            EchoPolicy::OnChange => 1,
+            EchoPolicy::Always => 2,
+        })?;
+        writer.push_counted(&self.values)?;
+
+        writer.push(self.program_channel.map_or(NO_CHANNEL, u8::from))?;
+        writer.push(self.presets.iter().flatten().count() as u8)?;
+        for (slot, preset) in self.presets.iter().enumerate() {
+            if let Some(preset) = preset {
+                writer.push(slot as u8)?;
+                writer.push_counted(preset.name.as_bytes())?;
+                writer.push_counted(&preset.values)?;
+            }
+        }
+
+        match &self.attributes {
+            None => writer.push(0)?,
+            Some(attributes) => {
+                writer.push(1)?;
+                writer.push(attributes.len() as u8)?;
+                for attribute in attributes {
+                    attribute.encode(&mut writer)?;
+                }
+            }
+        }
+
+        writer.push_counted(&self.fine)?;
+        self.routing.encode(&mut writer)?;
+
+        Some(writer.len())
+    }
+
+    /// Parses a payload written in format `version`, migrating older formats.
+    pub fn decode(version: u8, payload: &[u8]) -> Option<Self> {
+        if !(1..=VERSION).contains(&version) {
+            return None;
+        }
+
+        let mut reader = Reader::new(payload);
+        let mut settings = Self {
+            selected: reader.byte()?,
+            echo_policy: match reader.byte()? {
+                0 => EchoPolicy::Suppress,
+                1 => EchoPolicy::OnChange,
+                2 => EchoPolicy::Always,
+                _ => return None,
+            },
+            values: Vec::from_slice(reader.counted()?).ok()?,
+            ..Self::default()
+        };
+
+        // Version 1 records predate presets; they start out empty.
+        if version >= 2 {
+            settings.program_channel = match reader.byte()? {
+                NO_CHANNEL => None,
+                channel @ 0..16 => Some(Channel::new(channel)),
+                _ => return None,
+            };
+            for _ in 0..reader.byte()? {
+                let slot = settings.presets.get_mut(reader.byte()? as usize)?;
+                *slot = Some(Preset {
+                    name: Name::try_from(reader.str()?).ok()?,
+                    values: Vec::from_slice(reader.counted()?).ok()?,
+                });
+            }
+        }
+
+        if version >= 3 && reader.byte()? == 1 {
+            let mut attributes = Attributes::new();
+            for _ in 0..reader.byte()? {
+                let attribute = if version >= 4 {
+                    Attribute::decode(&mut reader)?
+                } else {
+                    Attribute::decode_fixed(&mut reader)?
+                };
+                attributes.push(attribute).ok()?;
+            }
+            settings.attributes = Some(attributes);
+        }
+
+        if version >= 5 {
+            settings.fine = Vec::from_slice(reader.counted()?).ok()?;
+        }
+
+        // Older records keep the default routing.
+        if version >= 6 {
+            settings.routing = Routing::decode(&mut reader)?;
+        }
+
+        reader.is_empty().then_some(settings)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The encoded settings don't fit in a slot.
+    TooLarge,
+}
+
+/// Wear-levelled record log on a flash region.
+pub struct Store<F> {
+    flash: F,
+    slots: u32,
+    next_slot: u32,
+    sequence: u32,
+    last_crc: Option<u32>,
+    buffer: [u8; SLOT_SIZE],
+}
+
+impl<F: NorFlash> Store<F> {
+    /// Wraps a flash region, which must span at least two erase sectors.
+    pub fn new(flash: F) -> Self {
+        debug_assert!(
+            F::ERASE_SIZE.is_multiple_of(SLOT_SIZE) && SLOT_SIZE.is_multiple_of(F::WRITE_SIZE)
+        );
+        debug_assert!(flash.capacity() >= 2 * F::ERASE_SIZE);
+
+        Self {
+            slots: (flash.capacity() / SLOT_SIZE) as u32,
+            flash,
+            next_slot: 0,
+            sequence: 0,
+            last_crc: None,
+            buffer: [0; SLOT_SIZE],
+        }
+    }
+
+    /// Returns the newest valid settings, or `None` if there are none.
+    pub fn load(&mut self) -> Option<Settings> {
+        let mut newest: Option<(u32, u32, Settings)> = None;
+
+        for slot in 0..self.slots {
+            let Some((sequence, crc, settings)) = self.read_slot(slot) else {
+                continue;
+            };
+            if newest
+                .as_ref()
+                .is_none_or(|(newest_sequence, ..)| sequence > *newest_sequence)
+            {
+                newest = Some((sequence, crc, settings));
+                self.next_slot = (slot + 1) % self.slots;
+            }
+        }
+
+        let (sequence, crc, settings) = newest?;
+        self.sequence = sequence.wrapping_add(1);
+        self.last_crc = Some(crc);
+        Some(settings)
+    }
+
+    /// Appends `settings` to the log, unless they equal the newest record.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error<F::Error>> {
+        let payload_len = settings
+            .encode(&mut self.buffer[HEADER_SIZE..HEADER_SIZE + MAX_PAYLOAD])
+            .ok_or(Error::TooLarge)?;
+
+        self.buffer[..2].copy_from_slice(&MAGIC);
+        self.buffer[2] = VERSION;
+        self.buffer[3] = 0;
+        self.buffer[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        self.buffer[8..10].copy_from_slice(&(payload_len as u16).to_le_bytes());
+        self.buffer[10..12].fill(0);
+
+        let crc_offset = HEADER_SIZE + payload_len;
+        let crc = crc32(&self.buffer[..crc_offset]);
+        // The sequence number differs between records, so compare payloads only.
+        let payload_crc = crc32(&self.buffer[HEADER_SIZE..crc_offset]);
+        if self.last_crc == Some(payload_crc) {
+            return Ok(());
+        }
+        self.buffer[crc_offset..crc_offset + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        let len = (crc_offset + CRC_SIZE).next_multiple_of(F::WRITE_SIZE);
+        self.buffer[crc_offset + CRC_SIZE..len].fill(0xFF);
+
+        let slot = self.prepare_slot().map_err(Error::Flash)?;
+        self.flash
+            .write(slot * SLOT_SIZE as u32, &self.buffer[..len])
+            .map_err(Error::Flash)?;
+
+        self.next_slot = (slot + 1) % self.slots;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.last_crc = Some(payload_crc);
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Finds an erased slot to write to, erasing the next sector if needed.
+    fn prepare_slot(&mut self) -> Result<u32, F::Error> {
+        let slots_per_sector = (F::ERASE_SIZE / SLOT_SIZE) as u32;
+        let mut slot = self.next_slot;
+
+        // A torn write can leave the slot after the newest record dirty; skip
+        // ahead to the next sector rather than erasing the newest record.
+        if !slot.is_multiple_of(slots_per_sector) && !self.slot_is_erased(slot)? {
+            slot = (slot / slots_per_sector + 1) * slots_per_sector % self.slots;
+        }
+
+        if slot.is_multiple_of(slots_per_sector) {
+            let from = slot * SLOT_SIZE as u32;
+            self.flash.erase(from, from + F::ERASE_SIZE as u32)?;
+        }
+
+        Ok(slot)
+    }
+
+    /// Checks a slot without touching `buffer`, which holds the pending record.
+    fn slot_is_erased(&mut self, slot: u32) -> Result<bool, F::Error> {
+        let mut chunk = [0; 32];
+        let start = slot * SLOT_SIZE as u32;
+        for offset in (start..start + SLOT_SIZE as u32).step_by(chunk.len()) {
+            self.flash.read(offset, &mut chunk)?;
+            if chunk.iter().any(|&b| b != 0xFF) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_slot(&mut self, slot: u32) -> Option<(u32, u32, Settings)> {
+        let offset = slot * SLOT_SIZE as u32;
+        self.flash.read(offset, &mut self.buffer).ok()?;
+
+        if self.buffer[..2] != MAGIC {
+            return None;
+        }
+        let version = self.buffer[2];
+        let sequence = u32::from_le_bytes(self.buffer[4..8].try_into().ok()?);
+        let payload_len = u16::from_le_bytes(self.buffer[8..10].try_into().ok()?) as usize;
+        if payload_len > MAX_PAYLOAD {
+            return None;
+        }
+
+        let crc_offset = HEADER_SIZE + payload_len;
+        let stored_crc = u32::from_le_bytes(
+            self.buffer[crc_offset..crc_offset + CRC_SIZE]
+                .try_into()
+                .ok()?,
+        );
+        if crc32(&self.buffer[..crc_offset]) != stored_crc {
+            return None;
+        }
+
+        let payload = &self.buffer[HEADER_SIZE..crc_offset];
+        let settings = Settings::decode(version, payload)?;
+        Some((sequence, crc32(payload), settings))
+    }
+}
+
+/// CRC-32 as used by zlib and Ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+    use midi_convert::midi_types::Control;
+
+    use super::*;
+    use crate::attribute::Format;
+    use crate::encoder::Acceleration;
+    use crate::routing::{Destination, Route, Source};
+
+    const SECTOR: usize = 4096;
+
+    /// NOR flash stand-in: erase sets bytes to `0xFF`, writes can only clear bits.
+    struct MemFlash {
+        data: std::vec::Vec<u8>,
+        writes: usize,
+    }
+
+    impl MemFlash {
+        fn new(sectors: usize) -> Self {
+            Self {
+                data: vec![0xFF; sectors * SECTOR],
+                writes: 0,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct OutOfBounds;
+
+    impl NorFlashError for OutOfBounds {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::OutOfBounds
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = OutOfBounds;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let src = self
+                .data
+                .get(offset..offset + bytes.len())
+                .ok_or(OutOfBounds)?;
+            bytes.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let range = self
+                .data
+                .get_mut(from as usize..to as usize)
+                .ok_or(OutOfBounds)?;
+            range.fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let dst = self
+                .data
+                .get_mut(offset..offset + bytes.len())
+                .ok_or(OutOfBounds)?;
+            for (d, s) in dst.iter_mut().zip(bytes) {
+                *d &= s;
+            }
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    fn settings(value: u8) -> Settings {
+        let mut settings = Settings {
+            selected: 1,
+            echo_policy: EchoPolicy::OnChange,
+            values: Vec::from_slice(&[value, 50, 7]).unwrap(),
+            fine: Vec::from_slice(&[0, 64, 0]).unwrap(),
+            program_channel: Some(Channel::C2),
+            ..Settings::default()
+        };
+        settings.presets[3] = Some(Preset {
+            name: Name::try_from("Dub").unwrap(),
+            values: Vec::from_slice(&[1, 2, 3]).unwrap(),
+        });
+        settings
+            .routing
+            .set_route(Source::Din, Destination::Din, Route::OPEN);
+        settings
+    }
+
+    #[test]
+    fn crc32_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut buf = [0; 64];
+        let len = settings(15).encode(&mut buf).unwrap();
+        assert_eq!(Settings::decode(VERSION, &buf[..len]), Some(settings(15)));
+    }
+
+    #[test]
+    fn edited_tables_round_trip() {
+        let mut settings = settings(15);
+        settings.attributes = Some(Attributes::from_iter([
+            Attribute::new("Delay", Channel::C1, Control::new(20)).with_default(15),
+            Attribute::new("Cutoff", Channel::C2, Control::new(74)).with_default(64),
+        ]));
+
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = settings.encode(&mut buf).unwrap();
+        assert_eq!(Settings::decode(VERSION, &buf[..len]), Some(settings));
+    }
+
+    #[test]
+    fn largest_settings_fit_in_a_slot() {
+        let mut settings = settings(0);
+        let attribute = Attribute::new("Twelve chars", Channel::C1, Control::new(1))
+            .with_format(Format::Milliseconds(1000));
+        settings.values = Vec::from_slice(&[0; MAX_ATTRIBUTES]).unwrap();
+        settings.fine = Vec::from_slice(&[0; MAX_ATTRIBUTES]).unwrap();
+        settings.attributes = Some(Attributes::from_iter(core::iter::repeat_n(
+            attribute,
+            MAX_ATTRIBUTES,
+        )));
+        settings.presets = core::array::from_fn(|_| {
+            Some(Preset {
+                name: Name::try_from("Twelve chars").unwrap(),
+                values: Vec::from_slice(&[0; MAX_ATTRIBUTES]).unwrap(),
+            })
+        });
+
+        let mut store = Store::new(MemFlash::new(2));
+        assert!(store.save(&settings).is_ok());
+    }
+
+    #[test]
+    fn version_1_records_migrate_without_presets() {
+        let settings = Settings::decode(1, &[1, 2, 2, 15, 50]).unwrap();
+
+        assert_eq!(settings.selected, 1);
+        assert_eq!(settings.echo_policy, EchoPolicy::Always);
+        assert_eq!(settings.values, [15, 50]);
+        assert!(settings.fine.is_empty());
+        assert_eq!(settings.program_channel, None);
+        assert!(settings.presets.iter().all(Option::is_none));
+        assert_eq!(settings.routing, Routing::new());
+    }
+
+    #[test]
+    fn version_3_tables_migrate_with_default_settings() {
+        let payload = [
+            0, 0, 1, 15, 0x7F, 0, // selection, echo, values, program channel, presets
+            1, 1, // one attribute, without optional settings
+            5, b'D', b'e', b'l', b'a', b'y', 0, 20, 0, 100, 15, 15, 0, 0, 0, 0,
+        ];
+        let settings = Settings::decode(3, &payload).unwrap();
+
+        let attributes = settings.attributes.unwrap();
+        assert_eq!(
+            attributes[0],
+            Attribute::new("Delay", Channel::C1, Control::new(20))
+                .with_range(0, 100)
+                .with_default(15)
+        );
+        assert_eq!(attributes[0].acceleration, Acceleration::default());
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        assert_eq!(Settings::decode(1, &[0, 0]), None);
+        assert_eq!(Settings::decode(1, &[0, 9, 0]), None);
+        assert_eq!(Settings::decode(1, &[0, 0, 3, 1, 2]), None);
+        assert_eq!(Settings::decode(1, &[0, 0, 0, 0xAA]), None);
+        // Channel out of range, preset slot out of range.
+        assert_eq!(Settings::decode(2, &[0, 0, 0, 16, 0]), None);
+        assert_eq!(Settings::decode(2, &[0, 0, 0, 0, 1, 8, 0, 0]), None);
+        // Table flag without a table.
+        assert_eq!(Settings::decode(3, &[0, 0, 0, 0x7F, 0, 1]), None);
+        assert_eq!(Settings::decode(VERSION + 1, &[0, 0, 0]), None);
+        assert_eq!(Settings::decode(0, &[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn empty_flash_loads_nothing() {
+        let mut store = Store::new(MemFlash::new(2));
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn newest_record_wins_across_reboots() {
+        let mut store = Store::new(MemFlash::new(2));
+        for value in 0..20 {
+            store.save(&settings(value)).unwrap();
+        }
+
+        let mut store = Store::new(store.into_inner());
+        assert_eq!(store.load(), Some(settings(19)));
+
+        store.save(&settings(42)).unwrap();
+        let mut store = Store::new(store.into_inner());
+        assert_eq!(store.load(), Some(settings(42)));
+    }
+
+    #[test]
+    fn writes_are_spread_over_all_slots() {
+        let slots = 2 * SECTOR / SLOT_SIZE;
+        let mut store = Store::new(MemFlash::new(2));
+        for value in 0..slots {
+            store.save(&settings(value as u8)).unwrap();
+        }
+
+        let flash = store.into_inner();
+        for slot in 0..slots {
+            assert_eq!(&flash.data[slot * SLOT_SIZE..slot * SLOT_SIZE + 2], b"ST");
+        }
+    }
+
+    #[test]
+    fn unchanged_settings_are_not_rewritten() {
+        let mut store = Store::new(MemFlash::new(2));
+        store.save(&settings(1)).unwrap();
+        store.save(&settings(1)).unwrap();
+        assert_eq!(store.into_inner().writes, 1);
+    }
+
+    #[test]
+    fn corrupted_record_falls_back_to_previous() {
+        let mut store = Store::new(MemFlash::new(2));
+        store.save(&settings(1)).unwrap();
+        store.save(&settings(2)).unwrap();
+
+        let mut flash = store.into_inner();
+        flash.data[SLOT_SIZE + HEADER_SIZE] ^= 0x01;
+
+        let mut store = Store::new(flash);
+        assert_eq!(store.load(), Some(settings(1)));
+
+        // The damaged slot is skipped and the next save becomes the newest.
+        store.save(&settings(3)).unwrap();
+        let mut store = Store::new(store.into_inner());
+        assert_eq!(store.load(), Some(settings(3)));
+    }
+
+    #[test]
+    fn torn_write_is_skipped() {
+        let mut store = Store::new(MemFlash::new(2));
+        store.save(&settings(1)).unwrap();
+
+        // Power lost halfway through writing the second slot.
+        let mut flash = store.into_inner();
+        flash.data[SLOT_SIZE..SLOT_SIZE + 8].copy_from_slice(b"ST\x01\x00\x01\x00\x00\x00");
+
+        let mut store = Store::new(flash);
+        assert_eq!(store.load(), Some(settings(1)));
+        store.save(&settings(2)).unwrap();
+
+        let mut store = Store::new(store.into_inner());
+        assert_eq!(store.load(), Some(settings(2)));
+    }
+}
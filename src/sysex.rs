@@ -0,0 +1,853 @@
+//! SysEx configuration protocol.
+//!
+//! Besides the Universal Identity Request, the device answers requests in a
+//! manufacturer-specific format using the non-commercial ID `0x7D`:
+//!
+//! ```text
+//! F0 7D 53 54 <version> <command> <payload ...> <checksum> F7
+//! ```
+//!
+//! * `53 54` (`"ST"`) tags the device family, so other `0x7D` devices are ignored.
+//! * `<version>` is [`PROTOCOL_VERSION`]. Requests with another version are
+//!   answered with [`ErrorCode::UnsupportedVersion`].
+//! * `<payload>` is 8-bit data packed into 7-bit bytes: every group of up to
+//!   seven bytes is sent as one byte holding their high bits (bit 0 for the
+//!   first byte) followed by the low seven bits of each byte.
+//! * `<checksum>` makes the sum of `<command>`, the packed payload and itself
+//!   a multiple of 128.
+//!
+//! Every request gets exactly one response. Multi-byte numbers are little
+//! endian; strings are a length byte followed by UTF-8. Requests that are too
+//! long for the device or cut short by another status byte are answered with
+//! [`ErrorCode::TooLarge`] or [`ErrorCode::Incomplete`], see [`error_response`].
+//!
+//! | request            | code | payload                    | response    |
+//! |--------------------|------|----------------------------|-------------|
+//! | get info           | 0x01 | -                          | info        |
+//! | get attribute      | 0x02 | index                      | attribute   |
+//! | set attribute      | 0x03 | index, attribute           | ack         |
+//! | get value          | 0x04 | index                      | value       |
+//! | set value          | 0x05 | index, value               | ack         |
+//! | get preset         | 0x06 | slot                       | preset      |
+//! | set preset         | 0x07 | slot, preset               | ack         |
+//! | get route          | 0x08 | source, destination        | route       |
+//! | set route          | 0x09 | source, destination, route | ack         |
+//!
+//! | response  | code | payload                                                 |
+//! |-----------|------|---------------------------------------------------------|
+//! | info      | 0x41 | protocol, firmware major/minor/patch, attribute count, max attributes, preset slots |
+//! | attribute | 0x42 | index, attribute                                        |
+//! | value     | 0x44 | index, value                                            |
+//! | preset    | 0x46 | slot, preset                                            |
+//! | route     | 0x48 | source, destination, route                              |
+//! | ack       | 0x70 | -                                                       |
+//! | error     | 0x7F | [`ErrorCode`]                                           |
+//!
+//! An attribute is its name, channel (0-15), controller, minimum, maximum,
+//! default and current value, format (0 raw, 1 milliseconds, 2 percent)
+//! followed by its 16-bit argument, and visual (0 bar, 1 ripple, 2 waves).
+//! Then comes a count of optional settings, each a tag and a length-prefixed
+//! payload; unknown tags are skipped and missing ones take their defaults:
+//!
+//! | tag | setting      | payload                                                  |
+//! |-----|--------------|----------------------------------------------------------|
+//! | 1   | acceleration | threshold and full speed in detents per second, max step |
+//! | 2   | encoding     | see below                                                |
+//! | 3   | step         | values moved per detent, at least 1                      |
+//! | 4   | curve        | 0 linear, 1 logarithmic, 2 exponential, 3 stepped        |
+//! | 5   | kind         | 0 continuous, 1 toggle, 2 bipolar, 3 enumerated, 4 tempo |
+//! | 6   | tempo sync   | 1 to pick note divisions of the MIDI clock's tempo       |
+//! | 7   | cable        | USB cable: 0 control, 1 clock, 2 thru                    |
+//!
+//! The encoding is one of 0 for a 7-bit Control Change, 1 for a 14-bit
+//! Control Change pair, 2 or 3 followed by a 16-bit number for an NRPN or
+//! RPN, 4 for pitch bend and 5 for channel pressure. Values are scaled from
+//! the minimum and maximum onto the full range of the encoding through the
+//! curve; a stepped curve is followed by its number of levels, at least 2.
+//! An enumerated kind is followed by a count of 2 to 6 choices, each the value
+//! sent for it and a length-prefixed label; its range has to be `0` to the
+//! last choice, `0..=1` for a toggle and `30..=250` BPM for the tempo.
+//!
+//! Setting the attribute one past the end of the table appends it.
+//!
+//! A preset is a byte that is 0 for an empty slot, or 1 followed by the name
+//! and a length-prefixed list of values in table order.
+//!
+//! Routes are addressed by source (0 local, 1 USB thru, 2 DIN) and
+//! destination (0 USB, 1 DIN). A route is a byte of flags, bit 0 for enabled
+//! and bits 1 to 3 to drop the clock, SysEx and active sensing, followed by
+//! the 16-bit mask of channels passed, bit 0 for channel 1.
+
+use heapless::Vec;
+
+use crate::attribute::{Attribute, MAX_ATTRIBUTES, Name};
+use crate::bytes::{Reader, Writer};
+use crate::io::MidiSink;
+use crate::preset::{PRESET_COUNT, Preset};
+use crate::reassembler::Dropped;
+use crate::routing::{Destination, Route, Source};
+use crate::state::State;
+
+/// Longest SysEx message, including `F0` and `F7`.
+pub const SYSEX_BUFFER_SIZE: usize = 128;
+
+/// Version byte sent in and expected from every message.
+///
+/// 1. initial protocol
+/// 2. attributes carry optional settings
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Firmware version reported by [`Response::Info`].
+pub const FIRMWARE_VERSION: [u8; 3] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+pub const MANUFACTURER_ID: u8 = 0x7D;
+const DEVICE_TAG: [u8; 2] = *b"ST";
+const HEADER: [u8; 5] = [
+    0xF0,
+    MANUFACTURER_ID,
+    DEVICE_TAG[0],
+    DEVICE_TAG[1],
+    PROTOCOL_VERSION,
+];
+
+/// Largest unpacked payload that still fits a message once packed.
+const MAX_PAYLOAD: usize = (SYSEX_BUFFER_SIZE - HEADER.len() - 3) / 8 * 7;
+
+pub type Message = Vec<u8, SYSEX_BUFFER_SIZE>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    GetInfo,
+    GetAttribute(u8),
+    SetAttribute(u8, Attribute),
+    GetValue(u8),
+    SetValue(u8, u8),
+    GetPreset(u8),
+    SetPreset(u8, Option<Preset>),
+    GetRoute(u8, u8),
+    SetRoute(u8, u8, Route),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Info(Info),
+    Attribute(u8, Attribute),
+    Value(u8, u8),
+    Preset(u8, Option<Preset>),
+    Route(u8, u8, Route),
+    Ack,
+    Error(ErrorCode),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    pub protocol: u8,
+    pub firmware: [u8; 3],
+    pub attributes: u8,
+    pub max_attributes: u8,
+    pub presets: u8,
+}
+
+/// Reasons a request was refused, sent back in [`Response::Error`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnsupportedVersion = 1,
+    UnknownCommand = 2,
+    Malformed = 3,
+    Checksum = 4,
+    OutOfRange = 5,
+    TooLarge = 6,
+    Incomplete = 7,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => Self::UnsupportedVersion,
+            2 => Self::UnknownCommand,
+            3 => Self::Malformed,
+            4 => Self::Checksum,
+            5 => Self::OutOfRange,
+            6 => Self::TooLarge,
+            7 => Self::Incomplete,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Not a message in this protocol.
+    NotForUs,
+    UnsupportedVersion(u8),
+    UnknownCommand(u8),
+    /// Bad framing, 7-bit violations or an invalid payload.
+    Malformed,
+    Checksum,
+    /// The message doesn't fit in [`SYSEX_BUFFER_SIZE`].
+    TooLarge,
+}
+
+impl From<Error> for ErrorCode {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::UnsupportedVersion(_) => Self::UnsupportedVersion,
+            Error::UnknownCommand(_) => Self::UnknownCommand,
+            Error::Checksum => Self::Checksum,
+            Error::TooLarge => Self::TooLarge,
+            Error::NotForUs | Error::Malformed => Self::Malformed,
+        }
+    }
+}
+
+impl From<Dropped> for ErrorCode {
+    fn from(dropped: Dropped) -> Self {
+        match dropped {
+            Dropped::TooLarge => Self::TooLarge,
+            Dropped::Interrupted => Self::Incomplete,
+        }
+    }
+}
+
+impl Request {
+    pub fn encode(&self) -> Result<Message, Error> {
+        let mut payload = [0; MAX_PAYLOAD];
+        let mut writer = Writer::new(&mut payload);
+        let command = match self {
+            Self::GetInfo => 0x01,
+            Self::GetAttribute(index) => {
+                writer.push(*index);
+                0x02
+            }
+            Self::SetAttribute(index, attribute) => {
+                writer.push(*index);
+                attribute.encode(&mut writer).ok_or(Error::TooLarge)?;
+                0x03
+            }
+            Self::GetValue(index) => {
+                writer.push(*index);
+                0x04
+            }
+            Self::SetValue(index, value) => {
+                writer.extend(&[*index, *value]);
+                0x05
+            }
+            Self::GetPreset(slot) => {
+                writer.push(*slot);
+                0x06
+            }
+            Self::SetPreset(slot, preset) => {
+                writer.push(*slot);
+                encode_preset(&mut writer, preset.as_ref()).ok_or(Error::TooLarge)?;
+                0x07
+            }
+            Self::GetRoute(source, destination) => {
+                writer.extend(&[*source, *destination]);
+                0x08
+            }
+            Self::SetRoute(source, destination, route) => {
+                writer.extend(&[*source, *destination]);
+                route.encode(&mut writer).ok_or(Error::TooLarge)?;
+                0x09
+            }
+        };
+        let len = writer.len();
+        frame(command, &payload[..len])
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self, Error> {
+        let (command, payload) = unframe(message)?;
+        let mut reader = Reader::new(&payload);
+        let r = &mut reader;
+        let request = match command {
+            0x01 => Self::GetInfo,
+            0x02 => Self::GetAttribute(byte(r)?),
+            0x03 => Self::SetAttribute(byte(r)?, Attribute::decode(r).ok_or(Error::Malformed)?),
+            0x04 => Self::GetValue(byte(r)?),
+            0x05 => Self::SetValue(byte(r)?, byte(r)?),
+            0x06 => Self::GetPreset(byte(r)?),
+            0x07 => Self::SetPreset(byte(r)?, decode_preset(r).ok_or(Error::Malformed)?),
+            0x08 => Self::GetRoute(byte(r)?, byte(r)?),
+            0x09 => Self::SetRoute(byte(r)?, byte(r)?, route(r)?),
+            command => return Err(Error::UnknownCommand(command)),
+        };
+        finish(reader, request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Result<Message, Error> {
+        let mut payload = [0; MAX_PAYLOAD];
+        let mut writer = Writer::new(&mut payload);
+        let command = match self {
+            Self::Info(info) => {
+                writer.push(info.protocol);
+                writer.extend(&info.firmware);
+                writer.extend(&[info.attributes, info.max_attributes, info.presets]);
+                0x41
+            }
+            Self::Attribute(index, attribute) => {
+                writer.push(*index);
+                attribute.encode(&mut writer).ok_or(Error::TooLarge)?;
+                0x42
+            }
+            Self::Value(index, value) => {
+                writer.extend(&[*index, *value]);
+                0x44
+            }
+            Self::Preset(slot, preset) => {
+                writer.push(*slot);
+                encode_preset(&mut writer, preset.as_ref()).ok_or(Error::TooLarge)?;
+                0x46
+            }
+            Self::Route(source, destination, route) => {
+                writer.extend(&[*source, *destination]);
+                route.encode(&mut writer).ok_or(Error::TooLarge)?;
+                0x48
+            }
+            Self::Ack => 0x70,
+            Self::Error(code) => {
+                writer.push(*code as u8);
+                0x7F
+            }
+        };
+        let len = writer.len();
+        frame(command, &payload[..len])
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self, Error> {
+        let (command, payload) = unframe(message)?;
+        let mut reader = Reader::new(&payload);
+        let r = &mut reader;
+        let response = match command {
+            0x41 => Self::Info(Info {
+                protocol: byte(r)?,
+                firmware: [byte(r)?, byte(r)?, byte(r)?],
+                attributes: byte(r)?,
+                max_attributes: byte(r)?,
+                presets: byte(r)?,
+            }),
+            0x42 => Self::Attribute(byte(r)?, Attribute::decode(r).ok_or(Error::Malformed)?),
+            0x44 => Self::Value(byte(r)?, byte(r)?),
+            0x46 => Self::Preset(byte(r)?, decode_preset(r).ok_or(Error::Malformed)?),
+            0x48 => Self::Route(byte(r)?, byte(r)?, route(r)?),
+            0x70 => Self::Ack,
+            0x7F => Self::Error(ErrorCode::from_u8(byte(r)?).ok_or(Error::Malformed)?),
+            command => return Err(Error::UnknownCommand(command)),
+        };
+        finish(reader, response)
+    }
+}
+
+fn byte(reader: &mut Reader) -> Result<u8, Error> {
+    reader.byte().ok_or(Error::Malformed)
+}
+
+fn route(reader: &mut Reader) -> Result<Route, Error> {
+    Route::decode(reader).ok_or(Error::Malformed)
+}
+
+/// Rejects trailing bytes after a decoded payload.
+fn finish<T>(reader: Reader, decoded: T) -> Result<T, Error> {
+    if reader.is_empty() {
+        Ok(decoded)
+    } else {
+        Err(Error::Malformed)
+    }
+}
+
+fn encode_preset(writer: &mut Writer, preset: Option<&Preset>) -> Option<()> {
+    match preset {
+        None => writer.push(0),
+        Some(preset) => {
+            writer.push(1)?;
+            writer.push_counted(preset.name.as_bytes())?;
+            writer.push_counted(&preset.values)
+        }
+    }
+}
+
+fn decode_preset(reader: &mut Reader) -> Option<Option<Preset>> {
+    match reader.byte()? {
+        0 => Some(None),
+        1 => Some(Some(Preset {
+            name: Name::try_from(reader.str()?).ok()?,
+            values: Vec::from_slice(reader.counted()?).ok()?,
+        })),
+        _ => None,
+    }
+}
+
+fn frame(command: u8, payload: &[u8]) -> Result<Message, Error> {
+    let mut message = Message::new();
+    message
+        .extend_from_slice(&HEADER)
+        .map_err(|_| Error::TooLarge)?;
+    message.push(command).map_err(|_| Error::TooLarge)?;
+    pack(payload, &mut message).ok_or(Error::TooLarge)?;
+
+    let checksum = checksum(&message[HEADER.len()..]);
+    message.push(checksum).map_err(|_| Error::TooLarge)?;
+    message.push(0xF7).map_err(|_| Error::TooLarge)?;
+    Ok(message)
+}
+
+/// Checks the framing and returns the command and the unpacked payload.
+fn unframe(message: &[u8]) -> Result<(u8, Vec<u8, MAX_PAYLOAD>), Error> {
+    let Some(body) = message
+        .strip_prefix(&HEADER[..HEADER.len() - 1])
+        .and_then(|rest| rest.strip_suffix(&[0xF7]))
+    else {
+        return Err(Error::NotForUs);
+    };
+    if message.len() > SYSEX_BUFFER_SIZE {
+        return Err(Error::TooLarge);
+    }
+
+    let (&version, body) = body.split_first().ok_or(Error::Malformed)?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if body.len() < 2 || body.iter().any(|&b| b > 0x7F) {
+        return Err(Error::Malformed);
+    }
+    if checksum(body) != 0 {
+        return Err(Error::Checksum);
+    }
+
+    let (&command, packed) = body.split_first().ok_or(Error::Malformed)?;
+    let packed = &packed[..packed.len() - 1];
+    let mut payload = Vec::new();
+    unpack(packed, &mut payload).ok_or(Error::Malformed)?;
+    Ok((command, payload))
+}
+
+/// Value that makes the 7-bit sum of `bytes` and itself zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    sum.wrapping_neg() & 0x7F
+}
+
+/// Packs 8-bit `data` into 7-bit bytes, see the module documentation.
+pub fn pack<const N: usize>(data: &[u8], out: &mut Vec<u8, N>) -> Option<()> {
+    for group in data.chunks(7) {
+        let high_bits = group
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &b)| bits | ((b >> 7) << i));
+        out.push(high_bits).ok()?;
+        for &b in group {
+            out.push(b & 0x7F).ok()?;
+        }
+    }
+    Some(())
+}
+
+/// Reverses [`pack`]. Fails on bytes with the top bit set or a dangling group header.
+pub fn unpack<const N: usize>(packed: &[u8], out: &mut Vec<u8, N>) -> Option<()> {
+    for group in packed.chunks(8) {
+        let (&high_bits, low) = group.split_first()?;
+        if low.is_empty() || group.iter().any(|&b| b > 0x7F) {
+            return None;
+        }
+        if high_bits >> low.len() != 0 {
+            return None;
+        }
+        for (i, &b) in low.iter().enumerate() {
+            out.push(b | (((high_bits >> i) & 1) << 7)).ok()?;
+        }
+    }
+    Some(())
+}
+
+const fn parse_version(digits: &str) -> u8 {
+    let digits = digits.as_bytes();
+    let mut value = 0u8;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+/// Answers a complete SysEx message, returning the response to send back.
+///
+/// Returns `None` for messages that aren't meant for this device.
+pub fn process_sysex(
+    request: &[u8],
+    state: &mut State,
+    sink: &mut impl MidiSink,
+) -> Option<Message> {
+    /// Identity request message.
+    ///
+    /// See section *DEVICE INQUIRY* of the *MIDI 1.0 Detailed Specification* for further details.
+    const IDENTITY_REQUEST: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+
+    if request == IDENTITY_REQUEST {
+        let mut response = Message::new();
+        response
+            .extend_from_slice(&[
+                0xF0, 0x7E, 0x7F, 0x06, 0x02, // Header
+                0x01, // Manufacturer ID
+                0x02, // Family code
+                0x03, // Family code
+                0x04, // Family member code
+                0x05, // Family member code
+                0x00, // Software revision level
+                0x00, // Software revision level
+                0x00, // Software revision level
+                0x00, // Software revision level
+                0xF7,
+            ])
+            .ok();
+
+        return Some(response);
+    }
+
+    let response = match Request::decode(request) {
+        Ok(request) => handle(request, state, sink),
+        Err(Error::NotForUs) => return None,
+        Err(error) => Response::Error(error.into()),
+    };
+
+    response.encode().ok()
+}
+
+/// Error response for a message that couldn't be received whole.
+///
+/// `start` is whatever arrived of it; `None` unless that shows it was meant
+/// for this device.
+pub fn error_response(start: &[u8], code: ErrorCode) -> Option<Message> {
+    if !start.starts_with(&HEADER[..HEADER.len() - 1]) {
+        return None;
+    }
+    Response::Error(code).encode().ok()
+}
+
+fn handle(request: Request, state: &mut State, sink: &mut impl MidiSink) -> Response {
+    let out_of_range = Response::Error(ErrorCode::OutOfRange);
+    let ack = |ok: bool| {
+        if ok {
+            Response::Ack
+        } else {
+            out_of_range.clone()
+        }
+    };
+
+    match request {
+        Request::GetInfo => Response::Info(Info {
+            protocol: PROTOCOL_VERSION,
+            firmware: FIRMWARE_VERSION,
+            attributes: state.attributes().len() as u8,
+            max_attributes: MAX_ATTRIBUTES as u8,
+            presets: PRESET_COUNT as u8,
+        }),
+        Request::GetAttribute(index) => match state.attributes().get(index as usize) {
+            Some(attribute) => Response::Attribute(index, attribute.clone()),
+            None => out_of_range,
+        },
+        Request::SetAttribute(index, attribute) => {
+            ack(state.set_attribute(index as usize, attribute))
+        }
+        Request::GetValue(index) => match state.attributes().get(index as usize) {
+            Some(attribute) => Response::Value(index, attribute.value),
+            None => out_of_range,
+        },
+        Request::SetValue(index, value) => ack(state.set_value(index as usize, value, sink)),
+        Request::GetPreset(slot) => match state.presets().get(slot as usize) {
+            Some(preset) => Response::Preset(slot, preset.clone()),
+            None => out_of_range,
+        },
+        Request::SetPreset(slot, preset) => ack(state.set_preset(slot as usize, preset)),
+        Request::GetRoute(source, destination) => {
+            match (
+                Source::from_number(source),
+                Destination::from_number(destination),
+            ) {
+                (Some(from), Some(to)) => {
+                    Response::Route(source, destination, *state.routing().route(from, to))
+                }
+                _ => out_of_range,
+            }
+        }
+        Request::SetRoute(source, destination, route) => {
+            match (
+                Source::from_number(source),
+                Destination::from_number(destination),
+            ) {
+                (Some(from), Some(to)) => {
+                    state.set_route(from, to, route);
+                    Response::Ack
+                }
+                _ => out_of_range,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Channel, Control, MidiMessage, Value7};
+
+    use super::*;
+    use crate::attribute::{Attributes, Encoding, Format, Kind, MAX_CHOICES, Visual};
+    use crate::parameter::Parameter;
+    use crate::value::Curve;
+
+    fn state() -> State {
+        let mut state = State::new();
+        state.set_attributes(Attributes::from_iter([
+            Attribute::new("Delay", Channel::C1, Control::new(20))
+                .with_range(0, 100)
+                .with_default(15)
+                .with_format(Format::Milliseconds(1000))
+                .with_visual(Visual::Ripple),
+            Attribute::new("Feedback", Channel::C1, Control::new(21))
+                .with_range(0, 100)
+                .with_default(50),
+        ]));
+        state
+    }
+
+    fn exchange(state: &mut State, request: Request) -> (Response, std::vec::Vec<MidiMessage>) {
+        let mut sent = std::vec::Vec::new();
+        let response = process_sysex(&request.encode().unwrap(), state, &mut sent).unwrap();
+        (Response::decode(&response).unwrap(), sent)
+    }
+
+    #[test]
+    fn answers_identity_request() {
+        let mut sent = std::vec::Vec::new();
+        let identity_request = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+        let response = process_sysex(&identity_request, &mut state(), &mut sent).unwrap();
+        assert_eq!(&response[..5], &[0xF0, 0x7E, 0x7F, 0x06, 0x02]);
+        assert_eq!(response.last(), Some(&0xF7));
+    }
+
+    #[test]
+    fn ignores_other_manufacturers() {
+        let mut state = state();
+        let mut sent = std::vec::Vec::new();
+        assert!(process_sysex(&[0xF0, 0x41, 0x10, 0x42, 0xF7], &mut state, &mut sent).is_none());
+        assert!(process_sysex(&[0xF0, 0x7D, 0x01, 0xF7], &mut state, &mut sent).is_none());
+    }
+
+    #[test]
+    fn packing_round_trips_high_bits() {
+        let data: std::vec::Vec<u8> = (0..=255).step_by(7).collect();
+        let mut packed = Vec::<u8, 64>::new();
+        pack(&data, &mut packed).unwrap();
+        assert!(packed.iter().all(|&b| b < 0x80));
+        assert_eq!(packed.len(), data.len() + data.len().div_ceil(7));
+
+        let mut unpacked = Vec::<u8, 64>::new();
+        unpack(&packed, &mut unpacked).unwrap();
+        assert_eq!(unpacked, data[..]);
+    }
+
+    #[test]
+    fn unpacking_rejects_invalid_groups() {
+        let mut out = Vec::<u8, 16>::new();
+        // Dangling header, 8-bit byte, high bit for a missing byte.
+        assert!(unpack(&[0x00, 1, 2, 3, 4, 5, 6, 7, 0x00], &mut out).is_none());
+        assert!(unpack(&[0x00, 0x80], &mut out).is_none());
+        assert!(unpack(&[0x02, 0x10], &mut out).is_none());
+    }
+
+    #[test]
+    fn frames_carry_header_and_checksum() {
+        let message = Request::GetValue(1).encode().unwrap();
+        assert_eq!(
+            &message[..],
+            &[0xF0, 0x7D, 0x53, 0x54, 0x02, 0x04, 0x00, 0x01, 0x7B, 0xF7]
+        );
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let attribute = state().attributes()[0].clone();
+        // As large as an attribute gets, which still has to fit a message.
+        let largest = Attribute::new("Oscillator 1", Channel::C16, Control::new(0))
+            .with_encoding(Encoding::Parameter(Parameter::NonRegistered(0x3FFF)))
+            .with_curve(Curve::Stepped(255))
+            .with_kind(Kind::choices(&[("Square", 127); MAX_CHOICES]));
+        let preset = Preset {
+            name: Name::try_from("Dub").unwrap(),
+            values: Vec::from_slice(&[200, 1, 127]).unwrap(),
+        };
+        let route = Route {
+            channels: 0x8001,
+            drop_sysex: true,
+            ..Route::OPEN
+        };
+
+        for request in [
+            Request::GetInfo,
+            Request::GetAttribute(3),
+            Request::SetAttribute(1, attribute.clone()),
+            Request::SetAttribute(31, largest.clone()),
+            Request::SetValue(0, 99),
+            Request::SetPreset(2, Some(preset.clone())),
+            Request::SetPreset(2, None),
+            Request::GetRoute(1, 0),
+            Request::SetRoute(2, 1, route),
+        ] {
+            assert_eq!(Request::decode(&request.encode().unwrap()), Ok(request));
+        }
+
+        for response in [
+            Response::Attribute(0, attribute),
+            Response::Attribute(31, largest),
+            Response::Preset(7, Some(preset)),
+            Response::Route(0, 1, route),
+            Response::Ack,
+            Response::Error(ErrorCode::Checksum),
+        ] {
+            assert_eq!(Response::decode(&response.encode().unwrap()), Ok(response));
+        }
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let good = Request::SetValue(0, 10).encode().unwrap();
+
+        let mut bad_checksum = good.clone();
+        bad_checksum[7] ^= 0x01;
+        assert_eq!(Request::decode(&bad_checksum), Err(Error::Checksum));
+
+        let mut bad_version = good.clone();
+        bad_version[4] = 1;
+        assert_eq!(
+            Request::decode(&bad_version),
+            Err(Error::UnsupportedVersion(1))
+        );
+
+        let mut eight_bit = good.clone();
+        eight_bit[6] = 0x80;
+        assert_eq!(Request::decode(&eight_bit), Err(Error::Malformed));
+
+        let truncated = [0xF0, 0x7D, 0x53, 0x54, PROTOCOL_VERSION, 0xF7];
+        assert_eq!(Request::decode(&truncated), Err(Error::Malformed));
+
+        let unterminated = &good[..good.len() - 1];
+        assert_eq!(Request::decode(unterminated), Err(Error::NotForUs));
+
+        // Valid framing around a command without its argument.
+        let mut empty_payload = Message::new();
+        empty_payload.extend_from_slice(&HEADER).unwrap();
+        empty_payload
+            .extend_from_slice(&[0x04, 0x7C, 0xF7])
+            .unwrap();
+        assert_eq!(Request::decode(&empty_payload), Err(Error::Malformed));
+
+        let unknown = frame(0x33, &[]).unwrap();
+        assert_eq!(Request::decode(&unknown), Err(Error::UnknownCommand(0x33)));
+    }
+
+    #[test]
+    fn device_reports_errors() {
+        let mut state = state();
+        let mut sent = std::vec::Vec::new();
+        let mut bad = Request::GetInfo.encode().unwrap();
+        bad[4] = 9;
+
+        let response = process_sysex(&bad, &mut state, &mut sent).unwrap();
+        assert_eq!(
+            Response::decode(&response),
+            Ok(Response::Error(ErrorCode::UnsupportedVersion))
+        );
+
+        let (response, _) = exchange(&mut state, Request::GetAttribute(2));
+        assert_eq!(response, Response::Error(ErrorCode::OutOfRange));
+    }
+
+    #[test]
+    fn oversize_and_dropped_requests_are_reported() {
+        let mut long = std::vec::Vec::from(&HEADER[..]);
+        long.extend(core::iter::repeat_n(0x00, 3000));
+        long.push(0xF7);
+        let response = process_sysex(&long, &mut state(), &mut std::vec::Vec::new()).unwrap();
+        assert_eq!(
+            Response::decode(&response),
+            Ok(Response::Error(ErrorCode::TooLarge))
+        );
+
+        let response = error_response(&long[..40], Dropped::Interrupted.into()).unwrap();
+        assert_eq!(
+            Response::decode(&response),
+            Ok(Response::Error(ErrorCode::Incomplete))
+        );
+        assert_eq!(
+            error_response(&[0xF0, 0x7E, 0x7F], ErrorCode::TooLarge),
+            None
+        );
+        assert_eq!(error_response(&[0xF0, 0x7D], ErrorCode::TooLarge), None);
+    }
+
+    #[test]
+    fn device_reads_and_writes_settings() {
+        let mut state = state();
+
+        let (response, _) = exchange(&mut state, Request::GetInfo);
+        let Response::Info(info) = response else {
+            panic!("expected info, got {response:?}");
+        };
+        assert_eq!(info.attributes, 2);
+        assert_eq!(info.protocol, PROTOCOL_VERSION);
+
+        let (response, sent) = exchange(&mut state, Request::SetValue(1, 70));
+        assert_eq!(response, Response::Ack);
+        assert_eq!(
+            sent,
+            [MidiMessage::ControlChange(
+                Channel::C1,
+                Control::new(21),
+                Value7::from(89)
+            )]
+        );
+        assert_eq!(
+            exchange(&mut state, Request::GetValue(1)).0,
+            Response::Value(1, 70)
+        );
+
+        // Appending grows the table.
+        let cutoff = Attribute::new("Cutoff", Channel::C2, Control::new(74)).with_default(64);
+        let (response, _) = exchange(&mut state, Request::SetAttribute(2, cutoff.clone()));
+        assert_eq!(response, Response::Ack);
+        assert_eq!(
+            exchange(&mut state, Request::GetAttribute(2)).0,
+            Response::Attribute(2, cutoff)
+        );
+
+        state.store_preset(0);
+        let (response, _) = exchange(&mut state, Request::GetPreset(0));
+        let Response::Preset(0, Some(preset)) = response else {
+            panic!("expected a stored preset, got {response:?}");
+        };
+        assert_eq!(preset.values, [15, 70, 64]);
+
+        let (response, _) = exchange(&mut state, Request::SetPreset(5, Some(preset.clone())));
+        assert_eq!(response, Response::Ack);
+        assert_eq!(state.presets()[5], Some(preset));
+
+        let route = Route {
+            drop_clock: true,
+            ..Route::OPEN
+        };
+        let (response, _) = exchange(&mut state, Request::SetRoute(1, 1, route));
+        assert_eq!(response, Response::Ack);
+        assert_eq!(
+            exchange(&mut state, Request::GetRoute(1, 1)).0,
+            Response::Route(1, 1, route)
+        );
+        assert_eq!(
+            exchange(&mut state, Request::GetRoute(3, 0)).0,
+            Response::Error(ErrorCode::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! USB MIDI 1.0 event packets.
+//!
+//! Every packet is four bytes: the cable number and Code Index Number (CIN)
+//! in the first byte, followed by up to three MIDI bytes. SysEx is split over
+//! as many packets as it takes, the CIN of the last one telling how many bytes
+//! it carries.
+
+use midi_convert::midi_types::MidiMessage;
+use midi_convert::render_slice::MidiRenderSlice;
+
+pub const PACKET_SIZE: usize = 4;
+
+const SYSEX_START: u8 = 0x4;
+const SINGLE_BYTE: u8 = 0x5;
+const REAL_TIME: u8 = 0xF;
+
+/// Virtual cables of the interface, each a MIDI port of its own on the host,
+/// so that a DAW can route them independently.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Cable {
+    /// Attribute values, preset changes and SysEx requests.
+    #[default]
+    Control,
+    /// Clock and transport, received and sent.
+    Clock,
+    /// Traffic for other gear, kept apart from the device's own controls.
+    Thru,
+}
+
+impl Cable {
+    /// Every cable, in order of its number.
+    pub const ALL: [Self; 3] = [Self::Control, Self::Clock, Self::Thru];
+    pub const COUNT: usize = Self::ALL.len();
+
+    pub const fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// The cable with `number`, if the interface has it.
+    pub fn from_number(number: u8) -> Option<Self> {
+        Self::ALL.get(number as usize).copied()
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Control => "Control",
+            Self::Clock => "Clock",
+            Self::Thru => "Thru",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Packet([u8; PACKET_SIZE]);
+
+impl Packet {
+    pub const fn from_bytes(bytes: [u8; PACKET_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; PACKET_SIZE] {
+        &self.0
+    }
+
+    /// Packs a message that isn't SysEx.
+    pub fn from_message(cable: u8, message: &MidiMessage) -> Self {
+        let mut bytes = [0; PACKET_SIZE];
+        message.render_slice(&mut bytes[1..]);
+        let cin = match bytes[1] {
+            status @ 0x80..=0xEF => status >> 4,
+            0xF1 | 0xF3 => 0x2,
+            0xF2 => 0x3,
+            0xF6 => SINGLE_BYTE,
+            _ => REAL_TIME,
+        };
+        bytes[0] = (cable << 4) | cin;
+        Self(bytes)
+    }
+
+    pub fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+
+    fn cin(&self) -> u8 {
+        self.0[0] & 0x0F
+    }
+
+    /// Whether the payload is part of a SysEx message.
+    pub fn is_sysex(&self) -> bool {
+        match self.cin() {
+            0x4 | 0x6 | 0x7 => true,
+            SINGLE_BYTE => self.0[1] == 0xF7,
+            _ => false,
+        }
+    }
+
+    /// The MIDI bytes carried, as many as the CIN says.
+    pub fn payload(&self) -> &[u8] {
+        let len = match self.cin() {
+            0x5 | 0xF => 1,
+            0x2 | 0x6 | 0xC | 0xD => 2,
+            0x3 | 0x4 | 0x7..=0xB | 0xE => 3,
+            // Reserved for future extensions.
+            _ => 0,
+        };
+        &self.0[1..1 + len]
+    }
+}
+
+/// Splits a complete SysEx message into packets.
+pub fn sysex_packets(cable: u8, message: &[u8]) -> impl Iterator<Item = Packet> + '_ {
+    let count = message.len().div_ceil(3);
+    message.chunks(3).enumerate().map(move |(i, chunk)| {
+        // Start or continue, or an end with one to three bytes.
+        let cin = if i + 1 < count {
+            SYSEX_START
+        } else {
+            SYSEX_START + chunk.len() as u8
+        };
+        let mut bytes = [(cable << 4) | cin, 0, 0, 0];
+        bytes[1..=chunk.len()].copy_from_slice(chunk);
+        Packet(bytes)
+    })
+}
+
+/// Splits a USB transfer into its packets, ignoring a trailing partial one.
+pub fn packets(transfer: &[u8]) -> impl Iterator<Item = Packet> + '_ {
+    transfer
+        .chunks_exact(PACKET_SIZE)
+        .map(|bytes| Packet([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_convert::midi_types::{Channel, Control, Program, Value7};
+
+    use super::*;
+
+    #[test]
+    fn messages_get_their_code_index() {
+        let cc = MidiMessage::ControlChange(Channel::C2, Control::new(20), Value7::new(64));
+        let packet = Packet::from_message(0, &cc);
+        assert_eq!(packet.as_bytes(), &[0x0B, 0xB1, 20, 64]);
+        assert_eq!(packet.payload(), [0xB1, 20, 64]);
+
+        let program = MidiMessage::ProgramChange(Channel::C1, Program::new(5));
+        let packet = Packet::from_message(3, &program);
+        assert_eq!(packet.as_bytes(), &[0x3C, 0xC0, 5, 0]);
+        assert_eq!(packet.cable(), 3);
+        assert_eq!(packet.payload(), [0xC0, 5]);
+
+        let clock = Packet::from_message(0, &MidiMessage::TimingClock);
+        assert_eq!(clock.as_bytes(), &[0x0F, 0xF8, 0, 0]);
+        assert!(!clock.is_sysex());
+    }
+
+    #[test]
+    fn cables_are_numbered_in_order() {
+        for (number, cable) in Cable::ALL.into_iter().enumerate() {
+            assert_eq!(cable.number() as usize, number);
+            assert_eq!(Cable::from_number(number as u8), Some(cable));
+        }
+        assert_eq!(Cable::from_number(Cable::COUNT as u8), None);
+        assert_eq!(Cable::default(), Cable::Control);
+    }
+
+    #[test]
+    fn sysex_is_split_and_rejoined() {
+        for len in 2..=10 {
+            let mut message = std::vec![0xF0];
+            message.extend(1..len - 1);
+            message.push(0xF7);
+
+            let packets: std::vec::Vec<_> = sysex_packets(1, &message).collect();
+            assert_eq!(packets.len(), len.div_ceil(3) as usize);
+            assert!(packets.iter().all(|p| p.is_sysex() && p.cable() == 1));
+
+            let rejoined: std::vec::Vec<u8> =
+                packets.iter().flat_map(|p| p.payload()).copied().collect();
+            assert_eq!(rejoined, message);
+        }
+    }
+
+    #[test]
+    fn transfers_are_split_into_packets() {
+        let transfer = [0x09, 0x90, 60, 100, 0x05, 0xF7, 0, 0, 0x0F];
+        let packets: std::vec::Vec<_> = packets(&transfer).collect();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].payload(), [0x90, 60, 100]);
+        assert!(packets[1].is_sysex());
+
+        // A single-byte system common message isn't SysEx.
+        assert!(!Packet::from_bytes([0x05, 0xF6, 0, 0]).is_sysex());
+        assert_eq!(Packet::from_bytes([0x01, 1, 2, 3]).payload(), []);
+    }
+}
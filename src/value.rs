@@ -0,0 +1,383 @@
+//! Attribute value model.
+//!
+//! A [`Range`] owns everything about an attribute's numbers: the values that
+//! can be picked, how far a detent moves them, and how they scale onto MIDI,
+//! the screen and text. Values are what the user picks and what presets, SysEx
+//! and storage hold; MIDI and the display see them scaled through a [`Curve`].
+//!
+//! For 14-bit output a value is refined by a fine part, see [`FINE_STEPS`].
+//! The two together form a position, which runs from the minimum with no fine
+//! part to the maximum with the largest one, so the full 14-bit range is
+//! reachable and a `0..=127` range maps one to one.
+
+/// Fine steps per value step.
+pub const FINE_STEPS: u16 = 128;
+
+/// Largest 7-bit MIDI value.
+pub const MIDI_7_MAX: u32 = 0x7F;
+/// Largest 14-bit MIDI value.
+pub const MIDI_14_MAX: u32 = 0x3FFF;
+
+/// Fixed-point one for curve inputs and outputs.
+const ONE: u32 = 1 << 16;
+const SEGMENTS: usize = 16;
+const SEGMENT: u32 = ONE / SEGMENTS as u32;
+/// `(2^(6x) - 1) / 63` at the segment ends, so the slope at the top is 64
+/// times the one at the bottom.
+const EXP_TABLE: [u32; SEGMENTS + 1] = [
+    0, 309, 709, 1229, 1902, 2775, 3908, 5377, 7282, 9752, 12956, 17110, 22498, 29485, 38546,
+    50297, 65536,
+];
+
+/// How the range maps onto its output. Every curve rises monotonically and
+/// maps the minimum and maximum onto the ends of the output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Rises quickly at first and flattens out.
+    Logarithmic,
+    /// Rises slowly at first, leaving fine resolution at the bottom, as times
+    /// and frequencies want.
+    Exponential,
+    /// This many evenly spaced levels, at least 2, e.g. for a waveform choice.
+    Stepped(u8),
+}
+
+impl Curve {
+    /// Maps `t` on `0..=ONE` through the curve.
+    fn apply(self, t: u32) -> u32 {
+        match self {
+            Self::Linear => t,
+            Self::Logarithmic => log(t),
+            Self::Exponential => exp(t),
+            Self::Stepped(levels) => quantize(levels, t),
+        }
+    }
+
+    /// The input on `0..=ONE` that [`Curve::apply`] maps closest to `t`.
+    fn invert(self, t: u32) -> u32 {
+        match self {
+            Self::Linear => t,
+            Self::Logarithmic => exp(t),
+            Self::Exponential => log(t),
+            Self::Stepped(levels) => quantize(levels, t),
+        }
+    }
+
+    /// Maps `x` from `from` onto `0..=to`.
+    fn scale(self, from: (u32, u32), to: u32, x: u32) -> u32 {
+        match self {
+            // Kept exact, so `0..=127` maps one to one.
+            Self::Linear => scale(from, to, x),
+            _ => scale((0, ONE), to, self.apply(scale(from, ONE, x))),
+        }
+    }
+
+    /// Maps `y` from `0..=from` onto `0..=to`, undoing the curve.
+    fn unscale(self, from: u32, to: u32, y: u32) -> u32 {
+        match self {
+            Self::Linear => scale((0, from), to, y),
+            _ => scale((0, ONE), to, self.invert(scale((0, from), ONE, y))),
+        }
+    }
+}
+
+fn exp(t: u32) -> u32 {
+    let segment = (t / SEGMENT) as usize;
+    if segment >= SEGMENTS {
+        return ONE;
+    }
+    let (low, high) = (EXP_TABLE[segment], EXP_TABLE[segment + 1]);
+    low + (high - low) * (t % SEGMENT) / SEGMENT
+}
+
+fn log(t: u32) -> u32 {
+    let t = t.min(ONE);
+    let segment = (EXP_TABLE.partition_point(|&y| y <= t) - 1).min(SEGMENTS - 1);
+    let (low, high) = (EXP_TABLE[segment], EXP_TABLE[segment + 1]);
+    segment as u32 * SEGMENT + (t - low) * SEGMENT / (high - low)
+}
+
+fn quantize(levels: u8, t: u32) -> u32 {
+    let steps = levels.max(2) as u32 - 1;
+    let level = (t.min(ONE) * steps + ONE / 2) / ONE;
+    level * ONE / steps
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    min: u8,
+    max: u8,
+    step: u8,
+    curve: Curve,
+}
+
+impl Default for Range {
+    /// `0..=127` in single steps.
+    fn default() -> Self {
+        Self::new(0, 127)
+    }
+}
+
+impl Range {
+    /// `min..=max` in single steps, scaled linearly; `max` is raised to `min`
+    /// if below it.
+    pub const fn new(min: u8, max: u8) -> Self {
+        Self {
+            min,
+            max: if max < min { min } else { max },
+            step: 1,
+            curve: Curve::Linear,
+        }
+    }
+
+    /// Sets how many values a detent moves, at least one.
+    pub const fn with_step(mut self, step: u8) -> Self {
+        self.step = if step == 0 { 1 } else { step };
+        self
+    }
+
+    pub const fn with_curve(mut self, curve: Curve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    pub const fn min(&self) -> u8 {
+        self.min
+    }
+
+    pub const fn max(&self) -> u8 {
+        self.max
+    }
+
+    pub const fn step(&self) -> u8 {
+        self.step
+    }
+
+    pub const fn curve(&self) -> Curve {
+        self.curve
+    }
+
+    /// The middle value, rounded up, e.g. `64` for `0..=127`.
+    pub const fn centre(&self) -> u8 {
+        self.min + (self.max - self.min).div_ceil(2)
+    }
+
+    pub fn contains(&self, value: u8) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+
+    pub fn clamp(&self, value: u8) -> u8 {
+        value.clamp(self.min, self.max)
+    }
+
+    /// Lowest and highest position.
+    pub fn positions(&self) -> (u16, u16) {
+        (
+            self.min as u16 * FINE_STEPS,
+            self.max as u16 * FINE_STEPS + FINE_STEPS - 1,
+        )
+    }
+
+    pub fn clamp_position(&self, position: i32) -> u16 {
+        let (low, high) = self.positions();
+        position.clamp(low as i32, high as i32) as u16
+    }
+
+    /// The position `detents` steps away from `position`, clamped.
+    pub fn step_position(&self, position: u16, detents: i16) -> u16 {
+        let offset = detents as i32 * self.step as i32 * FINE_STEPS as i32;
+        self.clamp_position(position as i32 + offset)
+    }
+
+    /// Scales `value` through the curve onto `0..=to`.
+    pub fn scale(&self, value: u8, to: u32) -> u32 {
+        self.curve.scale(
+            (self.min as u32, self.max as u32),
+            to,
+            self.clamp(value) as u32,
+        )
+    }
+
+    /// The value nearest to `scaled` on `0..=from`.
+    pub fn unscale(&self, scaled: u32, from: u32) -> u8 {
+        let span = (self.max - self.min) as u32;
+        self.min + self.curve.unscale(from, span, scaled.min(from)) as u8
+    }
+
+    /// Scales `position` through the curve onto `0..=to`.
+    pub fn scale_position(&self, position: u16, to: u32) -> u32 {
+        let (low, high) = self.positions();
+        self.curve.scale(
+            (low as u32, high as u32),
+            to,
+            position.clamp(low, high) as u32,
+        )
+    }
+
+    /// The position nearest to `scaled` on `0..=from`.
+    pub fn unscale_position(&self, scaled: u32, from: u32) -> u16 {
+        let (low, high) = self.positions();
+        low + self
+            .curve
+            .unscale(from, (high - low) as u32, scaled.min(from)) as u16
+    }
+}
+
+/// Maps `x` from `from` onto `0..=to` linearly, rounding to the nearest.
+fn scale(from: (u32, u32), to: u32, x: u32) -> u32 {
+    let span = from.1 - from.0;
+    if span == 0 {
+        return 0;
+    }
+    ((x - from.0) * to + span / 2) / span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construction_keeps_ranges_consistent() {
+        let range = Range::new(50, 10).with_step(0);
+        assert_eq!((range.min(), range.max(), range.step()), (50, 50, 1));
+        assert_eq!(range.clamp(0), 50);
+        assert_eq!(range.scale(50, 127), 0);
+        assert_eq!(range.unscale(127, 127), 50);
+    }
+
+    #[test]
+    fn full_midi_range_maps_one_to_one() {
+        let range = Range::default();
+        for value in 0..=127 {
+            assert_eq!(range.scale(value, MIDI_7_MAX), value as u32);
+            assert_eq!(range.unscale(value as u32, MIDI_7_MAX), value);
+        }
+        assert_eq!(range.positions(), (0, 0x3FFF));
+        assert_eq!(range.centre(), 64);
+        assert_eq!(range.scale_position(8192, MIDI_14_MAX), 8192);
+        assert_eq!(range.unscale_position(8192, MIDI_14_MAX), 8192);
+    }
+
+    #[test]
+    fn offset_ranges_scale_from_their_minimum() {
+        let range = Range::new(20, 120);
+        assert_eq!(range.scale(20, MIDI_7_MAX), 0);
+        assert_eq!(range.scale(70, 100), 50);
+        assert_eq!(range.centre(), 70);
+        assert_eq!(range.scale(120, MIDI_7_MAX), 127);
+        assert_eq!(range.unscale(0, MIDI_7_MAX), 20);
+        assert_eq!(range.unscale(64, MIDI_7_MAX), 70);
+
+        // Out of range inputs are clamped rather than wrapping.
+        assert_eq!(range.scale(5, MIDI_7_MAX), 0);
+        assert_eq!(range.unscale(500, MIDI_7_MAX), 120);
+    }
+
+    #[test]
+    fn ranges_above_127_fit_into_midi() {
+        let range = Range::new(0, 255);
+        assert_eq!(range.scale(255, MIDI_7_MAX), 127);
+        assert_eq!(range.scale(128, MIDI_7_MAX), 64);
+        assert_eq!(range.unscale(127, MIDI_7_MAX), 255);
+        assert_eq!(range.scale_position(255 * 128 + 127, MIDI_14_MAX), 0x3FFF);
+
+        // Values survive a 14-bit round trip, as there are fewer positions than steps.
+        let range = Range::new(100, 200);
+        for value in [100, 150, 200] {
+            let position = value as u16 * FINE_STEPS;
+            let scaled = range.scale_position(position, MIDI_14_MAX);
+            assert_eq!(range.unscale_position(scaled, MIDI_14_MAX), position);
+        }
+    }
+
+    #[test]
+    fn detents_move_by_the_step() {
+        let range = Range::new(10, 100).with_step(5);
+        assert_eq!(range.step_position(50 * 128 + 3, 2), 60 * 128 + 3);
+        assert_eq!(range.step_position(50 * 128, -20), 10 * 128);
+        assert_eq!(range.step_position(98 * 128, 1), 100 * 128 + 127);
+    }
+
+    const CURVES: [Curve; 5] = [
+        Curve::Linear,
+        Curve::Logarithmic,
+        Curve::Exponential,
+        Curve::Stepped(2),
+        Curve::Stepped(5),
+    ];
+
+    #[test]
+    fn curves_keep_their_end_points() {
+        for curve in CURVES {
+            assert_eq!((curve.apply(0), curve.apply(ONE)), (0, ONE), "{curve:?}");
+            assert_eq!((curve.invert(0), curve.invert(ONE)), (0, ONE), "{curve:?}");
+
+            let range = Range::new(20, 120).with_curve(curve);
+            assert_eq!(range.scale(20, MIDI_7_MAX), 0, "{curve:?}");
+            assert_eq!(range.scale(120, MIDI_7_MAX), 127, "{curve:?}");
+            assert_eq!(range.unscale(0, MIDI_7_MAX), 20, "{curve:?}");
+            assert_eq!(range.unscale(127, MIDI_7_MAX), 120, "{curve:?}");
+            let (low, high) = range.positions();
+            assert_eq!(range.scale_position(high, MIDI_14_MAX), 0x3FFF, "{curve:?}");
+            assert_eq!(range.unscale_position(0, MIDI_14_MAX), low, "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn curves_rise_monotonically() {
+        for curve in CURVES {
+            for t in 0..ONE {
+                assert!(curve.apply(t) <= curve.apply(t + 1), "{curve:?} at {t}");
+                assert!(curve.invert(t) <= curve.invert(t + 1), "{curve:?} at {t}");
+            }
+            let range = Range::new(0, 255).with_curve(curve);
+            for value in 0..255 {
+                assert!(range.scale(value, MIDI_14_MAX) <= range.scale(value + 1, MIDI_14_MAX));
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_leaves_resolution_at_the_bottom() {
+        let exponential = Range::new(0, 100).with_curve(Curve::Exponential);
+        let logarithmic = Range::new(0, 100).with_curve(Curve::Logarithmic);
+        assert_eq!(exponential.scale(50, 1000), 111);
+        assert_eq!(logarithmic.scale(50, 1000), 835);
+        assert!(exponential.scale(1, 1000) < 10);
+
+        // Received values land close to where they were sent from.
+        for value in [10, 50, 90] {
+            for range in [exponential, logarithmic] {
+                let sent = range.scale(value, MIDI_14_MAX);
+                assert!(range.unscale(sent, MIDI_14_MAX).abs_diff(value) <= 1);
+            }
+        }
+        // The log curve inverts the exponential one.
+        for t in (0..=ONE).step_by(1000) {
+            assert!(log(exp(t)).abs_diff(t) <= 16, "at {t}");
+        }
+    }
+
+    #[test]
+    fn stepped_curves_snap_to_levels() {
+        let range = Range::new(0, 100).with_curve(Curve::Stepped(5));
+        let levels: std::vec::Vec<u32> = (0..=100).map(|value| range.scale(value, 100)).collect();
+        let mut distinct = levels.clone();
+        distinct.dedup();
+        assert_eq!(distinct, [0, 25, 50, 75, 100]);
+        assert_eq!(range.scale(37, 100), 25);
+        assert_eq!(range.scale(38, 100), 50);
+
+        // Received values snap to the nearest level too.
+        assert_eq!(range.unscale(70, 127), 50);
+        // Fewer than two levels can't reach both ends.
+        assert_eq!(
+            Range::new(0, 10)
+                .with_curve(Curve::Stepped(0))
+                .scale(10, 127),
+            127
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Request/response exchange with the device over any byte stream.
+
+use std::io::{self, Read, Write};
+
+use midi::sysex::{Error, Request, Response};
+
+/// Longest message accepted while waiting for a response.
+const MAX_MESSAGE: usize = 4096;
+
+/// A device reachable through `stream`, e.g. a raw MIDI node such as
+/// `/dev/snd/midiC1D0`, a FIFO or stdin/stdout.
+pub struct Device<T> {
+    stream: T,
+}
+
+impl<T: Read + Write> Device<T> {
+    pub fn new(stream: T) -> Self {
+        Self { stream }
+    }
+
+    /// Sends `request` and waits for the device's response.
+    ///
+    /// Other traffic on the stream, such as clock or unrelated SysEx, is skipped.
+    pub fn request(&mut self, request: &Request) -> io::Result<Response> {
+        let message = request.encode().map_err(invalid)?;
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+
+        loop {
+            let message = read_sysex(&mut self.stream)?;
+            match Response::decode(&message) {
+                Ok(response) => return Ok(response),
+                Err(Error::NotForUs) => continue,
+                Err(error) => return Err(invalid(error)),
+            }
+        }
+    }
+}
+
+/// Reads bytes until a complete `F0 ... F7` message arrived.
+///
+/// Real-time bytes inside the message are dropped, and a new `F0` restarts it.
+pub fn read_sysex(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut message = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        stream.read_exact(&mut byte)?;
+        match byte[0] {
+            0xF0 => {
+                message.clear();
+                message.push(0xF0);
+            }
+            0xF8..=0xFF => {}
+            0xF7 if !message.is_empty() => {
+                message.push(0xF7);
+                return Ok(message);
+            }
+            b if !message.is_empty() && b < 0x80 => {
+                if message.len() == MAX_MESSAGE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "SysEx message too long",
+                    ));
+                }
+                message.push(b);
+            }
+            // Anything else ends a message without its F7.
+            _ => message.clear(),
+        }
+    }
+}
+
+/// Splits a `.syx` file into its messages.
+pub fn split_sysex(mut bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+    while bytes.contains(&0xF0) {
+        messages.push(read_sysex(&mut bytes)?);
+    }
+    Ok(messages)
+}
+
+pub fn invalid(error: impl std::fmt::Debug) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{error:?}"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::VecDeque;
+
+    use midi::attribute::{Attribute, Attributes};
+    use midi::io::MidiSink;
+    use midi::state::State;
+    use midi::sysex::{Info, process_sysex};
+    use midi::usb_midi::Cable;
+    use midi_convert::midi_types::{Channel, Control, MidiMessage};
+
+    use super::*;
+
+    struct Discard;
+
+    impl MidiSink for Discard {
+        fn send(&mut self, _cable: Cable, _message: MidiMessage) {}
+    }
+
+    /// Stand-in for the hardware: runs the firmware's SysEx handler in-process.
+    pub struct Loopback {
+        pub state: State,
+        received: Vec<u8>,
+        pending: VecDeque<u8>,
+    }
+
+    impl Loopback {
+        pub fn new() -> Self {
+            let mut state = State::new();
+            state.set_attributes(Attributes::from_iter([
+                Attribute::new("Delay", Channel::C1, Control::new(20))
+                    .with_range(0, 100)
+                    .with_default(15),
+                Attribute::new("Feedback", Channel::C1, Control::new(21))
+                    .with_range(0, 100)
+                    .with_default(50),
+            ]));
+            Self {
+                state,
+                received: Vec::new(),
+                pending: VecDeque::new(),
+            }
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                self.received.push(b);
+                if b == 0xF7 {
+                    // Some clock noise in front of every response.
+                    self.pending.push_back(0xF8);
+                    if let Some(response) =
+                        process_sysex(&self.received, &mut self.state, &mut Discard)
+                    {
+                        self.pending.extend(response.iter());
+                    }
+                    self.received.clear();
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.pending.read(buf)
+        }
+    }
+
+    #[test]
+    fn exchanges_requests_with_the_device() {
+        let mut device = Device::new(Loopback::new());
+
+        let Response::Info(Info { attributes, .. }) = device.request(&Request::GetInfo).unwrap()
+        else {
+            panic!("expected info");
+        };
+        assert_eq!(attributes, 2);
+
+        assert_eq!(
+            device.request(&Request::SetValue(1, 99)).unwrap(),
+            Response::Ack
+        );
+        assert_eq!(
+            device.request(&Request::GetValue(1)).unwrap(),
+            Response::Value(1, 99)
+        );
+    }
+
+    #[test]
+    fn silent_device_is_an_error() {
+        let mut device = Device::new(io::Cursor::new(Vec::new()));
+        let error = device.request(&Request::GetInfo).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reader_resynchronises() {
+        let mut bytes: &[u8] = &[0x01, 0xF0, 0x10, 0xF0, 0x20, 0xF8, 0x21, 0xF7, 0xF0, 0x30];
+        assert_eq!(read_sysex(&mut bytes).unwrap(), [0xF0, 0x20, 0x21, 0xF7]);
+        assert!(read_sysex(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn splits_syx_files() {
+        let messages = split_sysex(&[0xF0, 0x01, 0xF7, 0xF0, 0x02, 0x03, 0xF7]).unwrap();
+        assert_eq!(
+            messages,
+            [vec![0xF0, 0x01, 0xF7], vec![0xF0, 0x02, 0x03, 0xF7]]
+        );
+    }
+}
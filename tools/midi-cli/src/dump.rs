@@ -0,0 +1,296 @@
+//! Preset dumps: `.syx` files of Set Preset requests, and their TOML form.
+//!
+//! ```toml
+//! [[preset]]
+//! slot = 1
+//! name = "Dub"
+//! values = [15, 50]
+//! ```
+//!
+//! Slots are numbered from 1 as on the device. Slots missing from a dump are
+//! cleared when it is sent to the device.
+
+use std::fmt::Write;
+
+use midi::attribute::{MAX_ATTRIBUTES, NAME_LEN, Name};
+use midi::preset::{PRESET_COUNT, Preset, Presets};
+use midi::sysex::Request;
+
+use crate::device::split_sysex;
+
+/// Parses a `.syx` dump, checking every message.
+pub fn from_syx(bytes: &[u8]) -> Result<Presets, String> {
+    let messages = split_sysex(bytes).map_err(|e| format!("not a SysEx file: {e}"))?;
+    let mut presets = Presets::default();
+    let mut seen = [false; PRESET_COUNT];
+
+    for (i, message) in messages.iter().enumerate() {
+        let request = Request::decode(message).map_err(|e| format!("message {}: {e:?}", i + 1))?;
+        let Request::SetPreset(slot, preset) = request else {
+            return Err(format!("message {}: not a preset: {request:?}", i + 1));
+        };
+        let slot = slot as usize;
+        if slot >= PRESET_COUNT {
+            return Err(format!("message {}: slot {} out of range", i + 1, slot + 1));
+        }
+        if std::mem::replace(&mut seen[slot], true) {
+            return Err(format!(
+                "message {}: slot {} appears twice",
+                i + 1,
+                slot + 1
+            ));
+        }
+        presets[slot] = preset;
+    }
+
+    Ok(presets)
+}
+
+/// Writes one Set Preset request per slot, so empty slots are cleared too.
+pub fn to_syx(presets: &Presets) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (slot, preset) in presets.iter().enumerate() {
+        let message = Request::SetPreset(slot as u8, preset.clone())
+            .encode()
+            .expect("presets always fit in a message");
+        bytes.extend_from_slice(&message);
+    }
+    bytes
+}
+
+pub fn to_toml(presets: &Presets) -> String {
+    let mut toml = String::from(
+        "# Presets for the Staas MIDI Interface. Slots are numbered from 1;\n\
+         # slots missing from this file are cleared when it is sent.\n",
+    );
+    for (slot, preset) in presets.iter().enumerate() {
+        let Some(preset) = preset else {
+            continue;
+        };
+        let values: Vec<String> = preset.values.iter().map(u8::to_string).collect();
+        let name = preset.name.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = write!(
+            toml,
+            "\n[[preset]]\nslot = {}\nname = \"{}\"\nvalues = [{}]\n",
+            slot + 1,
+            name,
+            values.join(", ")
+        );
+    }
+    toml
+}
+
+/// Parses the TOML subset written by [`to_toml`].
+pub fn from_toml(text: &str) -> Result<Presets, String> {
+    struct Table {
+        line: usize,
+        slot: Option<usize>,
+        name: Option<Name>,
+        values: Option<heapless::Vec<u8, MAX_ATTRIBUTES>>,
+    }
+
+    let mut tables: Vec<Table> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let error = |message: &str| format!("line {number}: {message}");
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "[[preset]]" {
+            tables.push(Table {
+                line: number,
+                slot: None,
+                name: None,
+                values: None,
+            });
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(error("expected `key = value` or `[[preset]]`"));
+        };
+        let Some(table) = tables.last_mut() else {
+            return Err(error("key outside of a [[preset]] table"));
+        };
+        let value = value.trim();
+
+        match key.trim() {
+            "slot" => {
+                let slot: usize = value.parse().map_err(|_| error("slot must be a number"))?;
+                if !(1..=PRESET_COUNT).contains(&slot) {
+                    return Err(error(&format!("slot must be 1 to {PRESET_COUNT}")));
+                }
+                table.slot = Some(slot - 1);
+            }
+            "name" => {
+                let name = parse_string(value).ok_or_else(|| error("name must be a string"))?;
+                let name = Name::try_from(name.as_str())
+                    .map_err(|_| error(&format!("name longer than {NAME_LEN} bytes")))?;
+                table.name = Some(name);
+            }
+            "values" => {
+                let list = value
+                    .strip_prefix('[')
+                    .and_then(|v| v.strip_suffix(']'))
+                    .ok_or_else(|| error("values must be a [list]"))?;
+                let mut values = heapless::Vec::new();
+                for item in list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                {
+                    let value = item
+                        .parse()
+                        .map_err(|_| error(&format!("`{item}` is not a value from 0 to 255")))?;
+                    values
+                        .push(value)
+                        .map_err(|_| error(&format!("more than {MAX_ATTRIBUTES} values")))?;
+                }
+                table.values = Some(values);
+            }
+            key => return Err(error(&format!("unknown key `{key}`"))),
+        }
+    }
+
+    let mut presets = Presets::default();
+    for table in tables {
+        let error = |message: &str| format!("preset on line {}: {message}", table.line);
+        let slot = table.slot.ok_or_else(|| error("missing slot"))?;
+        let values = table.values.ok_or_else(|| error("missing values"))?;
+        if presets[slot].is_some() {
+            return Err(error(&format!("slot {} appears twice", slot + 1)));
+        }
+        presets[slot] = Some(Preset {
+            name: table.name.unwrap_or_else(|| Preset::default_name(slot)),
+            values,
+        });
+    }
+
+    Ok(presets)
+}
+
+/// Drops a `#` comment that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parses a basic string with `\"` and `\\` escapes.
+fn parse_string(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('"' | '\\') => string.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => string.push(c),
+        }
+    }
+    Some(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets() -> Presets {
+        let mut presets = Presets::default();
+        presets[0] = Some(Preset {
+            name: Name::try_from("Dub \"wide\"").unwrap(),
+            values: heapless::Vec::from_slice(&[15, 50]).unwrap(),
+        });
+        presets[4] = Some(Preset {
+            name: Name::try_from("Short").unwrap(),
+            values: heapless::Vec::from_slice(&[0, 200, 7]).unwrap(),
+        });
+        presets
+    }
+
+    #[test]
+    fn syx_round_trips() {
+        let syx = to_syx(&presets());
+        assert_eq!(syx.iter().filter(|&&b| b == 0xF0).count(), PRESET_COUNT);
+        assert_eq!(from_syx(&syx), Ok(presets()));
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        let toml = to_toml(&presets());
+        assert!(toml.contains("slot = 5\nname = \"Short\"\nvalues = [0, 200, 7]"));
+        assert_eq!(from_toml(&toml), Ok(presets()));
+    }
+
+    #[test]
+    fn toml_is_forgiving_about_layout() {
+        let presets =
+            from_toml("# comment\n[[preset]]\n  values = [ 1,2 , ]  # trailing\nslot=8\n").unwrap();
+        let preset = presets[7].as_ref().unwrap();
+        assert_eq!(preset.name, "Preset 8");
+        assert_eq!(preset.values, [1, 2]);
+    }
+
+    #[test]
+    fn toml_errors_point_at_the_line() {
+        let error = |toml| from_toml(toml).unwrap_err();
+
+        assert_eq!(
+            error("slot = 1"),
+            "line 1: key outside of a [[preset]] table"
+        );
+        assert_eq!(error("[[preset]]\nslot = 9"), "line 2: slot must be 1 to 8");
+        assert_eq!(
+            error("[[preset]]\nslot = 1\nvalues = [1, 256]"),
+            "line 3: `256` is not a value from 0 to 255"
+        );
+        assert_eq!(
+            error("[[preset]]\nname = \"Much too long a name\""),
+            "line 2: name longer than 12 bytes"
+        );
+        assert_eq!(
+            error("[[preset]]\ncolour = 1"),
+            "line 2: unknown key `colour`"
+        );
+        assert_eq!(
+            error("[[preset]]\nslot = 1"),
+            "preset on line 1: missing values"
+        );
+        assert_eq!(
+            error("[[preset]]\nslot = 1\nvalues = []\n[[preset]]\nslot = 1\nvalues = []"),
+            "preset on line 4: slot 1 appears twice"
+        );
+    }
+
+    #[test]
+    fn syx_validation_rejects_foreign_messages() {
+        let mut syx = to_syx(&presets());
+        syx.extend_from_slice(&Request::GetInfo.encode().unwrap());
+        assert!(from_syx(&syx).unwrap_err().contains("not a preset"));
+
+        let twice = [to_syx(&presets()), to_syx(&presets())].concat();
+        assert_eq!(
+            from_syx(&twice).unwrap_err(),
+            "message 9: slot 1 appears twice"
+        );
+
+        let mut corrupt = to_syx(&presets());
+        corrupt[8] ^= 0x01;
+        assert_eq!(from_syx(&corrupt).unwrap_err(), "message 1: Checksum");
+    }
+}
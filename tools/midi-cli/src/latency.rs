@@ -0,0 +1,75 @@
+//! Round-trip timing, to check the firmware against its latency target.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use midi::sysex::Request;
+
+use crate::device::Device;
+
+/// Longest acceptable round trip for 95% of requests, from request written
+/// to response read. Full-speed USB polls every 1 ms in each direction.
+pub const TARGET: Duration = Duration::from_millis(5);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl Summary {
+    pub fn meets_target(&self) -> bool {
+        self.p95 <= TARGET
+    }
+}
+
+/// Times `count` Get Info round trips.
+pub fn measure<T: Read + Write>(device: &mut Device<T>, count: usize) -> io::Result<Summary> {
+    let mut times = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = Instant::now();
+        device.request(&Request::GetInfo)?;
+        times.push(start.elapsed());
+    }
+    summarize(&mut times)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "nothing measured"))
+}
+
+pub fn summarize(times: &mut [Duration]) -> Option<Summary> {
+    times.sort_unstable();
+    let at = |fraction: f64| times[((times.len() - 1) as f64 * fraction).round() as usize];
+    Some(Summary {
+        min: *times.first()?,
+        median: at(0.5),
+        p95: at(0.95),
+        max: *times.last()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::Loopback;
+
+    #[test]
+    fn summarizes_percentiles() {
+        let mut times: Vec<_> = (1..=100).rev().map(Duration::from_millis).collect();
+        let summary = summarize(&mut times).unwrap();
+        assert_eq!(summary.min, Duration::from_millis(1));
+        assert_eq!(summary.median, Duration::from_millis(51));
+        assert_eq!(summary.p95, Duration::from_millis(95));
+        assert_eq!(summary.max, Duration::from_millis(100));
+        assert!(!summary.meets_target());
+        assert_eq!(summarize(&mut []), None);
+    }
+
+    #[test]
+    fn in_process_handler_meets_the_target() {
+        // Without USB in the way this bounds the cost of the firmware's own handling.
+        let mut device = Device::new(Loopback::new());
+        let summary = measure(&mut device, 200).unwrap();
+        assert!(summary.meets_target(), "{summary:?}");
+    }
+}
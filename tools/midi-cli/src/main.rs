@@ -0,0 +1,267 @@
+//! Host-side companion for the Staas MIDI Interface.
+//!
+//! Talks to the device's SysEx protocol through any byte stream and converts
+//! preset dumps between `.syx` and TOML. Run without arguments for usage.
+
+mod device;
+mod dump;
+mod latency;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use midi::preset::{PRESET_COUNT, Presets};
+use midi::sysex::{Request, Response};
+
+use device::{Device, split_sysex};
+
+const USAGE: &str = "\
+usage: midi-cli <command> [arguments]
+
+Device commands take a raw MIDI node (e.g. /dev/snd/midiC1D0), a FIFO, or `-`
+for stdin/stdout:
+    info <device>                      firmware and table sizes
+    attributes <device>                list attributes and their values
+    get-value <device> <index>         read one value
+    set-value <device> <index> <value> write one value
+    dump <device> <out.syx|out.toml>   save all presets
+    restore <device> <in.syx|in.toml>  replace all presets
+    latency <device> [count]           time request round trips against the target
+
+Offline commands:
+    syx2toml <in.syx> <out.toml>       convert a preset dump
+    toml2syx <in.toml> <out.syx>       convert a preset dump
+    validate <file.syx|file.toml>      check a preset dump
+    decode <file.syx>                  print every message in a SysEx file
+
+Indices are counted from 0 and preset slots from 1.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("midi-cli: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[&str]) -> Result<(), String> {
+    match args {
+        ["info", device] => info(&mut open(device)?),
+        ["attributes", device] => attributes(&mut open(device)?),
+        ["get-value", device, index] => {
+            let index = parse(index, "index")?;
+            match request(&mut open(device)?, Request::GetValue(index))? {
+                Response::Value(_, value) => {
+                    println!("{value}");
+                    Ok(())
+                }
+                response => Err(unexpected(response)),
+            }
+        }
+        ["set-value", device, index, value] => {
+            let request = Request::SetValue(parse(index, "index")?, parse(value, "value")?);
+            expect_ack(&mut open(device)?, request)
+        }
+        ["dump", device, path] => {
+            let presets = read_presets(&mut open(device)?)?;
+            write_dump(path, &presets)
+        }
+        ["restore", device, path] => {
+            let presets = read_dump(path)?;
+            let mut device = open(device)?;
+            for (slot, preset) in presets.into_iter().enumerate() {
+                expect_ack(&mut device, Request::SetPreset(slot as u8, preset))?;
+            }
+            Ok(())
+        }
+        ["latency", device] => latency(&mut open(device)?, 100),
+        ["latency", device, count] => latency(&mut open(device)?, parse_count(count)?),
+        ["syx2toml", input, output] | ["toml2syx", input, output] => {
+            write_dump(output, &read_dump(input)?)
+        }
+        ["validate", path] => {
+            let presets = read_dump(path)?;
+            let stored = presets.iter().flatten().count();
+            println!("{path}: {stored} of {PRESET_COUNT} slots stored");
+            Ok(())
+        }
+        ["decode", path] => decode(&read(path)?),
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Stdin and stdout as one stream, for `-`.
+struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+type Stream = Device<Box<dyn ReadWrite>>;
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+fn open(path: &str) -> Result<Stream, String> {
+    let stream: Box<dyn ReadWrite> = if path == "-" {
+        Box::new(Stdio)
+    } else {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("{path}: {e}"))?;
+        Box::new(file)
+    };
+    Ok(Device::new(stream))
+}
+
+fn request(device: &mut Stream, request: Request) -> Result<Response, String> {
+    match device.request(&request) {
+        Ok(Response::Error(code)) => Err(format!("device refused {request:?}: {code:?}")),
+        Ok(response) => Ok(response),
+        Err(error) => Err(format!("{request:?}: {error}")),
+    }
+}
+
+fn expect_ack(device: &mut Stream, request: Request) -> Result<(), String> {
+    match self::request(device, request)? {
+        Response::Ack => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: Response) -> String {
+    format!("unexpected response: {response:?}")
+}
+
+fn info(device: &mut Stream) -> Result<(), String> {
+    let Response::Info(info) = request(device, Request::GetInfo)? else {
+        return Err("unexpected response to Get Info".into());
+    };
+    let [major, minor, patch] = info.firmware;
+    println!("firmware   {major}.{minor}.{patch}");
+    println!("protocol   {}", info.protocol);
+    println!("attributes {} of {}", info.attributes, info.max_attributes);
+    println!("presets    {}", info.presets);
+    Ok(())
+}
+
+fn attributes(device: &mut Stream) -> Result<(), String> {
+    let Response::Info(info) = request(device, Request::GetInfo)? else {
+        return Err("unexpected response to Get Info".into());
+    };
+    for index in 0..info.attributes {
+        match request(device, Request::GetAttribute(index))? {
+            Response::Attribute(_, attribute) => println!(
+                "{index:>2} {:<12} ch {:>2} cc {:>3} {:>3}..={:<3} {}",
+                attribute.name.as_str(),
+                u8::from(attribute.channel) + 1,
+                u8::from(attribute.control),
+                attribute.range.min(),
+                attribute.range.max(),
+                attribute.to_human_readable().as_str(),
+            ),
+            response => return Err(unexpected(response)),
+        }
+    }
+    Ok(())
+}
+
+fn latency(device: &mut Stream, count: usize) -> Result<(), String> {
+    let summary = latency::measure(device, count).map_err(|e| e.to_string())?;
+    println!("{count} round trips");
+    println!("min    {:?}", summary.min);
+    println!("median {:?}", summary.median);
+    println!("p95    {:?}", summary.p95);
+    println!("max    {:?}", summary.max);
+
+    if !summary.meets_target() {
+        return Err(format!("p95 above the {:?} target", latency::TARGET));
+    }
+    Ok(())
+}
+
+fn read_presets(device: &mut Stream) -> Result<Presets, String> {
+    let mut presets = Presets::default();
+    for (slot, preset) in presets.iter_mut().enumerate() {
+        match request(device, Request::GetPreset(slot as u8))? {
+            Response::Preset(_, stored) => *preset = stored,
+            response => return Err(unexpected(response)),
+        }
+    }
+    Ok(presets)
+}
+
+fn decode(bytes: &[u8]) -> Result<(), String> {
+    let messages = split_sysex(bytes).map_err(|e| e.to_string())?;
+    for message in messages {
+        // The protocol's directions share no command numbers.
+        match (Request::decode(&message), Response::decode(&message)) {
+            (Ok(request), _) => println!("request  {request:?}"),
+            (_, Ok(response)) => println!("response {response:?}"),
+            (Err(error), _) => println!("invalid  {error:?}: {message:02X?}"),
+        }
+    }
+    Ok(())
+}
+
+fn is_toml(path: &str) -> bool {
+    path.ends_with(".toml")
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{path}: {e}"))
+}
+
+fn read_dump(path: &str) -> Result<Presets, String> {
+    let bytes = read(path)?;
+    let presets = if is_toml(path) {
+        let text = String::from_utf8(bytes).map_err(|e| e.to_string());
+        text.and_then(|text| dump::from_toml(&text))
+    } else {
+        dump::from_syx(&bytes)
+    };
+    presets.map_err(|e| format!("{path}: {e}"))
+}
+
+fn write_dump(path: &str, presets: &Presets) -> Result<(), String> {
+    let bytes = if is_toml(path) {
+        dump::to_toml(presets).into_bytes()
+    } else {
+        dump::to_syx(presets)
+    };
+    fs::write(path, bytes).map_err(|e| format!("{path}: {e}"))
+}
+
+fn parse(value: &str, what: &str) -> Result<u8, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{what} must be a number from 0 to 255, not `{value}`"))
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("count must be a positive number, not `{value}`")),
+    }
+}